@@ -1,11 +1,21 @@
 //! tiny-fs pack and test
 use tiny_fs::{BlockDevice, TinyFileSystem, BLOCK_SZ};
+#[cfg(test)]
+use tiny_fs::{
+    cached_generation, clear_block_cache, clear_io_logger, flush_dirty_blocks, needs_flush, set_background_flush,
+    set_io_logger, set_writeback_batch,
+    Contiguous, CreateConfig, DirEntry, Error, FaultyBlockDevice, FirstFit, Inode, IoEvent, Layout, RepairReport,
+    SliceBlockDevice, SubBlockDevice,
+    FAULT_MARKER,
+};
 
-use clap::{App, Arg};
+use clap::{Arg, ArgAction, Command};
 use std::fs::{read_dir, File, OpenOptions};
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::sync::Arc;
 use std::sync::Mutex;
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 struct BlockFile(Mutex<File>);
 
@@ -30,91 +40,258 @@ fn main() {
 }
 
 fn tiny_fs_pack() -> std::io::Result<()> {
-    let matches = App::new("TinyFileSystem packer")
+    let matches = Command::new("TinyFileSystem packer")
         .arg(
-            Arg::with_name("source")
-                .short("s")
+            Arg::new("source")
+                .short('s')
                 .long("source")
-                .takes_value(true)
+                .required(true)
                 .help("Exectuable source dir(with backslash)"),
         )
         .arg(
-            Arg::with_name("target")
-                .short("t")
+            Arg::new("target")
+                .short('t')
                 .long("target")
-                .takes_value(true)
+                .required(true)
                 .help("Executable target dir(with backslash)"),
         )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("open")
+                .help("Create a fresh tfs.img, the default"),
+        )
+        .arg(
+            Arg::new("open")
+                .long("open")
+                .action(ArgAction::SetTrue)
+                .help("Add the executables to the existing tfs.img"),
+        )
         .get_matches();
-    let src_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
+    let src_path = matches.get_one::<String>("source").unwrap();
+    let target_path = matches.get_one::<String>("target").unwrap();
+    let open = matches.get_flag("open");
     println!("src_path = {}", src_path);
     println!("target_path = {}", target_path);
-    //create and open block_file "tfs.img"
+    //open block_file "tfs.img", truncating it unless it is reused
     let block_file = Arc::new(BlockFile(Mutex::new(
         {
             let f = OpenOptions::new()
                 .read(true)
                 .write(true)
-                .create(true)
+                .create(!open)
+                .truncate(!open)
                 .open(format!("{}{}", target_path, "tfs.img"))?;
-            f.set_len(16 * 2048 * 512).unwrap();
+            if !open {
+                f.set_len(16 * 2048 * 512).unwrap();
+            }
             f
         }
     )));
-    //create tiny-fs
-    let tfs = TinyFileSystem::create(block_file, 16 * 2048, 1);
+    //create or open tiny-fs
+    let tfs = if open {
+        TinyFileSystem::open(block_file)
+    } else {
+        TinyFileSystem::create(block_file, 16 * 2048, 1)
+    }
+    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
     let root_inode = Arc::new(TinyFileSystem::root_inode(&tfs));
-    let apps_name: Vec<_> = read_dir(src_path)
+    let mut apps_name: Vec<_> = read_dir(src_path)
         .unwrap()
-        .into_iter()
         .map(|dir_entry| {
             let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
             name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
             name_with_ext
         })
         .collect();
+    //pack in a stable order so the same inputs give the same image
+    apps_name.sort();
     for name in apps_name {
         //load app data from host file system
         let mut host_file = File::open(format!("{}{}", target_path, name)).unwrap();
         let mut app_data: Vec<u8> = Vec::new();
         host_file.read_to_end(&mut app_data).unwrap();
-        //create file inode in tiny-fs
-        let new_inode = root_inode.create(name.as_str()).unwrap();
-        new_inode.write_at(0, app_data.as_slice());
+        //create file inode in tiny-fs, or overwrite it in a reused image
+        match root_inode.find(name.as_str()) {
+            Some(inode) => inode.replace_contents(app_data.as_slice()).unwrap(),
+            None => {
+                root_inode.create_file(name.as_str(), app_data.as_slice()).unwrap();
+            }
+        }
     }
-    for app_name in root_inode.ls() {
+    for app_name in root_inode.ls_sorted() {
         println!("{}", app_name);
     }
+    drop(root_inode);
+    TinyFileSystem::close(tfs);
     Ok(())  
 }
 
+/// All tests share the global block cache, so run them one at a time
+#[cfg(test)]
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+fn test_guard() -> std::sync::MutexGuard<'static, ()> {
+    TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Create a zeroed image "target/{name}" with the given blocks
+#[cfg(test)]
+fn test_block_file(name: &str, blocks: usize) -> Arc<BlockFile> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(format!("target/{}", name))
+        .unwrap();
+    f.set_len((blocks * BLOCK_SZ) as u64).unwrap();
+    Arc::new(BlockFile(Mutex::new(f)))
+}
+
+/// Open the existing image "target/{name}" as a new device
+#[cfg(test)]
+fn reopen_block_file(name: &str) -> Arc<BlockFile> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(format!("target/{}", name))
+        .unwrap();
+    Arc::new(BlockFile(Mutex::new(f)))
+}
+
+/// BlockDevice counting the calls that reach it
+#[cfg(test)]
+struct CountingBlockDevice<D> {
+    inner: D,
+    read_block_calls: AtomicUsize,
+    read_blocks_calls: AtomicUsize,
+    write_block_calls: AtomicUsize,
+    write_blocks_calls: AtomicUsize,
+}
+
+#[cfg(test)]
+impl<D: BlockDevice> CountingBlockDevice<D> {
+    fn new(inner: D) -> Self {
+        Self {
+            inner,
+            read_block_calls: Default::default(),
+            read_blocks_calls: Default::default(),
+            write_block_calls: Default::default(),
+            write_blocks_calls: Default::default(),
+        }
+    }
+    /// Get and reset the number of read_block calls
+    fn take_read_block_calls(&self) -> usize {
+        self.read_block_calls.swap(0, Ordering::Relaxed)
+    }
+    /// Get and reset the number of read_blocks calls
+    fn take_read_blocks_calls(&self) -> usize {
+        self.read_blocks_calls.swap(0, Ordering::Relaxed)
+    }
+    /// Get and reset the number of (write_block, write_blocks) calls
+    fn take_write_calls(&self) -> (usize, usize) {
+        (
+            self.write_block_calls.swap(0, Ordering::Relaxed),
+            self.write_blocks_calls.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+impl<D: BlockDevice> BlockDevice for CountingBlockDevice<D> {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.read_block_calls.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_block(block_id, buf);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.write_block_calls.fetch_add(1, Ordering::Relaxed);
+        self.inner.write_block(block_id, buf);
+    }
+    fn read_blocks(&self, start_block_id: usize, buf: &mut [u8]) {
+        self.read_blocks_calls.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_blocks(start_block_id, buf);
+    }
+    fn write_blocks(&self, start_block_id: usize, buf: &[u8]) {
+        self.write_blocks_calls.fetch_add(1, Ordering::Relaxed);
+        self.inner.write_blocks(start_block_id, buf);
+    }
+}
+
+/// BlockDevice whose reads and writes take a while once slow is set
+#[cfg(test)]
+struct SlowBlockDevice<D> {
+    inner: D,
+    slow: AtomicBool,
+}
+
+#[cfg(test)]
+impl<D: BlockDevice> BlockDevice for SlowBlockDevice<D> {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        if self.slow.load(Ordering::Relaxed) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        self.inner.read_block(block_id, buf);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        if self.slow.load(Ordering::Relaxed) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        self.inner.write_block(block_id, buf);
+    }
+}
+
+/// BlockDevice in memory with 4096-byte sectors, like a 4K NVMe drive
+#[cfg(test)]
+struct MemBlockDevice(Mutex<Vec<u8>>);
+
+#[cfg(test)]
+const SECTOR_SZ: usize = 4096;
+
+#[cfg(test)]
+impl BlockDevice for MemBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        assert_eq!(buf.len(), SECTOR_SZ, "Not a complete sector!");
+        let start = block_id * SECTOR_SZ;
+        buf.copy_from_slice(&self.0.lock().unwrap()[start..start + SECTOR_SZ]);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        assert_eq!(buf.len(), SECTOR_SZ, "Not a complete sector!");
+        let start = block_id * SECTOR_SZ;
+        self.0.lock().unwrap()[start..start + SECTOR_SZ].copy_from_slice(buf);
+    }
+}
+
 #[test]
 fn tiny_fs_test() -> std::io::Result<()> {
+    let _guard = test_guard();
     let block_file = Arc::new(BlockFile(Mutex::new(
         {
             let f = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(true)
                 .open("target/fs.img")?;
             f.set_len(8192 * 512).unwrap();
             f
         }
     )));
-    TinyFileSystem::create(block_file.clone(), 4096, 1);
-    let tfs = TinyFileSystem::open(block_file.clone());
+    TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let tfs = TinyFileSystem::open(block_file.clone()).unwrap();
     let root_inode = TinyFileSystem::root_inode(&tfs);
     //create file test
-    root_inode.create("file_a");
-    root_inode.create("file_b");
+    root_inode.create("file_a").unwrap();
+    root_inode.create("file_b").unwrap();
     for name in root_inode.ls() {
         println!("{}", name);
     }
     //write file test
     let test_str = "hello, tiny-fs!";
     let file_a = root_inode.find("file_a").unwrap();
-    file_a.write_at(0, test_str.as_bytes());
+    file_a.write_at(0, test_str.as_bytes()).unwrap();
     //let mut buf = [0u8; 512];
     let mut buf = [0u8; 233];
     let len = file_a.read_at(0, &mut buf);
@@ -129,9 +306,9 @@ fn tiny_fs_test() -> std::io::Result<()> {
         let mut str_random = String::new();
         //create a random string and write into the file_a
         for _ in 0..len {
-            str_random.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str_random.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
-        file_a.write_at(0, str_random.as_bytes());
+        file_a.write_at(0, str_random.as_bytes()).unwrap();
         //file_a read test
         let mut read_str = String::new();
         //read one part at a time
@@ -158,4 +335,2435 @@ fn tiny_fs_test() -> std::io::Result<()> {
     random_str_test(2000 * BLOCK_SZ);
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn cursor_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("cursor.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    let data: Vec<u8> = (0..10 * BLOCK_SZ + 77).map(|i| (i % 251) as u8).collect();
+    file.write_at(0, &data).unwrap();
+    //read the file in small chunks
+    let mut cursor = file.seek_read(0);
+    let mut read_data: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 33];
+    loop {
+        let len = cursor.read(&mut buf);
+        if len == 0 {
+            break;
+        }
+        read_data.extend_from_slice(&buf[..len]);
+    }
+    assert_eq!(cursor.position(), data.len());
+    assert_eq!(read_data, file.read_all());
+    assert_eq!(read_data, data);
+    //seek back and read again
+    cursor.seek(BLOCK_SZ - 3);
+    assert_eq!(cursor.read(&mut buf), buf.len());
+    assert_eq!(&buf[..], &data[BLOCK_SZ - 3..BLOCK_SZ + 30]);
+}
+
+#[test]
+fn inline_dir_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("inline_dir.img", 4096);
+    let tfs = TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //an empty directory consumes no data block
+    let first_data_block = tfs.lock().get_data_block_id(0);
+    root_inode.create("file_a").unwrap();
+    root_inode.create("file_b").unwrap();
+    root_inode.create("file_c").unwrap();
+    let mut fs = tfs.lock();
+    let block_id = fs.alloc_data().unwrap();
+    assert_eq!(block_id, first_data_block);
+    fs.dealloc_data(block_id);
+    drop(fs);
+    //the fourth entry spills the directory into a data block
+    root_inode.create("file_d").unwrap();
+    let mut fs = tfs.lock();
+    let block_id = fs.alloc_data().unwrap();
+    assert_eq!(block_id, first_data_block + 1);
+    fs.dealloc_data(block_id);
+    drop(fs);
+    assert_eq!(root_inode.ls(), ["file_a", "file_b", "file_c", "file_d"]);
+    let tfs = TinyFileSystem::open(block_file).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert!(root_inode.find("file_a").is_some());
+    assert!(root_inode.find("file_d").is_some());
+    //clear empties the spilled directory
+    root_inode.clear();
+    assert!(root_inode.ls().is_empty());
+}
+
+#[test]
+fn bitmap_iter_allocated_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("bitmap_iter.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let mut fs = tfs.lock();
+    let first_data_block = fs.get_data_block_id(0);
+    for _ in 0..130 {
+        fs.alloc_data().unwrap();
+    }
+    for bit in [3, 64, 100] {
+        fs.dealloc_data(first_data_block + bit);
+    }
+    let expected: Vec<usize> = (0..130).filter(|bit| ![3, 64, 100].contains(bit)).collect();
+    let allocated: Vec<usize> = fs.data_bitmap.iter_allocated(&fs.block_device).collect();
+    assert_eq!(allocated, expected);
+    //only the root inode is allocated
+    let allocated: Vec<usize> = fs.inode_bitmap.iter_allocated(&fs.block_device).collect();
+    assert_eq!(allocated, [0]);
+}
+
+#[test]
+fn used_blocks_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("used_blocks.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(tfs.lock().used_data_blocks(), 0);
+    let file_a = root_inode.create("file_a").unwrap();
+    let file_b = root_inode.create("file_b").unwrap();
+    file_a.write_at(0, &[1u8; 10 * BLOCK_SZ]).unwrap();
+    //26 direct blocks, one indirect1 block and 4 more data blocks
+    file_b.write_at(0, &[2u8; 30 * BLOCK_SZ]).unwrap();
+    assert_eq!(file_a.used_blocks(), 10);
+    assert_eq!(file_b.used_blocks(), 31);
+    assert_eq!(root_inode.used_blocks(), 41);
+    assert_eq!(tfs.lock().used_data_blocks(), 41);
+    file_b.clear();
+    assert_eq!(root_inode.used_blocks(), 10);
+    assert_eq!(tfs.lock().used_data_blocks(), 10);
+}
+
+#[test]
+fn alloc_data_bound_test() {
+    let _guard = test_guard();
+    //the data bitmap covers 4096 bits but the device only has 1100 blocks
+    let block_file = test_block_file("alloc_bound.img", 1100);
+    let tfs = TinyFileSystem::create(block_file, 1100, 1).unwrap();
+    let mut fs = tfs.lock();
+    let first_data_block = fs.get_data_block_id(0);
+    let mut allocated = 0;
+    while let Ok(block_id) = fs.alloc_data() {
+        assert!(block_id < 1100);
+        allocated += 1;
+    }
+    assert_eq!(allocated, 1100 - first_data_block);
+    assert_eq!(fs.alloc_data(), Err(Error::NoSpace));
+}
+
+#[test]
+fn replace_contents_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("replace.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("config").unwrap();
+    let long: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+    file.write_at(0, &long).unwrap();
+    file.replace_contents(b"short text").unwrap();
+    assert_eq!(file.read_all(), b"short text");
+    assert_eq!(tfs.lock().used_data_blocks(), 1);
+    file.replace_contents(&long).unwrap();
+    assert_eq!(file.read_all(), long);
+    assert_eq!(tfs.lock().used_data_blocks(), 2);
+    //shrink across the indirect2 and indirect1 boundaries
+    let huge: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    file.replace_contents(&huge).unwrap();
+    assert_eq!(file.read_all(), huge);
+    assert_eq!(tfs.lock().used_data_blocks(), 200 + 3);
+    file.replace_contents(&huge[..100 * BLOCK_SZ - 1]).unwrap();
+    assert_eq!(file.read_all(), &huge[..100 * BLOCK_SZ - 1]);
+    assert_eq!(tfs.lock().used_data_blocks(), 100 + 1);
+    file.replace_contents(b"short text").unwrap();
+    assert_eq!(file.read_all(), b"short text");
+    assert_eq!(tfs.lock().used_data_blocks(), 1);
+}
+
+#[test]
+fn fs_debug_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("debug.img", 4096);
+    let created = format!("{:?}", TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap().lock());
+    let opened = format!("{:?}", TinyFileSystem::open(block_file).unwrap().lock());
+    assert_eq!(created, opened);
+    assert!(created.contains("inode_bitmap: (1, 1)"));
+}
+
+#[test]
+fn old_format_magic_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("new_format_magic.img", 4096);
+    TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    //images with an old DiskInode layout carry a previous magic
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(0, &mut block);
+    for old_magic in [0x3b800001u32, 0x3b800002] {
+        block[..4].copy_from_slice(&old_magic.to_ne_bytes());
+        let old_file = test_block_file(&format!("old_format_magic_{:x}.img", old_magic), 4096);
+        old_file.write_block(0, &block);
+        assert_eq!(TinyFileSystem::open(old_file).err(), Some(Error::BadMagic));
+    }
+}
+
+//run with `cargo test --release -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn hash_index_find_bench() {
+    let _guard = test_guard();
+    let block_file = test_block_file("hash_index_bench.img", 8192);
+    let tfs = TinyFileSystem::create(block_file, 8192, 2).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let names: Vec<String> = (0..5000).map(|i| format!("f{}", i)).collect();
+    for name in names.iter() {
+        root_inode.create(name).unwrap();
+    }
+    let find_latency = || {
+        let start = std::time::Instant::now();
+        for name in names.iter() {
+            assert!(root_inode.find(name).is_some());
+        }
+        start.elapsed() / names.len() as u32
+    };
+    let linear = find_latency();
+    root_inode.enable_hash_index().unwrap();
+    let hashed = find_latency();
+    println!("find in a 5000-entry directory: linear scan {:?}, hash index {:?}", linear, hashed);
+    assert!(hashed < linear);
+}
+
+#[test]
+fn error_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("error.img", 1100);
+    assert_eq!(TinyFileSystem::open(block_file.clone()).err(), Some(Error::BadMagic));
+    //the inode area alone needs more blocks than the device has
+    assert_eq!(TinyFileSystem::create(block_file.clone(), 100, 1).err(), Some(Error::BadGeometry));
+    let tfs = TinyFileSystem::create(block_file, 1100, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    assert_eq!(root_inode.create("file").err(), Some(Error::AlreadyExists));
+    assert_eq!(root_inode.create(&"x".repeat(28)).err(), Some(Error::NameTooLong));
+    assert_eq!(file.create("file").err(), Some(Error::NotADirectory));
+    //the device runs out of data blocks
+    let used_data_blocks = tfs.lock().used_data_blocks();
+    assert_eq!(file.write_at(0, &[1u8; 100 * BLOCK_SZ]), Err(Error::NoSpace));
+    assert_eq!(tfs.lock().used_data_blocks(), used_data_blocks);
+    assert_eq!(format!("{}", Error::NoSpace), "no space left on device");
+}
+
+#[test]
+fn inode_capacity_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("inode_capacity.img", 4096);
+    TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    //shrink the inode area to 2 blocks in the superblock
+    let mut super_block = [0u8; BLOCK_SZ];
+    block_file.read_block(0, &mut super_block);
+    super_block[12..16].copy_from_slice(&2u32.to_ne_bytes());
+    block_file.write_block(0, &super_block);
+    let tfs = TinyFileSystem::open(reopen_block_file("inode_capacity.img")).unwrap();
+    let mut fs = tfs.lock();
+    //the root inode takes bit 0 of the 8 inodes in 2 blocks
+    for inode_bit in 1..8 {
+        assert_eq!(fs.alloc_inode(), Ok(inode_bit));
+    }
+    assert_eq!(fs.alloc_inode(), Err(Error::NoSpace));
+    assert_eq!(fs.alloc_inode(), Err(Error::NoSpace));
+}
+
+#[test]
+fn ls_sorted_test() {
+    let _guard = test_guard();
+    let names = ["kernel", "app_b", "shell", "app_a", "init"];
+    let mut listings = Vec::new();
+    for (i, order) in [[0, 1, 2, 3, 4], [4, 3, 2, 1, 0], [2, 0, 4, 1, 3]].iter().enumerate() {
+        let block_file = test_block_file(&format!("ls_sorted_{}.img", i), 4096);
+        let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+        let root_inode = TinyFileSystem::root_inode(&tfs);
+        for &idx in order {
+            root_inode.create(names[idx]).unwrap();
+        }
+        listings.push(root_inode.ls_sorted());
+    }
+    assert_eq!(listings[0], ["app_a", "app_b", "init", "kernel", "shell"]);
+    assert_eq!(listings[0], listings[1]);
+    assert_eq!(listings[0], listings[2]);
+}
+
+#[test]
+fn slice_block_device_test() {
+    let _guard = test_guard();
+    //the inode area alone takes 1024 blocks with one inode bitmap block
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(SliceBlockDevice::new(buf));
+    assert_eq!(block_device.blocks(), 2048);
+    TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let tfs = TinyFileSystem::open(block_device).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    let data: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 239) as u8).collect();
+    file.write_at(0, &data).unwrap();
+    assert_eq!(root_inode.find("file").unwrap().read_all(), data);
+}
+
+#[test]
+fn pad_to_block_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("pad_to_block.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, &[7u8; 700]).unwrap();
+    assert_eq!(file.block_len(), 2);
+    file.pad_to_block().unwrap();
+    let data = file.read_all();
+    assert_eq!(data.len(), 1024);
+    assert!(data[..700].iter().all(|&b| b == 7));
+    assert!(data[700..].iter().all(|&b| b == 0));
+    assert_eq!(file.block_len(), 2);
+    //already aligned
+    file.pad_to_block().unwrap();
+    assert_eq!(file.read_all().len(), 1024);
+}
+
+#[test]
+fn hash_index_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("hash_index.img", 4096);
+    let tfs = TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    for i in 0..100 {
+        let name = format!("f{}", i);
+        root_inode.create(&name).unwrap().write_at(0, name.as_bytes()).unwrap();
+    }
+    root_inode.enable_hash_index().unwrap();
+    //the table is rebuilt on the way
+    for i in 100..300 {
+        let name = format!("f{}", i);
+        root_inode.create(&name).unwrap().write_at(0, name.as_bytes()).unwrap();
+    }
+    assert_eq!(root_inode.create("f42").err(), Some(Error::AlreadyExists));
+    assert!(root_inode.find("f300").is_none());
+    //300 files of one block, 19 dir_entry blocks and a 1024-slot index
+    assert_eq!(root_inode.used_blocks(), 300 + 19 + 9);
+    drop(root_inode);
+    drop(tfs);
+    let tfs = TinyFileSystem::open(block_file).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    for i in 0..300 {
+        let name = format!("f{}", i);
+        assert_eq!(root_inode.find(&name).unwrap().read_all(), name.as_bytes());
+    }
+    root_inode.clear();
+    assert!(root_inode.find("f0").is_none());
+    //only the files themselves are left
+    assert_eq!(tfs.lock().used_data_blocks(), 300);
+}
+
+#[test]
+fn clear_no_zero_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("clear_no_zero.img", 4096);
+    let tfs = TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    let mut block = [0u8; BLOCK_SZ];
+    //zeroing is the default
+    file.write_at(0, &[0xaa; BLOCK_SZ]).unwrap();
+    file.clear();
+    let block_id = tfs.lock().alloc_data().unwrap();
+    block_file.read_block(block_id as usize, &mut block);
+    assert!(block.iter().all(|&b| b == 0));
+    tfs.lock().dealloc_data(block_id);
+    //lazily freed blocks keep their content
+    file.write_at(0, &[0xaa; 20 * BLOCK_SZ]).unwrap();
+    file.clear_no_zero();
+    assert_eq!(tfs.lock().used_data_blocks(), 0);
+    let block_id = tfs.lock().alloc_data().unwrap();
+    block_file.read_block(block_id as usize, &mut block);
+    assert!(block.iter().all(|&b| b == 0xaa));
+    tfs.lock().dealloc_data_no_zero(block_id);
+    //but rewritten files never expose it
+    file.write_at(0, &[1u8; 700]).unwrap();
+    assert_eq!(file.read_all(), vec![1u8; 700]);
+    file.write_at(1000, &[2u8; 24]).unwrap();
+    let data = file.read_all();
+    assert!(data[700..1000].iter().all(|&b| b == 0));
+    assert_eq!(data[1000..], [2u8; 24]);
+    file.clear_no_zero();
+    file.write_at(0, &[3u8; 100]).unwrap();
+    file.pad_to_block().unwrap();
+    let data = file.read_all();
+    assert_eq!(data.len(), BLOCK_SZ);
+    assert!(data[100..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn write_at_tracked_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("write_at_tracked.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    let (size, direct_blocks) = file.write_at_tracked(0, &[1u8; 26 * BLOCK_SZ]).unwrap();
+    assert_eq!(size, 26 * BLOCK_SZ);
+    assert_eq!(direct_blocks.len(), 26);
+    //the last direct block, one indirect1 block and 2 more data blocks
+    let used = tfs.lock().used_data_blocks();
+    let (size, new_blocks) = file.write_at_tracked(25 * BLOCK_SZ, &[2u8; 4 * BLOCK_SZ]).unwrap();
+    assert_eq!(size, 4 * BLOCK_SZ);
+    assert_eq!(new_blocks.len(), 4);
+    assert_eq!(tfs.lock().used_data_blocks(), used + 4);
+    for (i, block_id) in new_blocks.iter().enumerate() {
+        assert!(!direct_blocks.contains(block_id));
+        assert!(!new_blocks[..i].contains(block_id));
+    }
+    //no block needed
+    let (_, new_blocks) = file.write_at_tracked(0, &[3u8; BLOCK_SZ]).unwrap();
+    assert!(new_blocks.is_empty());
+}
+
+#[test]
+fn same_file_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("same_file.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file_a = root_inode.create("file_a").unwrap();
+    root_inode.create("file_b").unwrap();
+    let found_a = root_inode.find("file_a").unwrap();
+    assert!(file_a.same_file(&found_a));
+    assert!(found_a.same_file(&root_inode.find("file_a").unwrap()));
+    assert!(!file_a.same_file(&root_inode.find("file_b").unwrap()));
+    assert!(!file_a.same_file(&root_inode));
+}
+
+#[test]
+fn read_all_direct_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("read_all_direct.img", 4096);
+    let tfs = TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file_a = root_inode.create("file_a").unwrap();
+    let file_b = root_inode.create("file_b").unwrap();
+    //interleave the blocks of both files, the indirect1 block included
+    for i in 0..40 {
+        let data: Vec<u8> = (0..BLOCK_SZ).map(|_| rand::random::<u8>()).collect();
+        file_a.write_at(i * BLOCK_SZ, &data).unwrap();
+        file_b.write_at(i * 2 * BLOCK_SZ, &data).unwrap();
+    }
+    file_a.write_at(40 * BLOCK_SZ, &[7u8; 100]).unwrap();
+    for file in [&file_a, &file_b] {
+        assert_eq!(file.read_all_direct(), file.read_all());
+    }
+    //inline directory
+    assert_eq!(root_inode.read_all_direct(), root_inode.read_all());
+    //a whole image copy is still a valid file system
+    let copy_file = test_block_file("read_all_direct_copy.img", 4096);
+    block_file.copy_range(copy_file.as_ref(), 0, 4096);
+    let copy_tfs = TinyFileSystem::open(copy_file).unwrap();
+    let copy_root = TinyFileSystem::root_inode(&copy_tfs);
+    assert_eq!(copy_root.ls_sorted(), root_inode.ls_sorted());
+    assert_eq!(copy_root.find("file_a").unwrap().read_all(), file_a.read_all());
+}
+
+#[test]
+fn create_file_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("create_file.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    for len in [0, 10, BLOCK_SZ, 30 * BLOCK_SZ + 7] {
+        let data: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+        let two_step = root_inode.create(&format!("two_step_{}", len)).unwrap();
+        two_step.write_at(0, &data).unwrap();
+        let one_step = root_inode.create_file(&format!("one_step_{}", len), &data).unwrap();
+        assert_eq!(one_step.read_all(), two_step.read_all());
+        assert_eq!(one_step.used_blocks(), two_step.used_blocks());
+    }
+    assert_eq!(
+        root_inode.create_file("one_step_10", b"again").err(),
+        Some(Error::AlreadyExists)
+    );
+    assert_eq!(root_inode.find("one_step_10").unwrap().read_all().len(), 10);
+}
+
+#[test]
+fn dedup_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("dedup.img", 4096);
+    let tfs = TinyFileSystem::create_dedup(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert!(tfs.lock().is_dedup());
+    let data: Vec<u8> = (0..4 * BLOCK_SZ).map(|_| rand::random::<u8>()).collect();
+    let file_a = root_inode.create_file("file_a", &data).unwrap();
+    let file_b = root_inode.create_file("file_b", &data).unwrap();
+    assert_eq!(tfs.lock().used_data_blocks(), 4);
+    assert_eq!(file_b.read_all(), data);
+    //writing a shared block copies it first
+    file_b.write_at(BLOCK_SZ + 1, b"changed").unwrap();
+    assert_eq!(tfs.lock().used_data_blocks(), 5);
+    assert_eq!(file_a.read_all(), data);
+    let mut changed = data.clone();
+    changed[BLOCK_SZ + 1..BLOCK_SZ + 8].copy_from_slice(b"changed");
+    assert_eq!(file_b.read_all(), changed);
+    //a shared block is freed with its last owner
+    file_a.clear();
+    assert_eq!(tfs.lock().used_data_blocks(), 4);
+    assert_eq!(file_b.read_all(), changed);
+    file_b.clear();
+    assert_eq!(tfs.lock().used_data_blocks(), 0);
+}
+
+#[test]
+fn dedup_reopen_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("dedup_reopen.img", 4096);
+    let tfs = TinyFileSystem::create_dedup(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data = [9u8; 2 * BLOCK_SZ];
+    root_inode.create_file("file_a", &data).unwrap();
+    root_inode.create_file("file_b", &data).unwrap();
+    drop(root_inode);
+    drop(tfs);
+    //reference counts live on disk
+    let tfs = TinyFileSystem::open(reopen_block_file("dedup_reopen.img")).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(tfs.lock().used_data_blocks(), 1);
+    root_inode.find("file_a").unwrap().clear();
+    let file_b = root_inode.find("file_b").unwrap();
+    assert_eq!(file_b.read_all(), data);
+    file_b.clear();
+    assert_eq!(tfs.lock().used_data_blocks(), 0);
+}
+
+#[test]
+fn fill_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("fill.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, &[1u8; 10]).unwrap();
+    file.fill(100, 100 * BLOCK_SZ, 0xab).unwrap();
+    let data = file.read_all();
+    assert_eq!(data.len(), 100 + 100 * BLOCK_SZ);
+    assert_eq!(data[..10], [1u8; 10]);
+    assert!(data[10..100].iter().all(|&b| b == 0));
+    assert!(data[100..].iter().all(|&b| b == 0xab));
+    //fill inside the file does not grow it
+    file.fill(5, 10, 0).unwrap();
+    let data = file.read_all();
+    assert_eq!(data.len(), 100 + 100 * BLOCK_SZ);
+    assert_eq!(data[..5], [1u8; 5]);
+    assert!(data[5..15].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn create_dir_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("create_dir.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let dir = root_inode.create_dir("dir").unwrap();
+    assert_eq!(dir.ls(), vec![".", ".."]);
+    assert!(dir.find(".").unwrap().same_file(&dir));
+    assert!(dir.find("..").unwrap().same_file(&root_inode));
+    let sub_dir = dir.create_dir("sub_dir").unwrap();
+    assert!(sub_dir.find("..").unwrap().same_file(&dir));
+    sub_dir.create_file("file", &[1u8; BLOCK_SZ]).unwrap();
+    assert_eq!(dir.ls_sorted(), vec![".", "..", "sub_dir"]);
+    assert_eq!(root_inode.used_blocks(), 1);
+    //the target is kept inline
+    let link = dir.create_symlink("link", "sub_dir/file").unwrap();
+    assert_eq!(link.read_all(), b"sub_dir/file");
+    assert_eq!(link.used_blocks(), 0);
+    assert_eq!(dir.create_symlink("long", &"x".repeat(97)).err(), Some(Error::NameTooLong));
+}
+
+/// Run f and check that it hits a fault injected by FaultyBlockDevice
+#[cfg(test)]
+fn expect_fault<R>(f: impl FnOnce() -> R) {
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).err().unwrap();
+    assert_eq!(err.downcast_ref::<String>().map(String::as_str), Some(FAULT_MARKER));
+}
+
+#[test]
+fn faulty_block_device_test() {
+    let _guard = test_guard();
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(FaultyBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    let (_, blocks) = file.write_at_tracked(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    //a failed write back leaves the block dirty, the next sync retries it
+    block_device.fail_next_write(blocks[2] as usize);
+    expect_fault(|| file.write_at(0, &[2u8; 4 * BLOCK_SZ]));
+    tfs.lock().sync();
+    assert_eq!(file.read_all_direct(), vec![2u8; 4 * BLOCK_SZ]);
+    //every write fails once the budget is used up
+    let writes = block_device.writes();
+    block_device.fail_after_writes(1);
+    expect_fault(|| file.write_at(0, &[3u8; 4 * BLOCK_SZ]));
+    assert_eq!(block_device.writes(), writes + 1);
+    expect_fault(|| tfs.lock().sync());
+    block_device.clear_faults();
+    tfs.lock().sync();
+    assert_eq!(file.read_all_direct(), vec![3u8; 4 * BLOCK_SZ]);
+    //reads bypassing the cache fail as well
+    block_device.fail_next_read(blocks[0] as usize);
+    expect_fault(|| file.read_all_direct());
+    assert_eq!(file.read_all_direct(), vec![3u8; 4 * BLOCK_SZ]);
+}
+
+#[test]
+fn read_dir_into_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("read_dir_into.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let dir = root_inode.create_dir("dir").unwrap();
+    let mut entries = vec![(String::from("stale_entry_with_long_name"), 42); 5];
+    root_inode.read_dir_into(&mut entries);
+    assert_eq!(entries, vec![(String::from("dir"), 1)]);
+    dir.create("file_a").unwrap();
+    dir.create("b").unwrap();
+    dir.read_dir_into(&mut entries);
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec![".", "..", "file_a", "b"]);
+    assert_eq!(entries[0].1, 1);
+    assert_eq!(entries[1].1, 0);
+    assert_eq!(dir.ls(), names);
+}
+
+#[test]
+fn free_counts_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("free_counts.img", 4096);
+    let tfs = TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let (free_inodes, free_data_blocks) = tfs.lock().free_counts();
+    let files: Vec<_> = (0..8)
+        .map(|i| root_inode.create_file(&format!("f{}", i), &vec![1u8; i * 20 * BLOCK_SZ]).unwrap())
+        .collect();
+    for file in files.iter().step_by(2) {
+        file.clear();
+    }
+    files[1].write_at(0, &[2u8; 100 * BLOCK_SZ]).unwrap();
+    let used = tfs.lock().used_data_blocks();
+    assert_eq!(tfs.lock().free_counts(), (free_inodes - 8, free_data_blocks - used));
+    assert!(tfs.lock().verify_free_counts());
+    drop(files);
+    drop(root_inode);
+    drop(tfs);
+    //a drifted count is repaired by a full scan
+    let mut super_block = [0u8; BLOCK_SZ];
+    block_file.read_block(0, &mut super_block);
+    super_block[32..36].copy_from_slice(&7u32.to_ne_bytes());
+    block_file.write_block(0, &super_block);
+    let tfs = TinyFileSystem::open(reopen_block_file("free_counts.img")).unwrap();
+    assert_eq!(tfs.lock().free_counts().1, 7);
+    assert!(!tfs.lock().verify_free_counts());
+    assert_eq!(tfs.lock().free_counts(), (free_inodes - 8, free_data_blocks - used));
+}
+
+#[test]
+fn defragment_test() {
+    let _guard = test_guard();
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(CountingBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file_a = root_inode.create("file_a").unwrap();
+    let file_b = root_inode.create("file_b").unwrap();
+    //interleave the blocks of both files
+    for i in 0..40 {
+        let data: Vec<u8> = (0..BLOCK_SZ).map(|_| rand::random::<u8>()).collect();
+        file_a.write_at(i * BLOCK_SZ, &data).unwrap();
+        file_b.write_at(i * BLOCK_SZ, &data).unwrap();
+    }
+    let data_a = file_a.read_all();
+    let used = tfs.lock().used_data_blocks();
+    block_device.take_read_blocks_calls();
+    assert_eq!(file_a.read_all_direct(), data_a);
+    assert_eq!(block_device.take_read_blocks_calls(), 40);
+    tfs.lock().defragment_inode(&file_a).unwrap();
+    assert_eq!(file_a.read_all_direct(), data_a);
+    assert_eq!(block_device.take_read_blocks_calls(), 1);
+    assert_eq!(file_a.read_all(), data_a);
+    assert_eq!(tfs.lock().used_data_blocks(), used);
+    assert!(tfs.lock().verify_free_counts());
+    //no room for a contiguous run
+    while tfs.lock().free_counts().1 >= 40 {
+        tfs.lock().alloc_data().unwrap();
+    }
+    assert_eq!(tfs.lock().defragment_inode(&file_b), Err(Error::NoSpace));
+}
+
+#[test]
+fn dump_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("dump.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create_file("small", &[1u8; 10]).unwrap();
+    root_inode.create_file("large", &[2u8; 30 * BLOCK_SZ]).unwrap();
+    let dir = root_inode.create_dir("dir").unwrap();
+    dir.create_file("nested", &[3u8; 700]).unwrap();
+    let dump = tfs.lock().dump();
+    assert_eq!(dump.total_blocks, 4096);
+    assert_eq!(dump.inode_bitmap_blocks, 1);
+    assert_eq!(dump.used_inodes, 5);
+    assert_eq!(dump.used_data_blocks, tfs.lock().used_data_blocks());
+    let mut entries = Vec::new();
+    root_inode.read_dir_into(&mut entries);
+    let mut dir_entries = Vec::new();
+    dir.read_dir_into(&mut dir_entries);
+    entries.push(dir_entries.pop().unwrap());
+    let expected = [("small", "file", 10, 1), ("large", "file", 30 * BLOCK_SZ, 30),
+                    ("dir", "dir", 96, 0), ("nested", "file", 700, 2)];
+    for (name, kind, size, blocks) in expected {
+        let inode = entries.iter().find(|(n, _)| n == name).unwrap().1;
+        let inode_dump = dump.inodes.iter().find(|i| i.inode == inode).unwrap();
+        assert_eq!(inode_dump.kind, kind);
+        assert_eq!(inode_dump.size as usize, size);
+        assert_eq!(inode_dump.blocks.len(), blocks);
+    }
+    assert_eq!(dump.inodes[0].kind, "dir");
+}
+
+#[test]
+fn sync_write_combining_test() {
+    let _guard = test_guard();
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(CountingBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    block_device.take_write_calls();
+    let data: Vec<u8> = (0..8 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    file.write_at(0, &data).unwrap();
+    //8 data blocks in a row, plus the superblock, inode and bitmap blocks
+    let (write_block_calls, write_blocks_calls) = block_device.take_write_calls();
+    assert!(write_blocks_calls >= 1);
+    assert!(write_block_calls + write_blocks_calls < 8);
+    assert_eq!(file.read_all_direct(), data);
+}
+
+#[test]
+fn splice_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("splice.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let mut expected: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let file = root_inode.create_file("file", &expected).unwrap();
+    //insert
+    file.splice(500, 0, b"hello").unwrap();
+    expected.splice(500..500, *b"hello");
+    assert_eq!(file.read_all(), expected);
+    //replace with a longer range, growing into a new block
+    file.splice(10, 2, &[7u8; 100]).unwrap();
+    expected.splice(10..12, [7u8; 100]);
+    assert_eq!(file.read_all(), expected);
+    assert_eq!(file.block_len(), 3);
+    //delete, shrinking back to 2 blocks
+    file.splice(0, 200, &[]).unwrap();
+    expected.splice(0..200, []);
+    assert_eq!(file.read_all(), expected);
+    assert_eq!(file.block_len(), 2);
+    //append, and removal past the end is clamped
+    let size = expected.len();
+    file.splice(size, 10, b"end").unwrap();
+    expected.extend_from_slice(b"end");
+    assert_eq!(file.read_all(), expected);
+    assert_eq!(file.splice(size + 4, 0, b"x"), Err(Error::OutOfBounds));
+}
+
+#[test]
+fn truncate_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("truncate.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let contents: Vec<u8> = (0..3 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let file = root_inode.create_file("file", &contents).unwrap();
+    let (_, free_before) = tfs.lock().free_counts();
+    //shrink frees the blocks past the new end
+    file.truncate(BLOCK_SZ + 10).unwrap();
+    assert_eq!(file.read_all(), &contents[..BLOCK_SZ + 10]);
+    assert_eq!(file.block_len(), 2);
+    let (_, free_after) = tfs.lock().free_counts();
+    assert_eq!(free_after, free_before + 1);
+    //grow fills with zeros, not the bytes that were cut off
+    file.truncate(2 * BLOCK_SZ).unwrap();
+    let data = file.read_all();
+    assert_eq!(&data[..BLOCK_SZ + 10], &contents[..BLOCK_SZ + 10]);
+    assert!(data[BLOCK_SZ + 10..].iter().all(|&b| b == 0));
+    //replace_contents with a shorter buffer shrinks as well
+    file.replace_contents(b"tiny").unwrap();
+    assert_eq!(file.read_all(), b"tiny");
+    assert_eq!(file.block_len(), 1);
+}
+
+#[test]
+fn read_exact_at_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("read_exact_at.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", b"hello").unwrap();
+    let mut buf = [0u8; 10];
+    assert_eq!(file.read_exact_at(0, &mut buf), Err(Error::UnexpectedEof));
+    let mut buf = [0u8; 3];
+    file.read_exact_at(2, &mut buf).unwrap();
+    assert_eq!(&buf, b"llo");
+    assert_eq!(file.read_exact_at(3, &mut buf), Err(Error::UnexpectedEof));
+}
+
+#[test]
+fn warm_cache_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("warm_cache.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create_file("file_a", b"a").unwrap();
+    root_inode.create_file("file_b", b"b").unwrap();
+    tfs.lock().sync();
+    //a new device has nothing in the block cache
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("target/warm_cache.img")
+        .unwrap();
+    let block_device = Arc::new(CountingBlockDevice::new(BlockFile(Mutex::new(f))));
+    let tfs = TinyFileSystem::open(block_device.clone()).unwrap();
+    tfs.lock().warm_cache();
+    block_device.take_read_block_calls();
+    //the root dir_entries are inline, so ls only needs the root inode block
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.ls_sorted(), ["file_a", "file_b"]);
+    assert_eq!(block_device.take_read_block_calls(), 0);
+}
+
+#[test]
+fn clear_block_cache_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("clear_block_cache.img", 4096);
+    let tfs = TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data: Vec<u8> = (0..3 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    root_inode.create_file("file", &data).unwrap();
+    drop(root_inode);
+    drop(tfs);
+    //everything is read back from the device after the cache is gone
+    clear_block_cache();
+    let tfs = TinyFileSystem::open(block_file).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.find("file").unwrap().read_all(), data);
+}
+
+#[test]
+fn dir_entry_size_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("dir_entry_size.img", 4096);
+    assert_eq!(
+        TinyFileSystem::create_with_dir_entry_size(block_file.clone(), 4096, 1, 48).err(),
+        Some(Error::BadGeometry)
+    );
+    let tfs = TinyFileSystem::create_with_dir_entry_size(block_file, 4096, 1, 64).unwrap();
+    assert_eq!(tfs.lock().name_length_limit(), 59);
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let long_name = "a".repeat(50);
+    root_inode.create_file(&long_name, b"long").unwrap();
+    assert_eq!(root_inode.create(&"b".repeat(60)).err(), Some(Error::NameTooLong));
+    //`.` and `..` no longer fit inline
+    let dir = root_inode.create_dir("dir").unwrap();
+    assert_eq!(dir.ls(), [".", ".."]);
+    drop(root_inode);
+    drop(dir);
+    drop(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("dir_entry_size.img")).unwrap();
+    assert_eq!(tfs.lock().dir_entry_size(), 64);
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.ls(), [long_name.as_str(), "dir"]);
+    assert_eq!(root_inode.find(&long_name).unwrap().read_all(), b"long");
+    let dir = root_inode.find("dir").unwrap();
+    assert!(dir.find("..").unwrap().same_file(&root_inode));
+}
+
+#[test]
+fn create_dir_no_space_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("create_dir_no_space.img", 1100);
+    //two 64-byte entries do not fit inline, each directory takes a block
+    let tfs = TinyFileSystem::create_with_dir_entry_size(block_file, 1100, 1, 64).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let mut created = 0;
+    let (err, free_counts) = loop {
+        let free_counts = tfs.lock().free_counts();
+        match root_inode.create_dir(&format!("d{}", created)) {
+            Ok(dir) => assert_eq!(dir.ls(), [".", ".."]),
+            Err(err) => break (err, free_counts),
+        }
+        created += 1;
+    };
+    assert_eq!(err, Error::NoSpace);
+    //the directory that did not fit is not linked without its entries
+    assert!(root_inode.find(&format!("d{}", created)).is_none());
+    assert_eq!(root_inode.ls().len(), created);
+    assert_eq!(tfs.lock().free_counts(), free_counts);
+    assert!(tfs.lock().verify_free_counts());
+}
+
+#[test]
+fn verify_contents_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("verify_contents.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let mut data: Vec<u8> = (0..3 * BLOCK_SZ + 100).map(|i| (i % 251) as u8).collect();
+    let file = root_inode.create_file("file", &data).unwrap();
+    assert!(file.verify_contents(&data));
+    data[2 * BLOCK_SZ + 7] ^= 1;
+    assert!(!file.verify_contents(&data));
+    data[2 * BLOCK_SZ + 7] ^= 1;
+    assert!(!file.verify_contents(&data[..data.len() - 1]));
+    assert!(root_inode.create("empty").unwrap().verify_contents(&[]));
+}
+
+#[test]
+fn discard_front_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("discard_front.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let mut data: Vec<u8> = (0..5 * BLOCK_SZ + 100).map(|i| (i % 251) as u8).collect();
+    let log = root_inode.create_file("log", &data).unwrap();
+    let (_, free_before) = tfs.lock().free_counts();
+    //two whole leading blocks fall before the new start
+    log.discard_front(2 * BLOCK_SZ + 10).unwrap();
+    data.drain(..2 * BLOCK_SZ + 10);
+    assert_eq!(log.read_all(), data);
+    assert_eq!(log.block_len(), 4);
+    assert_eq!(tfs.lock().free_counts().1, free_before + 2);
+    //offsets are relative to the new start
+    let mut buf = [0u8; 4];
+    log.read_at(BLOCK_SZ, &mut buf);
+    assert_eq!(buf, data[BLOCK_SZ..BLOCK_SZ + 4]);
+    let more = [7u8; 2 * BLOCK_SZ];
+    log.write_at(data.len(), &more).unwrap();
+    data.extend_from_slice(&more);
+    assert_eq!(log.read_all(), data);
+    assert_eq!(log.read_all_direct(), data);
+    assert!(log.verify_contents(&data));
+    assert_eq!(log.discard_front(data.len() + 1), Err(Error::OutOfBounds));
+    log.discard_front(data.len()).unwrap();
+    assert!(log.read_all().is_empty());
+    assert!(tfs.lock().verify_free_counts());
+    assert_eq!(root_inode.discard_front(1), Err(Error::NotAFile));
+}
+
+#[test]
+fn create_unique_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("create_unique.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create("tmp1").unwrap();
+    let (name_a, file_a) = root_inode.create_unique("tmp").unwrap();
+    let (name_b, file_b) = root_inode.create_unique("tmp").unwrap();
+    let (name_c, _) = root_inode.create_unique("tmp").unwrap();
+    assert_eq!([name_a.as_str(), name_b.as_str(), name_c.as_str()], ["tmp0", "tmp2", "tmp3"]);
+    assert!(!file_a.same_file(&file_b));
+    assert!(root_inode.find("tmp2").unwrap().same_file(&file_b));
+    assert_eq!(root_inode.create_unique(&"x".repeat(27)).err(), Some(Error::NameTooLong));
+}
+
+#[test]
+fn read_raw_dirents_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("read_raw_dirents.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    for i in 0..10 {
+        root_inode.create(&format!("file{}", i)).unwrap();
+    }
+    root_inode.create_dir("dir").unwrap();
+    let dirents = root_inode.read_raw_dirents();
+    let names: Vec<&str> = dirents.iter().map(|dir_entry| dir_entry.name()).collect();
+    assert_eq!(names, root_inode.ls());
+    for dir_entry in dirents.iter() {
+        let (block_id, offset) = tfs.lock().get_disk_inode_pos(dir_entry.inode_number());
+        let block_device = tfs.lock().block_device.clone();
+        let expected = Inode::new(block_id, offset, tfs.clone(), block_device);
+        assert!(root_inode.find(dir_entry.name()).unwrap().same_file(&expected));
+    }
+}
+
+#[test]
+fn close_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("close.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data: Vec<u8> = (0..2 * BLOCK_SZ + 10).map(|i| (i % 251) as u8).collect();
+    root_inode.create("file").unwrap().write_at(0, &data).unwrap();
+    drop(root_inode);
+    TinyFileSystem::close(tfs);
+    //a new device only sees what reached the image
+    let tfs = TinyFileSystem::open(reopen_block_file("close.img")).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.find("file").unwrap().read_all(), data);
+}
+
+#[test]
+fn extents_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("extents.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file_a = root_inode.create_file("file_a", &[1u8; 5 * BLOCK_SZ]).unwrap();
+    let extents = file_a.extents();
+    assert_eq!(extents.len(), 1);
+    assert_eq!(extents[0].1, 5);
+    assert!(root_inode.create("empty").unwrap().extents().is_empty());
+    //interleave the blocks of two files
+    let file_b = root_inode.create("file_b").unwrap();
+    let file_c = root_inode.create("file_c").unwrap();
+    for i in 0..4 {
+        file_b.write_at(i * BLOCK_SZ, &[2u8; BLOCK_SZ]).unwrap();
+        file_c.write_at(i * BLOCK_SZ, &[3u8; BLOCK_SZ]).unwrap();
+    }
+    let extents = file_b.extents();
+    assert_eq!(extents.len(), 4);
+    assert!(extents.iter().all(|&(_, len)| len == 1));
+    tfs.lock().defragment_inode(&file_b).unwrap();
+    assert_eq!(file_b.extents().len(), 1);
+    assert_eq!(file_b.read_all(), [2u8; 4 * BLOCK_SZ]);
+}
+
+#[test]
+fn bitmap_self_check_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("bitmap_self_check.img", 4096);
+    let tfs = TinyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create_file("file", &[0xffu8; BLOCK_SZ]).unwrap();
+    TinyFileSystem::close(tfs);
+    assert!(TinyFileSystem::open(reopen_block_file("bitmap_self_check.img")).is_ok());
+    //one more inode area block moves the data bitmap onto the file data
+    let mut super_block = [0u8; BLOCK_SZ];
+    block_file.read_block(0, &mut super_block);
+    let inode_area_blocks = u32::from_ne_bytes(super_block[12..16].try_into().unwrap());
+    super_block[12..16].copy_from_slice(&(inode_area_blocks + 1).to_ne_bytes());
+    block_file.write_block(0, &super_block);
+    assert_eq!(
+        TinyFileSystem::open(reopen_block_file("bitmap_self_check.img")).err(),
+        Some(Error::Corrupt)
+    );
+}
+
+#[test]
+fn mode_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("mode.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    assert_eq!(file.mode(), 0o644);
+    assert_eq!(root_inode.create_dir("dir").unwrap().mode(), 0o755);
+    assert_eq!(file.owner(), (0, 0));
+    file.set_owner(1000, 100);
+    assert!(file.can_write(1000, 100));
+    assert!(!file.can_write(1001, 100));
+    file.set_mode(0o664);
+    assert!(file.can_write(1001, 100));
+    assert!(!file.can_write(1001, 101));
+    assert!(file.can_write(0, 0));
+    drop(file);
+    drop(root_inode);
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("mode.img")).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.find("file").unwrap();
+    assert_eq!(file.mode(), 0o664);
+    assert_eq!(file.owner(), (1000, 100));
+}
+
+#[test]
+fn clone_to_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("clone_src.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    root_inode.create_file("big", &data).unwrap();
+    root_inode.create_dir("dir").unwrap().create_file("small", b"small").unwrap();
+    let dest: Arc<dyn BlockDevice> = test_block_file("clone_dest.img", 4096);
+    tfs.lock().clone_to(&dest);
+    //the source changes after the clone
+    root_inode.create("later").unwrap();
+    let clone = TinyFileSystem::open(dest).unwrap();
+    let clone_root = TinyFileSystem::root_inode(&clone);
+    assert_eq!(clone_root.ls(), ["big", "dir"]);
+    assert_eq!(clone_root.find("big").unwrap().read_all(), data);
+    let dir = clone_root.find("dir").unwrap();
+    assert_eq!(dir.find("small").unwrap().read_all(), b"small");
+}
+
+#[cfg(test)]
+static IO_EVENTS: Mutex<Vec<IoEvent>> = Mutex::new(Vec::new());
+
+#[test]
+fn io_logger_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("io_logger.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", &[1u8; 20 * BLOCK_SZ]).unwrap();
+    let big = root_inode.create_file("big", &[3u8; 300 * BLOCK_SZ]).unwrap();
+    let data_block = file.extents()[0].0 as usize;
+    let (inode_block, _) = tfs.lock().get_disk_inode_pos(1);
+    let inode_block = inode_block as usize;
+    clear_block_cache();
+    IO_EVENTS.lock().unwrap().clear();
+    set_io_logger(|event| IO_EVENTS.lock().unwrap().push(event));
+    let mut buf = [0u8; 16];
+    file.read_at(0, &mut buf);
+    file.write_at(0, &[2u8; 16]).unwrap();
+    let events: Vec<IoEvent> = IO_EVENTS.lock().unwrap().drain(..).collect();
+    assert_eq!(events, [
+        IoEvent::CacheMiss(inode_block),
+        IoEvent::Read(inode_block),
+        IoEvent::CacheMiss(data_block),
+        IoEvent::Read(data_block),
+        IoEvent::CacheHit(inode_block),
+        IoEvent::CacheHit(data_block),
+        //writing in place leaves the disk_inode clean
+        IoEvent::Write(data_block),
+    ]);
+    //reading a file larger than the cache cannot keep every block cached
+    big.read_all();
+    clear_io_logger();
+    let events: Vec<IoEvent> = IO_EVENTS.lock().unwrap().drain(..).collect();
+    assert!(events.iter().any(|event| matches!(event, IoEvent::Evict(_))));
+    file.read_all();
+    assert!(IO_EVENTS.lock().unwrap().is_empty());
+}
+
+#[test]
+fn zero_range_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("zero_range.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data: Vec<u8> = (0..50 * BLOCK_SZ).map(|i| (i % 251 + 1) as u8).collect();
+    let file = root_inode.create_file("file", &data).unwrap();
+    let used_data_blocks = tfs.lock().used_data_blocks();
+    let used_blocks = file.used_blocks();
+    //blocks 11..30 are covered whole, 10 and 30 only in part
+    let (offset, len) = (10 * BLOCK_SZ + 100, 20 * BLOCK_SZ);
+    file.zero_range(offset, len).unwrap();
+    assert_eq!(tfs.lock().used_data_blocks(), used_data_blocks - 19);
+    assert_eq!(file.used_blocks(), used_blocks - 19);
+    assert!(tfs.lock().verify_free_counts());
+    let mut expected = data.clone();
+    expected[offset..offset + len].fill(0);
+    assert_eq!(file.read_all(), expected);
+    assert_eq!(file.read_all_direct(), expected);
+    assert_eq!(file.extents()[1], (0, 19));
+    //writing into the hole backs it with blocks again
+    file.write_at(20 * BLOCK_SZ + 7, &[0xaau8; 10]).unwrap();
+    expected[20 * BLOCK_SZ + 7..20 * BLOCK_SZ + 17].fill(0xaa);
+    assert_eq!(tfs.lock().used_data_blocks(), used_data_blocks - 18);
+    assert_eq!(file.read_all(), expected);
+    assert_eq!(root_inode.zero_range(0, 1), Err(Error::NotAFile));
+    file.clear();
+    assert_eq!(tfs.lock().used_data_blocks(), used_data_blocks - used_blocks);
+}
+
+#[test]
+fn range_lock_test() {
+    let _guard = test_guard();
+    let buf = Box::leak(vec![0u8; 4096 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(SlowBlockDevice {
+        inner: SliceBlockDevice::new(buf),
+        slow: AtomicBool::new(false),
+    });
+    let tfs = TinyFileSystem::create(block_device.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let half = 200 * BLOCK_SZ;
+    let file = root_inode.create_file("file", &vec![0u8; 2 * half]).unwrap();
+    //writers of disjoint halves, with writes crossing block boundaries
+    let writers: Vec<_> = (0..2)
+        .map(|i| {
+            let file = Arc::clone(&file);
+            std::thread::spawn(move || {
+                for round in 0..4u8 {
+                    for chunk in (0..half).step_by(1000) {
+                        let len = 1000.min(half - chunk);
+                        file.write_at(i * half + chunk, &vec![round * 2 + i as u8; len]).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    writers.into_iter().for_each(|writer| writer.join().unwrap());
+    let mut expected = vec![6u8; half];
+    expected.extend_from_slice(&vec![7u8; half]);
+    assert!(file.verify_contents(&expected));
+    //a small write to the second half does not wait for a slow one to the first
+    clear_block_cache();
+    block_device.slow.store(true, Ordering::Relaxed);
+    let first_done = Arc::new(AtomicBool::new(false));
+    let first = {
+        let (file, first_done) = (Arc::clone(&file), Arc::clone(&first_done));
+        std::thread::spawn(move || {
+            file.write_at(0, &vec![1u8; half]).unwrap();
+            first_done.store(true, Ordering::Relaxed);
+        })
+    };
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    file.write_at(half, &[2u8; 10]).unwrap();
+    assert!(!first_done.load(Ordering::Relaxed));
+    first.join().unwrap();
+    block_device.slow.store(false, Ordering::Relaxed);
+    expected[..half].fill(1);
+    expected[half..half + 10].fill(2);
+    assert_eq!(file.read_all(), expected);
+}
+
+#[test]
+fn preallocate_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("preallocate.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", b"head").unwrap();
+    let used_blocks = file.used_blocks();
+    let free_data_blocks = tfs.lock().free_counts().1 + used_blocks;
+    file.preallocate(20 * BLOCK_SZ).unwrap();
+    assert_eq!(file.used_blocks(), 20);
+    assert_eq!(tfs.lock().free_counts().1, free_data_blocks - 20);
+    let mut expected = vec![0u8; 20 * BLOCK_SZ];
+    expected[..4].copy_from_slice(b"head");
+    assert_eq!(file.read_all(), expected);
+    //holes are backed again, and a shorter len never shrinks
+    file.zero_range(5 * BLOCK_SZ, 10 * BLOCK_SZ).unwrap();
+    assert_eq!(file.used_blocks(), 10);
+    file.preallocate(BLOCK_SZ).unwrap();
+    assert_eq!(file.used_blocks(), 20);
+    assert_eq!(file.read_all(), expected);
+    //a request that cannot fit changes nothing
+    let free_data_blocks = tfs.lock().free_counts().1;
+    assert_eq!(
+        file.preallocate((free_data_blocks as usize + 20) * BLOCK_SZ),
+        Err(Error::NoSpace)
+    );
+    assert_eq!(tfs.lock().free_counts().1, free_data_blocks);
+    assert_eq!(file.read_all(), expected);
+    assert!(tfs.lock().verify_free_counts());
+    assert_eq!(root_inode.preallocate(BLOCK_SZ), Err(Error::NotAFile));
+}
+
+#[test]
+fn write_dirent_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("write_dirent.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let dir = root_inode.create_dir("dir").unwrap();
+    let file = dir.create_file("file", b"data").unwrap();
+    //after "." and ".."
+    let dir_entry = dir.read_dirent_raw(2).unwrap();
+    assert_eq!(dir_entry.name(), "file");
+    assert!(dir.read_dirent_raw(3).is_none());
+    //writing past the end grows the directory with empty entries
+    let dir_entry_size = tfs.lock().dir_entry_size();
+    let alias = DirEntry::new("alias", dir_entry.inode_number(), dir_entry_size);
+    dir.write_dirent(5, &alias).unwrap();
+    let read_back = dir.read_dirent_raw(5).unwrap();
+    assert_eq!(read_back.as_bytes(), alias.as_bytes());
+    assert_eq!(dir.read_dirent_raw(4).unwrap().name(), "");
+    assert!(dir.read_dirent_raw(6).is_none());
+    assert!(dir.find("alias").unwrap().same_file(&file));
+    //overwriting keeps the hash index in step
+    dir.enable_hash_index().unwrap();
+    dir.write_dirent(2, &DirEntry::new("renamed", dir_entry.inode_number(), dir_entry_size))
+        .unwrap();
+    assert!(dir.find("file").is_none());
+    assert!(dir.find("renamed").unwrap().same_file(&file));
+    dir.write_dirent(5, &DirEntry::empty(dir_entry_size)).unwrap();
+    assert!(dir.find("alias").is_none());
+    assert!(dir.find("renamed").is_some());
+}
+
+#[test]
+fn file_too_large_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("file_too_large.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", b"data").unwrap();
+    let free_counts = tfs.lock().free_counts();
+    //offset + len wraps a u32, and for the last one a usize as well
+    let offset = u32::MAX as usize - 10;
+    assert_eq!(file.write_at(offset, &[1u8; 100]), Err(Error::FileTooLarge));
+    assert_eq!(file.write_at(usize::MAX - 10, &[1u8; 100]), Err(Error::FileTooLarge));
+    assert_eq!(file.fill(offset, 100, 1), Err(Error::FileTooLarge));
+    assert_eq!(file.truncate(usize::MAX), Err(Error::FileTooLarge));
+    assert_eq!(file.preallocate(offset), Err(Error::FileTooLarge));
+    assert_eq!(file.read_at(usize::MAX - 10, &mut [0u8; 100]), 0);
+    assert_eq!(file.read_all(), b"data");
+    assert_eq!(tfs.lock().free_counts(), free_counts);
+}
+
+#[test]
+fn inode_of_path_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("inode_of_path.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create_file("file_a", b"aaa").unwrap();
+    root_inode.create_dir("dir").unwrap().create_file("file_b", b"bbb").unwrap();
+    drop(root_inode);
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("inode_of_path.img")).unwrap();
+    let file_a = TinyFileSystem::inode_of_path(&tfs, "/file_a").unwrap();
+    assert_eq!(file_a.read_all(), b"aaa");
+    let file_b = TinyFileSystem::inode_of_path(&tfs, "/dir//file_b").unwrap();
+    assert_eq!(file_b.read_all(), b"bbb");
+    let root_inode = TinyFileSystem::inode_of_path(&tfs, "/").unwrap();
+    assert!(root_inode.same_file(&TinyFileSystem::root_inode(&tfs)));
+    assert!(TinyFileSystem::inode_of_path(&tfs, "/missing").is_none());
+    assert!(TinyFileSystem::inode_of_path(&tfs, "/file_a/x").is_none());
+    //no current directory to resolve against
+    assert!(TinyFileSystem::inode_of_path(&tfs, "file_a").is_none());
+}
+
+#[test]
+fn physical_size_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("physical_size.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //a dense file within the direct blocks has no index block
+    let dense = root_inode.create_file("dense", &[1u8; 10 * BLOCK_SZ]).unwrap();
+    assert_eq!(dense.apparent_size(), 10 * BLOCK_SZ as u64);
+    assert_eq!(dense.physical_size(), dense.apparent_size());
+    //the last partial block and the index block count in full
+    let data = vec![2u8; 50 * BLOCK_SZ + 1];
+    let sparse = root_inode.create_file("sparse", &data).unwrap();
+    assert_eq!(sparse.physical_size(), 52 * BLOCK_SZ as u64);
+    sparse.zero_range(10 * BLOCK_SZ, 20 * BLOCK_SZ).unwrap();
+    assert_eq!(sparse.apparent_size(), data.len() as u64);
+    assert_eq!(sparse.physical_size(), 32 * BLOCK_SZ as u64);
+    //the entries of a small directory are inline and take no blocks
+    let dir = root_inode.create_dir("dir").unwrap();
+    assert!(dir.apparent_size() > 0);
+    assert_eq!(dir.physical_size(), 0);
+}
+
+#[test]
+fn background_flush_test() {
+    let _guard = test_guard();
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(CountingBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", &[1u8; 4 * BLOCK_SZ]).unwrap();
+    block_device.take_write_calls();
+    set_background_flush(Some(3));
+    //in place, then growing the file
+    file.write_at(0, &[2u8; 2 * BLOCK_SZ]).unwrap();
+    assert_eq!(block_device.take_write_calls(), (0, 0));
+    assert!(!needs_flush());
+    file.write_at(4 * BLOCK_SZ, &[3u8; 2 * BLOCK_SZ]).unwrap();
+    assert_eq!(block_device.take_write_calls(), (0, 0));
+    assert!(needs_flush());
+    //the worker writes back a bounded batch per call
+    assert_eq!(flush_dirty_blocks(2), 2);
+    assert_eq!(block_device.take_write_calls(), (2, 0));
+    file.write_at(BLOCK_SZ, &[4u8; 10]).unwrap();
+    tfs.lock().sync();
+    assert_ne!(block_device.take_write_calls(), (0, 0));
+    assert!(!needs_flush());
+    assert_eq!(flush_dirty_blocks(usize::MAX), 0);
+    set_background_flush(None);
+    file.write_at(0, &[5u8; 10]).unwrap();
+    assert_eq!(block_device.take_write_calls(), (1, 0));
+    let mut expected = vec![1u8; 6 * BLOCK_SZ];
+    expected[..2 * BLOCK_SZ].fill(2);
+    expected[4 * BLOCK_SZ..].fill(3);
+    expected[BLOCK_SZ..BLOCK_SZ + 10].fill(4);
+    expected[..10].fill(5);
+    clear_block_cache();
+    assert_eq!(file.read_all(), expected);
+}
+
+#[test]
+fn rename_or_replace_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("rename_or_replace.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data_a = vec![1u8; 3 * BLOCK_SZ];
+    let file_a = root_inode.create_file("file_a", &data_a).unwrap();
+    root_inode.create_file("file_b", &[2u8; 5 * BLOCK_SZ]).unwrap();
+    root_inode.create("file_c").unwrap();
+    let (free_inodes, free_data_blocks) = tfs.lock().free_counts();
+    root_inode.rename_or_replace("file_a", "file_b").unwrap();
+    //the old file_b is gone with its blocks
+    assert_eq!(tfs.lock().free_counts(), (free_inodes + 1, free_data_blocks + 5));
+    assert!(tfs.lock().verify_free_counts());
+    assert!(root_inode.find("file_b").unwrap().same_file(&file_a));
+    assert_eq!(root_inode.find("file_b").unwrap().read_all(), data_a);
+    assert!(root_inode.find("file_a").is_none());
+    assert_eq!(root_inode.ls_sorted(), ["file_b", "file_c"]);
+    //a free name is a plain rename, in a hash indexed directory as well
+    root_inode.enable_hash_index().unwrap();
+    root_inode.rename_or_replace("file_c", "file_d").unwrap();
+    assert_eq!(root_inode.ls_sorted(), ["file_b", "file_d"]);
+    assert!(root_inode.find("file_c").is_none());
+    root_inode.rename_or_replace("file_d", "file_b").unwrap();
+    assert_eq!(root_inode.ls(), ["file_b"]);
+    assert_eq!(root_inode.find("file_b").unwrap().read_all(), b"");
+    assert_eq!(root_inode.rename_or_replace("file_a", "x"), Err(Error::NotFound));
+    root_inode.create_dir("dir").unwrap();
+    assert_eq!(root_inode.rename_or_replace("file_b", "dir"), Err(Error::NotAFile));
+    assert_eq!(root_inode.ls_sorted(), ["dir", "file_b"]);
+    //the other entries stay reachable through the index
+    root_inode.create("file_c").unwrap();
+    let file_d = root_inode.create("file_d").unwrap();
+    root_inode.rename_or_replace("file_b", "file_c").unwrap();
+    assert_eq!(root_inode.ls_sorted(), ["dir", "file_c", "file_d"]);
+    assert!(root_inode.find("file_d").unwrap().same_file(&file_d));
+    assert!(root_inode.find("file_b").is_none());
+    assert!(tfs.lock().verify_free_counts());
+}
+
+#[test]
+fn inode_crc_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("inode_crc.img", 4096);
+    let tfs = TinyFileSystem::create_checked(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    for i in 0..8 {
+        root_inode.create_file(&format!("file{}", i), &[i as u8; 100]).unwrap();
+    }
+    //file6 is inode 7, which shares no block with the root inode
+    let (block_id, offset) = tfs.lock().get_disk_inode_pos(7);
+    assert_ne!(block_id, tfs.lock().get_disk_inode_pos(0).0);
+    drop(root_inode);
+    TinyFileSystem::close(tfs);
+    //tear the size of file6
+    let mut image = std::fs::read("target/inode_crc.img").unwrap();
+    image[block_id as usize * BLOCK_SZ + offset] ^= 0xff;
+    std::fs::write("target/inode_crc.img", &image).unwrap();
+    let tfs = TinyFileSystem::open(reopen_block_file("inode_crc.img")).unwrap();
+    assert!(tfs.lock().is_checked());
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.find("file0").unwrap().read_all(), [0u8; 100]);
+    let file6 = root_inode.find("file6").unwrap();
+    assert_eq!(file6.write_at(0, b"data"), Err(Error::Corrupt));
+    assert_eq!(file6.read_exact_at(0, &mut [0u8; 4]), Err(Error::Corrupt));
+    assert_eq!(file6.read_at(0, &mut [0u8; 4]), 0);
+    //changes keep the crcs of the other inodes current
+    let file0 = root_inode.find("file0").unwrap();
+    file0.write_at(100, &[1u8; 1000]).unwrap();
+    drop((root_inode, file0, file6));
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("inode_crc.img")).unwrap();
+    let file0 = TinyFileSystem::inode_of_path(&tfs, "/file0").unwrap();
+    assert_eq!(file0.read_exact_at(1000, &mut [0u8; 100]), Ok(()));
+}
+
+#[test]
+fn content_digest_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("content_digest.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data: Vec<u8> = (0..5 * BLOCK_SZ + 77).map(|i| (i % 251) as u8).collect();
+    let file_a = root_inode.create_file("file_a", &data).unwrap();
+    let file_b = root_inode.create_file("file_b", &data).unwrap();
+    assert_eq!(file_a.content_digest(), file_b.content_digest());
+    file_b.write_at(3 * BLOCK_SZ + 5, &[data[3 * BLOCK_SZ + 5] ^ 1]).unwrap();
+    assert_ne!(file_a.content_digest(), file_b.content_digest());
+    //what the file holds counts, not where it starts on disk
+    file_a.discard_front(10).unwrap();
+    let file_c = root_inode.create_file("file_c", &data[10..]).unwrap();
+    assert_eq!(file_a.content_digest(), file_c.content_digest());
+    assert_ne!(file_a.content_digest(), root_inode.create("empty").unwrap().content_digest());
+}
+
+#[test]
+fn repair_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("repair.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //indirect2 blocks, a subdirectory and a hash index are all in use
+    let data: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let file = root_inode.create_file("file", &data).unwrap();
+    let dir = root_inode.create_dir("dir").unwrap();
+    dir.create_file("small", b"small").unwrap();
+    root_inode.enable_hash_index().unwrap();
+    assert_eq!(tfs.lock().repair(), RepairReport::default());
+    let free_counts = tfs.lock().free_counts();
+    //leak a data block and an inode
+    let leaked_block = tfs.lock().alloc_data().unwrap();
+    let leaked_inode = tfs.lock().alloc_inode().unwrap();
+    let report = tfs.lock().repair();
+    assert_eq!((report.freed_inodes, report.freed_data_blocks), (1, 1));
+    assert!(report.unallocated_blocks.is_empty() && report.unallocated_inodes.is_empty());
+    assert_eq!(tfs.lock().free_counts(), free_counts);
+    assert_eq!(tfs.lock().alloc_data(), Ok(leaked_block));
+    assert_eq!(tfs.lock().alloc_inode(), Ok(leaked_inode));
+    tfs.lock().dealloc_data(leaked_block);
+    tfs.lock().dealloc_inode(leaked_inode);
+    //a block in use but free in the bitmap is only reported
+    let used_block = file.extents()[0].0;
+    let mut fs = tfs.lock();
+    let data_bit = (used_block - fs.get_data_block_id(0)) as usize;
+    fs.data_bitmap.dealloc(&fs.block_device, data_bit).unwrap();
+    let report = fs.repair();
+    drop(fs);
+    assert_eq!(report.unallocated_blocks, [used_block]);
+    assert_eq!((report.freed_inodes, report.freed_data_blocks), (0, 0));
+    assert_eq!(file.read_all(), data);
+    assert_eq!(TinyFileSystem::inode_of_path(&tfs, "/dir/small").unwrap().read_all(), b"small");
+}
+
+#[test]
+fn sub_block_device_test() {
+    let _guard = test_guard();
+    let sectors = 4096 * BLOCK_SZ / SECTOR_SZ;
+    let block_device = Arc::new(SubBlockDevice::new(
+        MemBlockDevice(Mutex::new(vec![0u8; sectors * SECTOR_SZ])),
+        SECTOR_SZ,
+    ));
+    //neighbours in a sector survive the read-modify-write of a block
+    block_device.write_block(9, &[9u8; BLOCK_SZ]);
+    block_device.write_block(10, &[10u8; BLOCK_SZ]);
+    let mut buf = [0u8; BLOCK_SZ];
+    block_device.read_block(9, &mut buf);
+    assert_eq!(buf, [9u8; BLOCK_SZ]);
+    assert_eq!(&block_device.inner().0.lock().unwrap()[10 * BLOCK_SZ..11 * BLOCK_SZ], [10u8; BLOCK_SZ]);
+    let tfs = TinyFileSystem::create(block_device.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data: Vec<u8> = (0..100 * BLOCK_SZ + 3).map(|i| (i % 251) as u8).collect();
+    root_inode.create_dir("dir").unwrap().create_file("file", &data).unwrap();
+    drop(root_inode);
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(block_device).unwrap();
+    assert_eq!(TinyFileSystem::inode_of_path(&tfs, "/dir/file").unwrap().read_all(), data);
+}
+
+#[test]
+fn reserve_dir_capacity_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("reserve_dir_capacity.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create("first").unwrap();
+    root_inode.reserve_dir_capacity(1000).unwrap();
+    assert_eq!(root_inode.ls(), ["first"]);
+    assert!(root_inode.read_dirent_raw(1).is_none());
+    let used_data_blocks = tfs.lock().used_data_blocks();
+    let size = root_inode.apparent_size();
+    for i in 1..1000 {
+        root_inode.create(&format!("file{}", i)).unwrap();
+    }
+    assert_eq!(tfs.lock().used_data_blocks(), used_data_blocks);
+    assert_eq!(root_inode.apparent_size(), size);
+    assert_eq!(root_inode.ls().len(), 1000);
+    assert!(root_inode.find("file999").is_some());
+    //a rename frees an entry for the next create without shrinking
+    root_inode.rename_or_replace("file1", "file2").unwrap();
+    assert_eq!(root_inode.ls().len(), 999);
+    root_inode.create("last").unwrap();
+    assert_eq!(root_inode.apparent_size(), size);
+    //past the reservation the directory grows again
+    root_inode.create("extra").unwrap();
+    assert!(root_inode.apparent_size() > size);
+    assert_eq!(root_inode.ls().len(), 1001);
+    let file = root_inode.find("first").unwrap();
+    assert_eq!(file.reserve_dir_capacity(10), Err(Error::NotADirectory));
+    assert!(tfs.lock().verify_free_counts());
+}
+
+#[test]
+fn inode_flush_test() {
+    let _guard = test_guard();
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(CountingBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file_a = root_inode.create_file("file_a", &[1u8; 2 * BLOCK_SZ]).unwrap();
+    let file_b = root_inode.create_file("file_b", &[1u8; 3 * BLOCK_SZ]).unwrap();
+    set_background_flush(Some(usize::MAX));
+    file_a.write_at(0, &[2u8; 2 * BLOCK_SZ]).unwrap();
+    file_b.write_at(0, &[3u8; 3 * BLOCK_SZ]).unwrap();
+    block_device.take_write_calls();
+    //only the two blocks of file_a are written back
+    file_a.flush();
+    assert_eq!(block_device.take_write_calls(), (2, 0));
+    file_a.flush();
+    assert_eq!(block_device.take_write_calls(), (0, 0));
+    file_b.flush();
+    assert_eq!(block_device.take_write_calls(), (3, 0));
+    set_background_flush(None);
+    assert_eq!(flush_dirty_blocks(usize::MAX), 0);
+    clear_block_cache();
+    assert_eq!(file_a.read_all(), [2u8; 2 * BLOCK_SZ]);
+    assert_eq!(file_b.read_all(), [3u8; 3 * BLOCK_SZ]);
+}
+
+#[test]
+fn nth_block_id_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("nth_block_id.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    //reach into the indirect1 blocks
+    let offset = 30 * BLOCK_SZ + 100;
+    file.write_at(offset, b"marker").unwrap();
+    assert_eq!(file.nth_block_id(31), None);
+    let block_id = file.nth_block_id(30).unwrap();
+    let mut block = [0u8; BLOCK_SZ];
+    file.block_device().read_block(block_id as usize, &mut block);
+    assert_eq!(&block[100..106], b"marker");
+    assert_eq!(root_inode.nth_block_id(0), None);
+}
+
+#[test]
+fn alloc_policy_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("alloc_policy.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //free every other block of a run so the front of the data area is fragmented
+    let block_ids: Vec<u32> = {
+        let mut fs = tfs.lock();
+        let block_ids = fs.alloc_n(16).unwrap();
+        for &block_id in block_ids.iter().step_by(2) {
+            fs.dealloc_data(block_id);
+        }
+        block_ids
+    };
+    let scattered = root_inode.create("scattered").unwrap();
+    scattered.write_at(0, &[1u8; 4 * BLOCK_SZ]).unwrap();
+    let expected: Vec<(u32, u32)> = block_ids.iter().step_by(2).take(4).map(|&id| (id, 1)).collect();
+    assert_eq!(scattered.extents(), expected);
+    tfs.lock().set_alloc_policy(Box::new(Contiguous));
+    let sequential = root_inode.create("sequential").unwrap();
+    sequential.write_at(0, &[2u8; 4 * BLOCK_SZ]).unwrap();
+    let extents = sequential.extents();
+    assert_eq!(extents.len(), 1);
+    assert_eq!(extents[0].1, 4);
+    assert!(extents[0].0 > block_ids[15]);
+    tfs.lock().set_alloc_policy(Box::new(FirstFit));
+    assert!(tfs.lock().verify_free_counts());
+    assert_eq!(sequential.read_all(), [2u8; 4 * BLOCK_SZ]);
+}
+
+#[test]
+fn read_to_string_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("read_to_string.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let text = "tiny-fs: 小文件系统 ✓\n".repeat(100);
+    let file = root_inode.create_file("text", text.as_bytes()).unwrap();
+    assert_eq!(file.read_to_string().unwrap(), text);
+    let file = root_inode.create_file("binary", &[b'a', 0xff, 0xfe, b'b']).unwrap();
+    let err = file.read_to_string().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn total_blocks_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("total_blocks.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 3000, 1).unwrap();
+    assert_eq!(tfs.lock().total_blocks(), 3000);
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("total_blocks.img")).unwrap();
+    assert_eq!(tfs.lock().total_blocks(), 3000);
+}
+
+#[test]
+fn remove_tombstone_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("remove_tombstone.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    for name in ["a", "b", "c", "d"] {
+        root_inode.create_file(name, &[1u8; 2 * BLOCK_SZ]).unwrap();
+    }
+    let (free_inodes, free_data_blocks) = tfs.lock().free_counts();
+    let size = root_inode.apparent_size();
+    root_inode.remove("b").unwrap();
+    assert_eq!(tfs.lock().free_counts(), (free_inodes + 1, free_data_blocks + 2));
+    //the other entries keep their offsets
+    assert_eq!(root_inode.read_dirent_raw(0).unwrap().name(), "a");
+    assert!(root_inode.read_dirent_raw(1).unwrap().is_empty());
+    assert_eq!(root_inode.read_dirent_raw(2).unwrap().name(), "c");
+    assert_eq!(root_inode.read_dirent_raw(3).unwrap().name(), "d");
+    assert_eq!(root_inode.ls(), ["a", "c", "d"]);
+    assert!(root_inode.find("b").is_none());
+    assert_eq!(root_inode.remove("b"), Err(Error::NotFound));
+    //the next create takes the slot without growing the directory
+    root_inode.create("e").unwrap();
+    assert_eq!(root_inode.read_dirent_raw(1).unwrap().name(), "e");
+    assert_eq!(root_inode.apparent_size(), size);
+    root_inode.create("f").unwrap();
+    assert_eq!(root_inode.ls(), ["a", "e", "c", "d", "f"]);
+    //the same through the hash index
+    root_inode.enable_hash_index().unwrap();
+    root_inode.remove("c").unwrap();
+    assert!(root_inode.find("c").is_none());
+    root_inode.create("g").unwrap();
+    assert!(root_inode.find("g").is_some());
+    assert_eq!(root_inode.read_dirent_raw(2).unwrap().name(), "g");
+    root_inode.create_dir("dir").unwrap();
+    assert_eq!(root_inode.remove("dir"), Err(Error::NotAFile));
+    assert!(tfs.lock().verify_free_counts());
+}
+
+#[test]
+fn hash_index_churn_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("hash_index_churn.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.enable_hash_index().unwrap();
+    root_inode.create_file("keep", b"kept").unwrap();
+    //far more cycles than the smallest index has slots
+    for i in 0..1000 {
+        let name = format!("t{}", i);
+        root_inode.create(&name).unwrap();
+        assert!(root_inode.find(&name).is_some());
+        root_inode.remove(&name).unwrap();
+        assert!(root_inode.find(&name).is_none());
+    }
+    assert_eq!(root_inode.ls(), ["keep"]);
+    assert_eq!(root_inode.find("keep").unwrap().read_all(), b"kept");
+    assert!(tfs.lock().verify_free_counts());
+}
+
+#[test]
+fn count_entries_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("count_entries.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.count_entries(), 0);
+    let dir = root_inode.create_dir("dir").unwrap();
+    //`.` and `..` are listed too
+    assert_eq!(dir.count_entries(), dir.ls().len());
+    for round in 0..3 {
+        for i in 0..20 {
+            dir.create(&format!("file{}_{}", round, i)).unwrap();
+        }
+        assert_eq!(dir.count_entries(), dir.ls().len());
+        for i in (0..20).step_by(3) {
+            dir.remove(&format!("file{}_{}", round, i)).unwrap();
+        }
+        assert_eq!(dir.count_entries(), dir.ls().len());
+    }
+    assert_eq!(dir.count_entries(), 2 + 3 * 13);
+    assert_eq!(root_inode.count_entries(), 1);
+}
+
+#[test]
+fn create_drop_open_test() {
+    let _guard = test_guard();
+    let sectors = 4096 * BLOCK_SZ / SECTOR_SZ;
+    let block_device = Arc::new(SubBlockDevice::new(
+        MemBlockDevice(Mutex::new(vec![0u8; sectors * SECTOR_SZ])),
+        SECTOR_SZ,
+    ));
+    let tfs = TinyFileSystem::create(block_device.clone(), 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create_file("file_a", b"hello").unwrap();
+    root_inode.create_dir("dir").unwrap();
+    root_inode.create("file_b").unwrap().write_at(0, &[7u8; 3 * BLOCK_SZ]).unwrap();
+    //dropped without close, the cache still holds the blocks
+    drop(root_inode);
+    drop(tfs);
+    let tfs = TinyFileSystem::open(block_device.clone()).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.ls(), ["file_a", "dir", "file_b"]);
+    assert_eq!(root_inode.find("file_a").unwrap().read_all(), b"hello");
+    drop(root_inode);
+    drop(tfs);
+    //a copy of the device has no cached blocks at all, so what the
+    //root directory holds must have been written back
+    let bytes = block_device.inner().0.lock().unwrap().clone();
+    let copy = Arc::new(SubBlockDevice::new(MemBlockDevice(Mutex::new(bytes)), SECTOR_SZ));
+    let tfs = TinyFileSystem::open(copy).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.ls(), ["file_a", "dir", "file_b"]);
+    assert_eq!(root_inode.find("file_a").unwrap().read_all(), b"hello");
+    assert_eq!(root_inode.find("file_b").unwrap().read_all(), [7u8; 3 * BLOCK_SZ]);
+    assert_eq!(root_inode.find("dir").unwrap().ls(), [".", ".."]);
+}
+
+#[test]
+fn entries_iter_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("entries_iter.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let dir = root_inode.create_dir("dir").unwrap();
+    for i in 0..40 {
+        dir.create(&format!("file{}", i)).unwrap();
+    }
+    dir.remove("file7").unwrap();
+    let names: Vec<String> = dir.entries_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, dir.ls());
+    let mut expected: Vec<(String, u32)> = Vec::new();
+    dir.read_dir_into(&mut expected);
+    assert!(dir.entries_iter().eq(expected));
+    //no lock is held between steps
+    let mut iter = dir.entries_iter();
+    assert_eq!(iter.next().unwrap().0, ".");
+    dir.remove("file0").unwrap();
+    assert_eq!(iter.next().unwrap().0, "..");
+    assert_eq!(iter.next().unwrap().0, "file1");
+    assert_eq!(root_inode.entries_iter().count(), 1);
+}
+
+#[test]
+fn format_keeps_data_area_test() {
+    let _guard = test_guard();
+    //a device full of leftovers, only the metadata is cleared by create
+    let buf: &'static mut [u8] = Box::leak(vec![0xabu8; 4096 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(CountingBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 4096, 1).unwrap();
+    let (block_writes, _) = block_device.take_write_calls();
+    assert!(block_writes < 4096 / 2);
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.ls(), Vec::<String>::new());
+    //unwritten bytes of files read as zeros however the file grew
+    let sparse = root_inode.create("sparse").unwrap();
+    sparse.write_at(3 * BLOCK_SZ + 5, b"end").unwrap();
+    let mut expected = vec![0u8; 3 * BLOCK_SZ + 8];
+    expected[3 * BLOCK_SZ + 5..].copy_from_slice(b"end");
+    assert_eq!(sparse.read_all(), expected);
+    let grown = root_inode.create_file("grown", b"head").unwrap();
+    grown.truncate(2 * BLOCK_SZ).unwrap();
+    let mut expected = vec![0u8; 2 * BLOCK_SZ];
+    expected[..4].copy_from_slice(b"head");
+    assert_eq!(grown.read_all(), expected);
+    let preallocated = root_inode.create("preallocated").unwrap();
+    preallocated.preallocate(5 * BLOCK_SZ).unwrap();
+    assert_eq!(preallocated.read_all(), vec![0u8; 5 * BLOCK_SZ]);
+    let dir = root_inode.create_dir("dir").unwrap();
+    dir.reserve_dir_capacity(100).unwrap();
+    assert_eq!(dir.ls(), [".", ".."]);
+    for i in 0..100 {
+        dir.create(&format!("file{}", i)).unwrap();
+    }
+    assert_eq!(dir.count_entries(), 102);
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(block_device).unwrap();
+    assert!(tfs.lock().verify_free_counts());
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.find("sparse").unwrap().read_all()[..3 * BLOCK_SZ], [0u8; 3 * BLOCK_SZ]);
+}
+
+#[test]
+fn inode_type_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("inode_type.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    let dir = root_inode.create_dir("dir").unwrap();
+    let symlink = root_inode.create_symlink("link", "/file").unwrap();
+    assert!(file.is_file() && !file.is_dir() && !file.is_symlink());
+    assert!(!dir.is_file() && dir.is_dir() && !dir.is_symlink());
+    assert!(!symlink.is_file() && !symlink.is_dir() && symlink.is_symlink());
+    assert!(root_inode.is_dir());
+    assert!(root_inode.find("link").unwrap().is_symlink());
+}
+
+#[test]
+fn writeback_batch_test() {
+    let _guard = test_guard();
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(CountingBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let data: Vec<u8> = (0..320 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    //far more blocks than the cache holds, so dirty ones get evicted
+    block_device.take_write_calls();
+    root_inode.create_file("unbatched", &data).unwrap();
+    let (unbatched_block, unbatched_blocks) = block_device.take_write_calls();
+    set_writeback_batch(8);
+    root_inode.create_file("batched", &data).unwrap();
+    let (batched_block, batched_blocks) = block_device.take_write_calls();
+    assert!(batched_block + batched_blocks < unbatched_block + unbatched_blocks);
+    assert!(batched_blocks > unbatched_blocks);
+    //with nothing synced, evicted blocks wait on the list and are read back from it
+    set_writeback_batch(usize::MAX);
+    set_background_flush(Some(usize::MAX));
+    let file = root_inode.find("batched").unwrap();
+    file.write_at(0, &[7u8; 300 * BLOCK_SZ]).unwrap();
+    assert_eq!(block_device.take_write_calls(), (0, 0));
+    assert_eq!(file.read_all()[..300 * BLOCK_SZ], [7u8; 300 * BLOCK_SZ]);
+    set_background_flush(None);
+    set_writeback_batch(0);
+    clear_block_cache();
+    assert_eq!(root_inode.find("unbatched").unwrap().read_all(), data);
+    let mut expected = data.clone();
+    expected[..300 * BLOCK_SZ].fill(7);
+    assert_eq!(file.read_all(), expected);
+}
+
+#[test]
+fn case_insensitive_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("case_sensitive.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create("file").unwrap();
+    root_inode.create("File").unwrap();
+    assert!(root_inode.find("FILE").is_none());
+    assert!(!tfs.lock().is_case_insensitive());
+    let block_file = test_block_file("case_insensitive.img", 4096);
+    let tfs = TinyFileSystem::create_case_insensitive(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    assert_eq!(root_inode.create("File").err(), Some(Error::AlreadyExists));
+    assert!(root_inode.find("FILE").unwrap().same_file(&file));
+    //a case-only rename keeps the entry and takes the new case
+    root_inode.create("other").unwrap();
+    root_inode.rename_or_replace("FILE", "File").unwrap();
+    assert_eq!(root_inode.ls(), ["File", "other"]);
+    root_inode.rename_or_replace("OTHER", "file").unwrap();
+    assert_eq!(root_inode.ls(), ["file"]);
+    assert!(!root_inode.find("file").unwrap().same_file(&file));
+    //the hash index folds case as well
+    root_inode.enable_hash_index().unwrap();
+    root_inode.create("MixedCase").unwrap();
+    assert!(root_inode.find("mixedcase").is_some());
+    assert_eq!(root_inode.create("MIXEDCASE").err(), Some(Error::AlreadyExists));
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("case_insensitive.img")).unwrap();
+    assert!(tfs.lock().is_case_insensitive());
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.create("FILE").err(), Some(Error::AlreadyExists));
+}
+
+#[test]
+fn superblock_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("superblock.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 3000, 1).unwrap();
+    assert_eq!(tfs.lock().superblock(|super_block| super_block.total_blocks), 3000);
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create_file("file", &[1u8; 3 * BLOCK_SZ]).unwrap();
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("superblock.img")).unwrap();
+    let fs = tfs.lock();
+    let (total_blocks, free_data_blocks, valid) = fs.superblock(|super_block| {
+        (super_block.total_blocks, super_block.free_data_blocks, super_block.is_valid())
+    });
+    assert_eq!(total_blocks, 3000);
+    assert_eq!(free_data_blocks, fs.free_counts().1);
+    assert!(valid);
+}
+
+#[test]
+fn aligned_write_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("aligned_write.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", &[1u8; 8 * BLOCK_SZ]).unwrap();
+    let data_blocks: Vec<usize> = (0..8).map(|n| file.nth_block_id(n).unwrap() as usize).collect();
+    clear_block_cache();
+    IO_EVENTS.lock().unwrap().clear();
+    set_io_logger(|event| IO_EVENTS.lock().unwrap().push(event));
+    //in place, then growing the file by whole blocks
+    file.write_at(2 * BLOCK_SZ, &[2u8; 4 * BLOCK_SZ]).unwrap();
+    file.write_at(8 * BLOCK_SZ, &[3u8; 2 * BLOCK_SZ]).unwrap();
+    //a partial block is still read first
+    file.write_at(BLOCK_SZ + 10, &[4u8; 10]).unwrap();
+    clear_io_logger();
+    let reads: Vec<usize> = IO_EVENTS
+        .lock()
+        .unwrap()
+        .drain(..)
+        .filter_map(|event| match event {
+            IoEvent::Read(block_id) => Some(block_id),
+            _ => None,
+        })
+        .collect();
+    for block_id in &data_blocks[2..6] {
+        assert!(!reads.contains(block_id));
+    }
+    for n in 8..10 {
+        assert!(!reads.contains(&(file.nth_block_id(n).unwrap() as usize)));
+    }
+    assert!(reads.contains(&data_blocks[1]));
+    let mut expected = vec![1u8; 8 * BLOCK_SZ];
+    expected[2 * BLOCK_SZ..6 * BLOCK_SZ].fill(2);
+    expected.extend_from_slice(&[3u8; 2 * BLOCK_SZ]);
+    expected[BLOCK_SZ + 10..BLOCK_SZ + 20].fill(4);
+    clear_block_cache();
+    assert_eq!(file.read_all(), expected);
+}
+
+#[test]
+fn export_to_test() {
+    let _guard = test_guard();
+    let new_fs = || {
+        let sectors = 4096 * BLOCK_SZ / SECTOR_SZ;
+        let block_device = Arc::new(SubBlockDevice::new(
+            MemBlockDevice(Mutex::new(vec![0u8; sectors * SECTOR_SZ])),
+            SECTOR_SZ,
+        ));
+        TinyFileSystem::create(block_device, 4096, 1).unwrap()
+    };
+    let (src_fs, dest_fs) = (new_fs(), new_fs());
+    let src_root = TinyFileSystem::root_inode(&src_fs);
+    let dest_root = TinyFileSystem::root_inode(&dest_fs);
+    let contents: Vec<u8> = (0..5 * BLOCK_SZ + 100).map(|i| (i % 251) as u8).collect();
+    let file = src_root.create_file("file", &contents).unwrap();
+    //the destination already uses the same block ids for something else
+    dest_root.create_file("other", &[9u8; 5 * BLOCK_SZ]).unwrap();
+    let dest_dir = dest_root.create_dir("dir").unwrap();
+    let copy = file.export_to(&dest_dir, "copy").unwrap();
+    assert_eq!(copy.read_all(), contents);
+    assert_eq!(dest_root.find("other").unwrap().read_all(), [9u8; 5 * BLOCK_SZ]);
+    assert_eq!(file.read_all(), contents);
+    assert_eq!(src_root.ls(), ["file"]);
+    clear_block_cache();
+    assert_eq!(dest_dir.find("copy").unwrap().read_all(), contents);
+    assert!(matches!(file.export_to(&dest_dir, "copy"), Err(Error::AlreadyExists)));
+    assert!(matches!(dest_root.export_to(&src_root, "dir"), Err(Error::NotAFile)));
+}
+
+#[test]
+fn get_or_create_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("get_or_create.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let created = root_inode.get_or_create("file").unwrap();
+    created.write_at(0, b"kept").unwrap();
+    let opened = root_inode.get_or_create("file").unwrap();
+    assert!(opened.same_file(&created));
+    assert_eq!(opened.read_all(), b"kept");
+    assert_eq!(root_inode.ls(), ["file"]);
+    root_inode.create_dir("dir").unwrap();
+    assert!(matches!(root_inode.get_or_create("dir"), Err(Error::IsADirectory)));
+    root_inode.create_symlink("link", "file").unwrap();
+    assert!(matches!(root_inode.get_or_create("link"), Err(Error::NotAFile)));
+    assert_eq!(format!("{}", Error::IsADirectory), "is a directory");
+    assert!(matches!(created.get_or_create("x"), Err(Error::NotADirectory)));
+}
+
+#[test]
+fn quick_format_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("quick_format.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let free_counts = tfs.lock().free_counts();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    root_inode.create_file("file", &[3u8; 10 * BLOCK_SZ]).unwrap();
+    root_inode.create_dir("dir").unwrap().create("inner").unwrap();
+    drop(root_inode);
+    tfs.lock().quick_format();
+    assert_eq!(tfs.lock().free_counts(), free_counts);
+    assert!(tfs.lock().verify_free_counts());
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert!(root_inode.ls().is_empty());
+    root_inode.create_file("new", b"fresh").unwrap();
+    drop(root_inode);
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("quick_format.img")).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.ls(), ["new"]);
+    assert_eq!(root_inode.find("new").unwrap().read_all(), b"fresh");
+}
+
+#[test]
+fn metadata_cache_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("metadata_cache.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", b"hello").unwrap();
+    file.cache_metadata(true);
+    assert_eq!(file.apparent_size(), 5);
+    assert!(file.is_file());
+    //a cached read does not go to the block cache
+    IO_EVENTS.lock().unwrap().clear();
+    clear_block_cache();
+    set_io_logger(|event| IO_EVENTS.lock().unwrap().push(event));
+    assert_eq!(file.apparent_size(), 5);
+    assert!(file.is_file() && !file.is_dir() && !file.is_symlink());
+    clear_io_logger();
+    assert!(IO_EVENTS.lock().unwrap().is_empty());
+    //writes through the same handle are seen
+    file.write_at(5, &[1u8; BLOCK_SZ]).unwrap();
+    assert_eq!(file.apparent_size(), 5 + BLOCK_SZ as u64);
+    file.truncate(2).unwrap();
+    assert_eq!(file.apparent_size(), 2);
+    file.clear();
+    assert_eq!(file.apparent_size(), 0);
+    //another handle's write is not, until the cache is reset
+    root_inode.find("file").unwrap().write_at(0, b"abc").unwrap();
+    assert_eq!(file.apparent_size(), 0);
+    file.cache_metadata(false);
+    assert_eq!(file.apparent_size(), 3);
+}
+
+#[test]
+fn inodes_last_layout_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("inodes_last.img", 4096);
+    let config = CreateConfig {
+        checked: true,
+        dedup: true,
+        layout: Layout::InodesLast,
+        ..CreateConfig::default()
+    };
+    let tfs = TinyFileSystem::create_with_config(block_file, 4096, 1, config).unwrap();
+    let free_counts = tfs.lock().free_counts();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //the data bitmap, refcount and crc areas come right after the superblock
+    let data_area_start = tfs.lock().superblock(|super_block| {
+        1 + super_block.data_bitmap_blocks + super_block.refcount_blocks + super_block.inode_crc_blocks
+    });
+    let file = root_inode.create_file("file", &[5u8; 3 * BLOCK_SZ]).unwrap();
+    assert_eq!(file.nth_block_id(0), Some(data_area_start));
+    root_inode.create_dir("dir").unwrap().create_file("inner", b"inner").unwrap();
+    drop((root_inode, file));
+    TinyFileSystem::close(tfs);
+    let tfs = TinyFileSystem::open(reopen_block_file("inodes_last.img")).unwrap();
+    assert!(tfs.lock().superblock(|super_block| super_block.is_inodes_last()));
+    assert!(tfs.lock().verify_free_counts());
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    assert_eq!(root_inode.ls(), ["file", "dir"]);
+    assert_eq!(root_inode.find("file").unwrap().read_all(), [5u8; 3 * BLOCK_SZ]);
+    let inner = TinyFileSystem::inode_of_path(&tfs, "/dir/inner").unwrap();
+    assert_eq!(inner.read_all(), b"inner");
+    root_inode.find("file").unwrap().clear();
+    inner.clear();
+    drop(inner);
+    root_inode.remove("file").unwrap();
+    assert_eq!(tfs.lock().free_counts(), (free_counts.0 - 2, free_counts.1));
+}
+
+#[test]
+fn with_block_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("with_block.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let contents: Vec<u8> = (0..3 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    let file = root_inode.create_file("file", &contents).unwrap();
+    for n in 0..3 {
+        let mut copied = [0u8; BLOCK_SZ];
+        assert_eq!(file.read_at(n * BLOCK_SZ, &mut copied), BLOCK_SZ);
+        assert_eq!(file.with_block(n, |block| *block == copied), Some(true));
+    }
+    assert_eq!(file.with_block(3, |block| block[0]), None);
+    //a directory with few entries keeps them inline
+    assert_eq!(root_inode.with_block(0, |block| block[0]), None);
+}
+
+#[test]
+fn open_at_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("open_at.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let a = root_inode.create_dir("a").unwrap();
+    let b = a.create_dir("b").unwrap();
+    b.create_dir("c").unwrap().create_file("file", b"deep").unwrap();
+    root_inode.create_file("top", b"top").unwrap();
+    let file = a.open_at("b/c/file").unwrap();
+    assert_eq!(file.read_all(), b"deep");
+    assert!(file.same_file(&b.open_at("./c//file").unwrap()));
+    assert!(b.open_at("..").unwrap().same_file(&a));
+    assert_eq!(b.open_at("../../top").unwrap().read_all(), b"top");
+    //the root is its own parent, and an absolute path starts there
+    assert_eq!(a.open_at("../../../top").unwrap().read_all(), b"top");
+    assert_eq!(b.open_at("/a/b/c/file").unwrap().read_all(), b"deep");
+    assert!(b.open_at("").unwrap().same_file(&b));
+    assert!(a.open_at("b/missing").is_none());
+    assert!(a.open_at("b/c/file/x").is_none());
+    assert!(a.open_at("b/c/file/..").is_none());
+}
+
+#[test]
+fn mounted_root_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("mounted_root.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root = TinyFileSystem::mounted_root(&tfs);
+    let again = TinyFileSystem::mounted_root(&tfs);
+    assert!(Arc::ptr_eq(&root, &again));
+    assert!(root.same_file(&TinyFileSystem::root_inode(&tfs)));
+    root.create_file("file", b"hello").unwrap();
+    assert_eq!(again.find("file").unwrap().read_all(), b"hello");
+    //the fs does not keep the root alive, nor the root the fs once dropped
+    drop((root, again));
+    assert_eq!(Arc::strong_count(&tfs), 1);
+    let root = TinyFileSystem::mounted_root(&tfs);
+    assert_eq!(root.ls(), ["file"]);
+    drop(root);
+    let weak = Arc::downgrade(&tfs);
+    TinyFileSystem::close(tfs);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn invalid_name_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("invalid_name.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    for name in ["a/b", "/", "nul\0byte", ".", "..", ""] {
+        assert!(matches!(root_inode.create(name), Err(Error::InvalidName)), "{:?}", name);
+        assert!(matches!(root_inode.create_dir(name), Err(Error::InvalidName)));
+    }
+    root_inode.create_file("file", b"x").unwrap();
+    assert!(matches!(root_inode.rename_or_replace("file", "a/b"), Err(Error::InvalidName)));
+    assert!(matches!(root_inode.rename_or_replace("file", ".."), Err(Error::InvalidName)));
+    //names that merely contain dots are fine
+    root_inode.create("...").unwrap();
+    root_inode.rename_or_replace("file", ".hidden").unwrap();
+    assert_eq!(root_inode.ls(), [".hidden", "..."]);
+    assert!(tfs.lock().verify_free_counts());
+}
+
+#[test]
+fn index_block_count_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("index_block_count.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //26 direct blocks, then 128 through indirect1, then indirect2
+    let indirect1_bound = 26 + BLOCK_SZ / 4;
+    for (data_blocks, index_blocks) in [
+        (26, 0),
+        (27, 1),
+        (indirect1_bound, 1),
+        (indirect1_bound + 1, 3),
+        (indirect1_bound + BLOCK_SZ / 4 + 1, 4),
+    ] {
+        let file = root_inode.create(&format!("file{}", data_blocks)).unwrap();
+        file.write_at(0, &vec![1u8; data_blocks * BLOCK_SZ - 10]).unwrap();
+        assert_eq!(file.data_block_count(), data_blocks as u32);
+        assert_eq!(file.index_block_count(), index_blocks);
+        assert_eq!(file.physical_size(), ((data_blocks as u32 + index_blocks) * BLOCK_SZ as u32) as u64);
+    }
+    //a small directory keeps its entries inline
+    let dir = root_inode.create_dir("dir").unwrap();
+    assert_eq!((dir.data_block_count(), dir.index_block_count()), (0, 0));
+    assert_eq!((root_inode.data_block_count(), root_inode.index_block_count()), (1, 0));
+}
+
+#[test]
+fn validate_params_test() {
+    let _guard = test_guard();
+    let config = CreateConfig { checked: true, ..CreateConfig::default() };
+    let geometry = TinyFileSystem::validate_params(4096, 1, config).unwrap();
+    assert_eq!(geometry.inode_bitmap_blocks, 1);
+    assert_eq!(geometry.inode_crc_blocks, geometry.inode_area_blocks.div_ceil(BLOCK_SZ as u32 / 4));
+    assert_eq!(geometry.refcount_blocks, 0);
+    let metadata_blocks = 1
+        + geometry.inode_bitmap_blocks
+        + geometry.inode_area_blocks
+        + geometry.data_bitmap_blocks
+        + geometry.inode_crc_blocks;
+    assert_eq!(geometry.data_blocks, 4096 - metadata_blocks);
+    //the filesystem create lays out is the one validated
+    let block_file = test_block_file("validate_params.img", 4096);
+    let tfs = TinyFileSystem::create_with_config(block_file, 4096, 1, config).unwrap();
+    assert_eq!(tfs.lock().free_counts(), (geometry.inodes - 1, geometry.data_blocks));
+    //the smallest device leaves exactly one data block
+    assert_eq!(
+        TinyFileSystem::validate_params(metadata_blocks + 1, 1, config).map(|geometry| geometry.data_blocks),
+        Ok(1)
+    );
+    for (total_blocks, inode_bitmap_blocks, config) in [
+        (metadata_blocks, 1, config),
+        (10, 1, CreateConfig::default()),
+        (0, 1, CreateConfig::default()),
+        (4096, 0, CreateConfig::default()),
+        (u32::MAX, u32::MAX, CreateConfig::default()),
+        (4096, 1, CreateConfig { dir_entry_size: 48, ..CreateConfig::default() }),
+    ] {
+        assert_eq!(
+            TinyFileSystem::validate_params(total_blocks, inode_bitmap_blocks, config),
+            Err(Error::BadGeometry)
+        );
+    }
+}
+
+#[test]
+fn blocks_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("blocks.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", &[1u8; 40 * BLOCK_SZ]).unwrap();
+    let blocks = file.blocks();
+    assert_eq!(blocks.len(), 40);
+    assert!(blocks.iter().all(Option::is_some));
+    assert_eq!(blocks[30], file.nth_block_id(30));
+    //blocks 2, 3 and 30 go, 1 and 4 are only zeroed in part
+    file.zero_range(BLOCK_SZ + 10, 4 * BLOCK_SZ - 20).unwrap();
+    file.zero_range(30 * BLOCK_SZ, BLOCK_SZ).unwrap();
+    let punched = file.blocks();
+    for (index, block_id) in punched.iter().enumerate() {
+        if [2, 3, 30].contains(&index) {
+            assert_eq!(*block_id, None, "{}", index);
+        } else {
+            assert_eq!(*block_id, blocks[index], "{}", index);
+        }
+    }
+    assert!(root_inode.create_dir("dir").unwrap().blocks().is_empty());
+}
+
+#[test]
+fn cached_generation_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("cached_generation.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create_file("file", b"hello").unwrap();
+    let block_id = file.nth_block_id(0).unwrap() as usize;
+    let block_device = file.block_device();
+    let generation = cached_generation(block_id, &block_device).unwrap();
+    assert_eq!(file.read_all(), b"hello");
+    assert_eq!(cached_generation(block_id, &block_device), Some(generation));
+    clear_block_cache();
+    assert_eq!(cached_generation(block_id, &block_device), None);
+    assert_eq!(file.read_all(), b"hello");
+    assert!(cached_generation(block_id, &block_device).unwrap() > generation);
+}
+
+#[test]
+fn grow_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("grow.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //leave stale bytes in the blocks handed out next
+    let old = root_inode.create_file("old", &[0xabu8; 10 * BLOCK_SZ]).unwrap();
+    let old_blocks = old.extents();
+    old.clear_no_zero();
+    let file = root_inode.create_file("file", &[0xffu8; BLOCK_SZ]).unwrap();
+    file.truncate(3).unwrap();
+    file.grow(10 * BLOCK_SZ + 100).unwrap();
+    assert_eq!(file.extents()[0].0, old_blocks[0].0);
+    let mut expected = vec![0u8; 10 * BLOCK_SZ + 100];
+    expected[..3].fill(0xff);
+    assert_eq!(file.read_all(), expected);
+    assert_eq!(file.grow(5), Err(Error::OutOfBounds));
+    file.grow(10 * BLOCK_SZ + 100).unwrap();
+    assert_eq!(file.read_all(), expected);
+}
+
+#[test]
+fn create_dir_all_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("create_dir_all.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root = TinyFileSystem::mounted_root(&tfs);
+    let c = root.create_dir_all("a/b/c").unwrap();
+    assert!(c.is_dir());
+    assert_eq!(root.ls(), ["a"]);
+    let a = root.find("a").unwrap();
+    assert_eq!(a.ls(), [".", "..", "b"]);
+    let b = a.find("b").unwrap();
+    assert_eq!(b.ls(), [".", "..", "c"]);
+    assert!(b.find("c").unwrap().same_file(&c));
+    assert_eq!(c.ls(), [".", ".."]);
+    //existing directories are reused, and only the missing tail is made
+    assert!(root.create_dir_all("a/b/c").unwrap().same_file(&c));
+    let d = b.create_dir_all("../b/./c/d/").unwrap();
+    assert!(c.find("d").unwrap().same_file(&d));
+    assert!(d.create_dir_all("/a/b").unwrap().same_file(&b));
+    c.create_file("file", b"data").unwrap();
+    assert_eq!(root.create_dir_all("a/b/c/file/e").err(), Some(Error::NotADirectory));
+    assert_eq!(root.create_dir_all("a/b/c/file").err(), Some(Error::NotADirectory));
+    assert!(c.find("file").unwrap().find("e").is_none());
+}
+
+#[test]
+fn sync_data_test() {
+    let _guard = test_guard();
+    let buf: &'static mut [u8] = Box::leak(vec![0u8; 2048 * BLOCK_SZ].into_boxed_slice());
+    let block_device = Arc::new(CountingBlockDevice::new(SliceBlockDevice::new(buf)));
+    let tfs = TinyFileSystem::create(block_device.clone(), 2048, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    //both disk inodes share one block with the root's
+    let file_a = root_inode.create_file("file_a", &[1u8; 2 * BLOCK_SZ]).unwrap();
+    let file_b = root_inode.create_file("file_b", &[1u8; BLOCK_SZ]).unwrap();
+    set_background_flush(Some(usize::MAX));
+    file_a.write_at(0, &[2u8; 2 * BLOCK_SZ]).unwrap();
+    //file_b grows, leaving the shared disk inode block dirty
+    file_b.write_at(BLOCK_SZ, &[3u8; BLOCK_SZ]).unwrap();
+    block_device.take_write_calls();
+    //file_a kept its size and blocks, so only its data is written
+    file_a.sync_data();
+    assert_eq!(block_device.take_write_calls(), (2, 0));
+    //the new data block of file_b and the disk inode block
+    file_b.sync_data();
+    assert_eq!(block_device.take_write_calls(), (2, 0));
+    file_a.sync_all();
+    assert_eq!(block_device.take_write_calls(), (0, 0));
+    file_a.write_at(2 * BLOCK_SZ, &[4u8; 10]).unwrap();
+    file_a.sync_data();
+    assert_eq!(block_device.take_write_calls(), (2, 0));
+    set_background_flush(None);
+    clear_block_cache();
+    let mut expected = vec![2u8; 2 * BLOCK_SZ];
+    expected.extend_from_slice(&[4u8; 10]);
+    assert_eq!(file_a.read_all(), expected);
+    assert_eq!(file_b.read_all()[BLOCK_SZ..], [3u8; BLOCK_SZ]);
+}
+
+#[test]
+fn indirect2_resume_growth_test() {
+    let _guard = test_guard();
+    let block_file = test_block_file("indirect2_resume_growth.img", 4096);
+    let tfs = TinyFileSystem::create(block_file, 4096, 1).unwrap();
+    let root_inode = TinyFileSystem::root_inode(&tfs);
+    let file = root_inode.create("file").unwrap();
+    //the first grow stops 10 blocks into the second first-level block of
+    //indirect2, the second one resumes there and fills it further
+    let indirect2_start = 26 + BLOCK_SZ / 4;
+    let first_len = (indirect2_start + BLOCK_SZ / 4 + 10) * BLOCK_SZ;
+    let total_len = first_len + 50 * BLOCK_SZ;
+    let contents: Vec<u8> = (0..total_len).map(|i| (i / BLOCK_SZ) as u8 ^ i as u8).collect();
+    file.write_at(0, &contents[..first_len]).unwrap();
+    file.write_at(first_len, &contents[first_len..]).unwrap();
+    assert_eq!(file.read_all(), contents);
+    assert_eq!(file.index_block_count(), 4);
+    clear_block_cache();
+    assert_eq!(file.read_all(), contents);
+    assert!(tfs.lock().verify_free_counts());
+}
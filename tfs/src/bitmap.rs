@@ -1,5 +1,5 @@
 //! Bitmap for {inode_bitmap/data_bitmap}
-use super::{get_block_cache, BlockDevice, BLOCK_SZ};
+use super::{get_block_cache, get_block_cache_for_overwrite, BlockDevice, Error, BLOCK_SZ};
 
 use alloc::sync::Arc;
 
@@ -27,7 +27,7 @@ impl Bitmap {
     pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
         for inner_id in 0..self.blocks {
             let pos = get_block_cache(
-                inner_id + self.start_block_id as usize,
+                inner_id + self.start_block_id,
                 Arc::clone(block_device)
             )
             .lock()
@@ -52,9 +52,61 @@ impl Bitmap {
         }
         None
     }
+    /// Allocate count consecutive bits below limit, return the first one
+    pub fn alloc_contiguous(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+        limit: usize,
+    ) -> Option<usize> {
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for inner_id in 0..self.blocks {
+            if run_len == count {
+                break;
+            }
+            let bitmap_block = get_block_cache(
+                inner_id + self.start_block_id,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+            for pos in 0..BLOCK_BITS {
+                let bit = inner_id * BLOCK_BITS + pos;
+                if bit >= limit || run_len == count {
+                    break;
+                }
+                if bitmap_block[pos / 64] & (1u64 << (pos % 64)) != 0 {
+                    run_len = 0;
+                    continue;
+                }
+                if run_len == 0 {
+                    run_start = bit;
+                }
+                run_len += 1;
+            }
+        }
+        if count == 0 || run_len < count {
+            return None;
+        }
+        let run = (run_start..run_start + count).filter_map(|bit| self.decomposition(bit));
+        for (block_pos, bits64_pos, inner_pos) in run {
+            get_block_cache(
+                self.start_block_id + block_pos,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            });
+        }
+        Some(run_start)
+    }
     /// Deallocate a block
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
-        let (block_pos, bits64_pos, inner_pos) = Self::decomposition(bit);
+    /// A bit past the area is refused with `Error::OutOfBounds` instead of
+    /// clearing a bit in whatever block follows the bitmap
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> Result<(), Error> {
+        let (block_pos, bits64_pos, inner_pos) = self.decomposition(bit).ok_or(Error::OutOfBounds)?;
         get_block_cache(
             self.start_block_id + block_pos,
             Arc::clone(block_device)
@@ -65,16 +117,233 @@ impl Bitmap {
             assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
             bitmap_block[bits64_pos] -= 1u64 << inner_pos;
         });
+        Ok(())
+    }
+    /// Free every bit by zeroing all blocks of the area
+    /// The blocks are overwritten whole, so none is read from the device
+    pub fn clear_all(&self, block_device: &Arc<dyn BlockDevice>) {
+        for inner_id in 0..self.blocks {
+            get_block_cache_for_overwrite(
+                inner_id + self.start_block_id,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                bitmap_block.fill(0);
+            });
+        }
+    }
+    /// Iterate over allocated bits lazily
+    /// A copy of each bitmap block is taken, so no block cache lock
+    /// is held between two items
+    pub fn iter_allocated<'a>(
+        &'a self,
+        block_device: &'a Arc<dyn BlockDevice>,
+    ) -> impl Iterator<Item = usize> + 'a {
+        (0..self.blocks).flat_map(move |inner_id| {
+            let bitmap_block = get_block_cache(
+                inner_id + self.start_block_id,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+            bitmap_block
+                .into_iter()
+                .enumerate()
+                .flat_map(move |(bits64_pos, mut bits64)| {
+                    core::iter::from_fn(move || {
+                        if bits64 == 0 {
+                            return None;
+                        }
+                        let inner_pos = bits64.trailing_zeros() as usize;
+                        // clear the lowest set bit
+                        bits64 &= bits64 - 1;
+                        Some(inner_id * BLOCK_BITS + bits64_pos * 64 + inner_pos)
+                    })
+                })
+        })
+    }
+    /// Count allocated bits
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|inner_id| {
+                get_block_cache(
+                    inner_id + self.start_block_id,
+                    Arc::clone(block_device)
+                )
+                .lock()
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block
+                        .iter()
+                        .map(|bits64| bits64.count_ones() as usize)
+                        .sum::<usize>()
+                })
+            })
+            .sum()
+    }
+    /// Get the lowest free bit without allocating it
+    pub fn first_free(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+        (0..self.blocks).find_map(|inner_id| {
+            get_block_cache(
+                inner_id + self.start_block_id,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block
+                    .iter()
+                    .enumerate()
+                    .find(|(_, bits64)| **bits64 != u64::MAX)
+                    .map(|(bits64_pos, bits64)| {
+                        inner_id * BLOCK_BITS + bits64_pos * 64 + bits64.trailing_ones() as usize
+                    })
+            })
+        })
+    }
+    /// Get the highest allocated bit
+    pub fn last_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+        (0..self.blocks).rev().find_map(|inner_id| {
+            get_block_cache(
+                inner_id + self.start_block_id,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .find(|(_, bits64)| **bits64 != 0)
+                    .map(|(bits64_pos, bits64)| {
+                        inner_id * BLOCK_BITS + bits64_pos * 64 + 63 - bits64.leading_zeros() as usize
+                    })
+            })
+        })
+    }
+    /// Resize the bitmap to new_blocks blocks
+    /// Added blocks are zeroed so all their bits read as free,
+    /// bits in the original blocks are left as they are
+    pub fn resize(&mut self, new_blocks: usize, block_device: &Arc<dyn BlockDevice>) {
+        for inner_id in self.blocks..new_blocks {
+            get_block_cache(
+                inner_id + self.start_block_id,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                bitmap_block.fill(0);
+            });
+        }
+        self.blocks = new_blocks;
+    }
+    /// Check that no bit at or past capacity is set
+    /// Allocation never leaves such a bit set, so one means the bitmap
+    /// is not where the geometry says it is
+    pub fn self_check(&self, block_device: &Arc<dyn BlockDevice>, capacity: usize) -> bool {
+        (capacity / BLOCK_BITS..self.blocks).all(|inner_id| {
+            get_block_cache(
+                inner_id + self.start_block_id,
+                Arc::clone(block_device)
+            )
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block.iter().enumerate().all(|(bits64_pos, bits64)| {
+                    let first_bit = inner_id * BLOCK_BITS + bits64_pos * 64;
+                    let valid = capacity.saturating_sub(first_bit).min(64);
+                    valid == 64 || bits64 >> valid == 0
+                })
+            })
+        })
+    }
+    /// Get the area of the bitmap as (start_block_id, blocks)
+    pub fn span(&self) -> (usize, usize) {
+        (self.start_block_id, self.blocks)
     }
     /// Get the max number of allocatable blocks
     pub fn maxium(&self) -> usize {
         self.blocks * BLOCK_BITS
     }
     /// Decomposition `bit_id` is used to [dealloc]
-    /// (block_pos, bits64_pos, inner_pos)
-    fn decomposition(mut bit: usize) -> (usize, usize, usize) {
+    /// (block_pos, bits64_pos, inner_pos), None if bit is past the area
+    fn decomposition(&self, mut bit: usize) -> Option<(usize, usize, usize)> {
         let block_pos = bit / BLOCK_BITS;
+        if block_pos >= self.blocks {
+            return None;
+        }
         bit %= BLOCK_BITS;
-        (block_pos, bit / 64, bit % 64)
+        Some((block_pos, bit / 64, bit % 64))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SliceBlockDevice;
+    use alloc::{boxed::Box, vec, vec::Vec};
+
+    #[test]
+    fn resize_keeps_allocated_bits() {
+        let buf = Box::leak(vec![0xffu8; 4 * BLOCK_SZ].into_boxed_slice());
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        let mut bitmap = Bitmap::new(1, 1);
+        //the first block starts out full, clear a few bits in it
+        for bit in [0, 7, 100] {
+            bitmap.dealloc(&block_device, bit).unwrap();
+        }
+        bitmap.resize(2, &block_device);
+        assert_eq!(bitmap.maxium(), 2 * BLOCK_BITS);
+        assert_eq!(bitmap.count_allocated(&block_device), BLOCK_BITS - 3);
+        //freed bits in the old block come first, then the new capacity
+        let allocated: Vec<usize> = (0..4)
+            .map(|_| bitmap.alloc(&block_device).unwrap())
+            .collect();
+        assert_eq!(allocated, [0, 7, 100, BLOCK_BITS]);
+    }
+    #[test]
+    fn first_free_and_last_allocated() {
+        let buf = Box::leak(vec![0u8; 3 * BLOCK_SZ].into_boxed_slice());
+        //bits 0..5, 7, 200 and one in the second bitmap block
+        for bit in [0, 1, 2, 3, 4, 7, 200, BLOCK_BITS + 5] {
+            buf[BLOCK_SZ + bit / 8] |= 1 << (bit % 8);
+        }
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        let bitmap = Bitmap::new(1, 2);
+        assert_eq!(bitmap.first_free(&block_device), Some(5));
+        assert_eq!(bitmap.last_allocated(&block_device), Some(BLOCK_BITS + 5));
+        //the queries leave the bitmap as it was
+        assert_eq!(bitmap.count_allocated(&block_device), 8);
+        assert_eq!(bitmap.alloc(&block_device), Some(5));
+        assert_eq!(bitmap.first_free(&block_device), Some(6));
+        bitmap.dealloc(&block_device, BLOCK_BITS + 5).unwrap();
+        assert_eq!(bitmap.last_allocated(&block_device), Some(200));
+        let empty = Bitmap::new(2, 0);
+        assert_eq!(empty.first_free(&block_device), None);
+        assert_eq!(empty.last_allocated(&block_device), None);
+    }
+    #[test]
+    fn clear_all_frees_every_bit() {
+        let buf = Box::leak(vec![0xffu8; 3 * BLOCK_SZ].into_boxed_slice());
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        let bitmap = Bitmap::new(1, 2);
+        assert_eq!(bitmap.alloc(&block_device), None);
+        bitmap.clear_all(&block_device);
+        assert_eq!(bitmap.count_allocated(&block_device), 0);
+        assert_eq!(bitmap.alloc(&block_device), Some(0));
+        assert_eq!(bitmap.alloc(&block_device), Some(1));
+    }
+    #[test]
+    fn dealloc_past_the_area_is_refused() {
+        let buf = Box::leak(vec![0xffu8; 3 * BLOCK_SZ].into_boxed_slice());
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        //block 2 stands for the area after a one-block bitmap
+        let bitmap = Bitmap::new(1, 1);
+        for bit in [bitmap.maxium(), bitmap.maxium() + 3, usize::MAX] {
+            assert_eq!(bitmap.dealloc(&block_device, bit), Err(Error::OutOfBounds));
+        }
+        let next_block = get_block_cache(2, Arc::clone(&block_device))
+            .lock()
+            .read(0, |block: &BitmapBlock| *block);
+        assert!(next_block.iter().all(|bits64| *bits64 == u64::MAX));
+        assert_eq!(bitmap.dealloc(&block_device, bitmap.maxium() - 1), Ok(()));
+        assert_eq!(bitmap.count_allocated(&block_device), BLOCK_BITS - 1);
+    }
+}
@@ -1,13 +1,112 @@
 //! BlockCache/BlockCacheManager
-use crate::block_dev;
-
 use super::{BlockDevice, BLOCK_SZ};
 
 use lazy_static::*;
-use alloc::collections::VecDeque;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use spin::Mutex;
 
+/// Block cache activity reported to the logger set by `set_io_logger`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoEvent {
+    /// A block is read from its device into the cache
+    Read(usize),
+    /// A dirty cached block is written back to its device
+    Write(usize),
+    /// A requested block is already cached
+    CacheHit(usize),
+    /// A requested block has to be loaded
+    CacheMiss(usize),
+    /// A cached block is dropped to make room for another
+    Evict(usize),
+}
+
+// fn(IoEvent) stored as a raw pointer, null when no logger is set
+static IO_LOGGER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Report every block cache event to logger, e.g. to trace which blocks
+/// the filesystem touches. Events of all devices are reported.
+/// logger may run with the block cache locked, so it must not call into tiny-fs.
+pub fn set_io_logger(logger: fn(IoEvent)) {
+    IO_LOGGER.store(logger as *mut (), Ordering::Release);
+}
+
+/// Stop reporting block cache events
+pub fn clear_io_logger() {
+    IO_LOGGER.store(core::ptr::null_mut(), Ordering::Release);
+}
+
+fn log_io(event: IoEvent) {
+    let logger = IO_LOGGER.load(Ordering::Acquire);
+    if !logger.is_null() {
+        // only set_io_logger stores a non-null pointer, and it is a fn(IoEvent)
+        let logger = unsafe { core::mem::transmute::<*mut (), fn(IoEvent)>(logger) };
+        logger(event);
+    }
+}
+
+// number of cached blocks of all devices that are modified but not written back
+static DIRTY_BLOCKS: AtomicUsize = AtomicUsize::new(0);
+// whether write_at leaves dirty blocks to a flush worker
+static BACKGROUND_FLUSH: AtomicBool = AtomicBool::new(false);
+// dirty blocks from which needs_flush asks the worker to run
+static FLUSH_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+
+/// With Some(dirty_threshold), `write_at` no longer writes back its blocks,
+/// a worker driven by the caller does with `flush_dirty_blocks` once
+/// `needs_flush` reports dirty_threshold dirty blocks. Evicted blocks and
+/// explicit syncs are still written back. None syncs every `write_at` again.
+pub fn set_background_flush(dirty_threshold: Option<usize>) {
+    FLUSH_THRESHOLD.store(dirty_threshold.unwrap_or(0), Ordering::Relaxed);
+    BACKGROUND_FLUSH.store(dirty_threshold.is_some(), Ordering::Release);
+}
+
+/// Whether background flushing is on and dirty blocks reached its threshold
+pub fn needs_flush() -> bool {
+    background_flush()
+        && DIRTY_BLOCKS.load(Ordering::Relaxed) >= FLUSH_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Write back up to max dirty blocks, the earliest loaded first,
+/// and return how many were written. Blocks in use are left for later.
+/// Unlike `block_cache_sync_all` this may run without the fs lock.
+pub fn flush_dirty_blocks(max: usize) -> usize {
+    BLOCK_CACHE_MANAGER.lock().flush_dirty(max)
+}
+
+/// Keep dirty blocks evicted from the cache on a write-back list, written
+/// with `write_blocks` in runs of consecutive blocks once it holds blocks
+/// entries, rather than writing each one as it is evicted.
+/// 0 or 1 writes every evicted block at once, as by default.
+/// Syncs and `flush_dirty_blocks` write the list out as well.
+pub fn set_writeback_batch(blocks: usize) {
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    manager.writeback_batch = blocks;
+    if manager.writeback.len() >= blocks {
+        manager.flush_writeback();
+    }
+}
+
+/// Whether write_at leaves its dirty blocks to `flush_dirty_blocks`
+pub fn background_flush() -> bool {
+    BACKGROUND_FLUSH.load(Ordering::Acquire)
+}
+
+// generation of the next BlockCache made
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+fn mark_dirty(modified: &mut bool) {
+    if !*modified {
+        *modified = true;
+        DIRTY_BLOCKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
 /// BlockCache mapped on block device
 pub struct BlockCache {
@@ -19,21 +118,54 @@ pub struct BlockCache {
     block_device: Arc<dyn BlockDevice>,
     /// whether dirty
     modified: bool,
+    /// when this BlockCache was made, see `generation`
+    generation: u64,
 }
 
 impl BlockCache {
+    /// A dirty BlockCache holding data not yet written to block device
+    fn with_data(block_id: usize, block_device: Arc<dyn BlockDevice>, cache: [u8; BLOCK_SZ]) -> Self {
+        BlockCache {
+            cache,
+            block_id,
+            block_device,
+            modified: true,
+            generation: next_generation(),
+        }
+    }
+
+    /// A clean BlockCache of zeros, for a block about to be overwritten
+    /// whole, so its content is not read from block device
+    fn unloaded(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+        BlockCache {
+            cache: [0u8; BLOCK_SZ],
+            block_id,
+            block_device,
+            modified: false,
+            generation: next_generation(),
+        }
+    }
+
     /// Load a new BlockCache from block device
     pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
         let mut cache = [0u8; BLOCK_SZ];
+        log_io(IoEvent::Read(block_id));
         block_device.read_block(block_id, &mut cache);
         BlockCache {
             cache,
             block_id,
             block_device,
             modified: false,
+            generation: next_generation(),
         }
     }
 
+    /// Unique among BlockCaches and higher for later ones, so a block
+    /// evicted and loaded again is told apart from the copy seen before
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
     fn addr_of_offset(&self, offset: usize) -> usize {
         &self.cache[offset] as *const u8 as usize
     }
@@ -67,17 +199,62 @@ impl BlockCache {
     }
 
     pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
+        mark_dirty(&mut self.modified);
         f(self.obtain_mut(offset))
     }
 
     pub fn sync(&mut self) {
         if self.modified {
-            self.modified = false;
+            log_io(IoEvent::Write(self.block_id));
             self.block_device.write_block(self.block_id, &self.cache);
+            //clean only once the write has completed
+            self.modified = false;
+            DIRTY_BLOCKS.fetch_sub(1, Ordering::Relaxed);
         }
     }    
 }
 
+/* checked accessors for offsets that come from untrusted metadata */
+impl BlockCache {
+    /// Like obtain_ref, but None if T at offset does not fit in the block
+    pub fn try_obtain_ref<T>(&self, offset: usize) -> Option<&T>
+    where
+        T: Sized,
+    {
+        let type_size = core::mem::size_of::<T>();
+        if offset.checked_add(type_size)? > BLOCK_SZ {
+            return None;
+        }
+        Some(self.obtain_ref(offset))
+    }
+
+    /// Like obtain_mut, but None if T at offset does not fit in the block
+    pub fn try_obtain_mut<T>(&mut self, offset: usize) -> Option<&mut T>
+    where
+        T: Sized,
+    {
+        let type_size = core::mem::size_of::<T>();
+        if offset.checked_add(type_size)? > BLOCK_SZ {
+            return None;
+        }
+        Some(self.obtain_mut(offset))
+    }
+
+    /// Like read, but None instead of a panic on a bad offset
+    pub fn try_read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> Option<V> {
+        self.try_obtain_ref(offset).map(f)
+    }
+
+    /// Like modify, but None instead of a panic on a bad offset
+    pub fn try_modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> Option<V> {
+        let value = self.try_obtain_mut(offset).map(f);
+        if value.is_some() {
+            mark_dirty(&mut self.modified);
+        }
+        value
+    }
+}
+
 impl Drop for BlockCache {
     fn drop(&mut self) {
         self.sync();
@@ -85,48 +262,158 @@ impl Drop for BlockCache {
 }
 
 /* BlockCache-Manager */
-const BLOCK_CACHE_SIZE: usize = 16;
+const BLOCK_CACHE_SIZE: usize = 256;
+
+// (device_id, block_id)
+type CacheKey = (usize, usize);
+
+// key, device and data of an evicted dirty block
+type PendingBlock = (CacheKey, Arc<dyn BlockDevice>, [u8; BLOCK_SZ]);
 
 pub struct BlockCacheManager {
-    // (block_id, block_cache)
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    // key -> (block_cache, stamp of its last use)
+    caches: BTreeMap<CacheKey, (Arc<Mutex<BlockCache>>, u64)>,
+    // stamp -> key of caches, least recently used first, for eviction
+    lru: BTreeMap<u64, CacheKey>,
+    // stamp of the next use
+    clock: u64,
+    // evicted dirty blocks waiting to be written back, they still count
+    // in DIRTY_BLOCKS
+    writeback: Vec<PendingBlock>,
+    // entries of writeback from which it is written out
+    writeback_batch: usize,
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
         Self {
-            queue: VecDeque::new(),
+            caches: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            writeback: Vec::new(),
+            writeback_batch: 0,
         }
     }
 
     pub fn get_block_cache(&mut self, block_id: usize, block_device: Arc<dyn BlockDevice>)
         -> Arc<Mutex<BlockCache>>
     {
-        if let Some(pair) =
-            self.queue.iter().find(|pair| pair.0 == block_id)
-        {
-            Arc::clone(&pair.1)
+        self.get_or_insert(block_id, block_device, true)
+    }
+
+    /// Get the cached block, or insert it, read from its device if load
+    fn get_or_insert(&mut self, block_id: usize, block_device: Arc<dyn BlockDevice>, load: bool)
+        -> Arc<Mutex<BlockCache>>
+    {
+        let key = (device_id(&block_device), block_id);
+        let stamp = self.clock;
+        self.clock += 1;
+        if let Some((block_cache, last_use)) = self.caches.get_mut(&key) {
+            log_io(IoEvent::CacheHit(block_id));
+            self.lru.remove(last_use);
+            self.lru.insert(stamp, key);
+            *last_use = stamp;
+            Arc::clone(block_cache)
         } else {
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // Delete a block_cache that is not used elsewhere
-                if let Some((idx, _)) = self.queue
+            log_io(IoEvent::CacheMiss(block_id));
+            if self.caches.len() == BLOCK_CACHE_SIZE {
+                // Delete the least recently used block_cache that is not used elsewhere
+                if let Some((&last_use, &key)) = self.lru
                     .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
+                    .find(|(_, key)| Arc::strong_count(&self.caches[key].0) == 1)
                 {
-                    self.queue.drain(idx..=idx);
+                    self.lru.remove(&last_use);
+                    log_io(IoEvent::Evict(key.1));
+                    let (evicted, _) = self.caches.remove(&key).unwrap();
+                    self.evict(key, &evicted);
                 } else {
                     panic!("Run out of BlockCache!");
                 }
             }
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
-                block_id,
-                Arc::clone(&block_device),
-            )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
+            //a block on the write-back list is newer than its device copy
+            let block_cache = match self.writeback.iter().position(|(pending, _, _)| *pending == key) {
+                Some(idx) => {
+                    let (_, _, data) = self.writeback.swap_remove(idx);
+                    BlockCache::with_data(block_id, Arc::clone(&block_device), data)
+                }
+                None if load => BlockCache::new(block_id, Arc::clone(&block_device)),
+                None => BlockCache::unloaded(block_id, Arc::clone(&block_device)),
+            };
+            let block_cache = Arc::new(Mutex::new(block_cache));
+            self.caches.insert(key, (Arc::clone(&block_cache), stamp));
+            self.lru.insert(stamp, key);
             block_cache
         }
     }
+
+    /// Move the data of an evicted dirty block to the write-back list,
+    /// unless batching is off and dropping it writes it back at once
+    fn evict(&mut self, key: CacheKey, evicted: &Arc<Mutex<BlockCache>>) {
+        if self.writeback_batch <= 1 {
+            return;
+        }
+        let mut cache = evicted.lock();
+        if !cache.modified {
+            return;
+        }
+        cache.modified = false;
+        self.writeback.push((key, Arc::clone(&cache.block_device), cache.cache));
+        if self.writeback.len() >= self.writeback_batch {
+            self.flush_writeback();
+        }
+    }
+
+    /// Write out the write-back list, a run of consecutive blocks of one
+    /// device by a single write_blocks call, and return the count
+    fn flush_writeback(&mut self) -> usize {
+        let mut writeback = core::mem::take(&mut self.writeback);
+        writeback.sort_unstable_by_key(|(key, _, _)| *key);
+        let mut start = 0;
+        while start < writeback.len() {
+            let ((device_id, start_block_id), _, _) = writeback[start];
+            let mut end = start + 1;
+            while end < writeback.len() && writeback[end].0 == (device_id, start_block_id + (end - start)) {
+                end += 1;
+            }
+            let mut buf: Vec<u8> = Vec::with_capacity((end - start) * BLOCK_SZ);
+            for ((_, block_id), _, data) in writeback[start..end].iter() {
+                log_io(IoEvent::Write(*block_id));
+                buf.extend_from_slice(data);
+            }
+            writeback[start].1.write_blocks(start_block_id, &buf);
+            start = end;
+        }
+        DIRTY_BLOCKS.fetch_sub(writeback.len(), Ordering::Relaxed);
+        writeback.len()
+    }
+
+    /// Write back up to max dirty blocks, least recently used first, and
+    /// return the count.
+    /// A locked block is skipped rather than waited for, so this never
+    /// blocks on a cache while the manager is locked.
+    /// The write-back list is written out first, whatever its length.
+    pub fn flush_dirty(&mut self, max: usize) -> usize {
+        let mut flushed = self.flush_writeback();
+        for key in self.lru.values() {
+            if flushed == max {
+                break;
+            }
+            if let Some(mut cache) = self.caches[key].0.try_lock() {
+                if cache.modified {
+                    cache.sync();
+                    flushed += 1;
+                }
+            }
+        }
+        flushed
+    }
+}
+
+/// Blocks of different devices share the manager, so entries are keyed by
+/// the device as well. The cached entry keeps its device alive, hence the
+/// address cannot be reused while the entry exists.
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
 }
 
 lazy_static! {
@@ -142,9 +429,233 @@ pub fn get_block_cache(block_id: usize, block_device: Arc<dyn BlockDevice>)
         .get_block_cache(block_id, block_device)
 }
 
-pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+/// Like `get_block_cache`, for a block the caller is about to overwrite
+/// whole: if it is not cached, it is not read from its device and reads
+/// as zeros until written, which saves a read per block of aligned writes
+pub fn get_block_cache_for_overwrite(block_id: usize, block_device: Arc<dyn BlockDevice>)
+    -> Arc<Mutex<BlockCache>>
+{
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .get_or_insert(block_id, block_device, false)
+}
+
+/// Get the generation of block_id of block_device if it is cached, without
+/// loading it; a new one means the block was evicted and loaded again
+pub fn cached_generation(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> Option<u64> {
+    let block_cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .caches
+        .get(&(device_id(block_device), block_id))
+        .map(|(block_cache, _)| Arc::clone(block_cache))?;
+    //the manager is unlocked first, its holder may wait for this block
+    let generation = block_cache.lock().generation();
+    Some(generation)
+}
+
+/// Write back all dirty blocks and drop the cached blocks of every device,
+/// so the next access of any block reads it from its device again.
+/// No filesystem should be in use meanwhile, as a block still held
+/// elsewhere would get a second cache entry.
+pub fn clear_block_cache() {
+    block_cache_sync_all();
+    let caches = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        manager.lru.clear();
+        core::mem::take(&mut manager.caches)
+    };
+    drop(caches);
+}
+
+/// Write back all dirty blocks and drop the cached blocks of block_device
+/// that are not used elsewhere
+pub fn block_cache_drop_device(block_device: &Arc<dyn BlockDevice>) {
+    block_cache_sync_all();
+    let device_id = device_id(block_device);
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    let BlockCacheManager { caches, lru, .. } = &mut *manager;
+    caches.retain(|key, (cache, last_use)| {
+        let keep = key.0 != device_id || Arc::strong_count(cache) > 1;
+        if !keep {
+            lru.remove(last_use);
+        }
+        keep
+    });
+}
+
+/// Sync the cached ones among block_ids of block_device
+///
+/// No block cache is locked while the manager is, so unlike
+/// `block_cache_sync_all` this may run without the fs lock.
+pub fn block_cache_sync_blocks(block_device: &Arc<dyn BlockDevice>, block_ids: &[u32]) {
+    let device_id = device_id(block_device);
+    let caches: Vec<_> = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        manager.flush_writeback();
+        block_ids
+            .iter()
+            .filter_map(|&block_id| manager.caches.get(&(device_id, block_id as usize)))
+            .map(|(cache, _)| Arc::clone(cache))
+            .collect()
+    };
+    for cache in caches {
         cache.lock().sync();
     }
-}
\ No newline at end of file
+}
+
+/// Write back all dirty blocks, a run of consecutive dirty blocks
+/// of one device is written by a single write_blocks call
+pub fn block_cache_sync_all() {
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    manager.flush_writeback();
+    //caches are ordered by (device_id, block_id) already
+    let mut dirty: Vec<_> = manager.caches
+        .iter()
+        .map(|((device_id, block_id), (cache, _))| (*device_id, *block_id, cache.lock()))
+        .filter(|(_, _, cache)| cache.modified)
+        .collect();
+    let mut start = 0;
+    while start < dirty.len() {
+        let (device_id, start_block_id, _) = dirty[start];
+        let mut end = start + 1;
+        while end < dirty.len()
+            && dirty[end].0 == device_id
+            && dirty[end].1 == start_block_id + (end - start)
+        {
+            end += 1;
+        }
+        if end - start == 1 {
+            dirty[start].2.sync();
+        } else {
+            let mut buf: Vec<u8> = Vec::with_capacity((end - start) * BLOCK_SZ);
+            for (_, block_id, cache) in dirty[start..end].iter() {
+                log_io(IoEvent::Write(*block_id));
+                buf.extend_from_slice(&cache.cache);
+            }
+            dirty[start].2.block_device.write_blocks(start_block_id, &buf);
+            //clean only once the write has completed
+            for (_, _, cache) in dirty[start..end].iter_mut() {
+                cache.modified = false;
+            }
+            DIRTY_BLOCKS.fetch_sub(end - start, Ordering::Relaxed);
+        }
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ZeroDevice;
+
+    impl BlockDevice for ZeroDevice {
+        fn read_block(&self, _block_id: usize, buf: &mut [u8]) {
+            buf.fill(0);
+        }
+        fn write_block(&self, _block_id: usize, _buf: &[u8]) {}
+    }
+
+    #[test]
+    fn try_obtain_out_of_bounds() {
+        let mut cache = BlockCache::new(0, Arc::new(ZeroDevice));
+        assert_eq!(cache.try_read(BLOCK_SZ - 4, |v: &u32| *v), Some(0));
+        assert_eq!(cache.try_read(BLOCK_SZ - 2, |v: &u32| *v), None);
+        assert_eq!(cache.try_read(usize::MAX, |v: &u32| *v), None);
+        assert_eq!(cache.try_modify(BLOCK_SZ, |v: &mut u64| *v = 1), None);
+        assert!(!cache.modified);
+        assert_eq!(cache.try_modify(0, |v: &mut u64| *v = 1), Some(()));
+        assert!(cache.modified);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let device: Arc<dyn BlockDevice> = Arc::new(ZeroDevice);
+        let mut manager = BlockCacheManager::new();
+        for block_id in 0..BLOCK_CACHE_SIZE {
+            manager.get_block_cache(block_id, Arc::clone(&device));
+        }
+        //block 0 is used again, so block 1 is the one to go
+        manager.get_block_cache(0, Arc::clone(&device));
+        let held = manager.get_block_cache(2, Arc::clone(&device));
+        manager.get_block_cache(BLOCK_CACHE_SIZE, Arc::clone(&device));
+        let key = |block_id| (device_id(&device), block_id);
+        assert!(manager.caches.contains_key(&key(0)));
+        assert!(!manager.caches.contains_key(&key(1)));
+        //a block held elsewhere is skipped
+        manager.get_block_cache(0, Arc::clone(&device));
+        manager.get_block_cache(BLOCK_CACHE_SIZE + 1, Arc::clone(&device));
+        assert!(manager.caches.contains_key(&key(2)));
+        assert!(!manager.caches.contains_key(&key(3)));
+        assert_eq!(manager.caches.len(), BLOCK_CACHE_SIZE);
+        assert_eq!(manager.lru.len(), BLOCK_CACHE_SIZE);
+        drop(held);
+    }
+
+    //run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn lookup_bench() {
+        extern crate std;
+        use alloc::collections::VecDeque;
+        use std::time::{Duration, Instant};
+        //the former manager: one queue scanned on every lookup and eviction
+        struct ScanManager(VecDeque<(CacheKey, Arc<Mutex<BlockCache>>)>);
+        impl ScanManager {
+            fn get_block_cache(&mut self, block_id: usize, block_device: Arc<dyn BlockDevice>)
+                -> Arc<Mutex<BlockCache>>
+            {
+                let key = (device_id(&block_device), block_id);
+                if let Some((_, block_cache)) = self.0.iter().find(|(k, _)| *k == key) {
+                    return Arc::clone(block_cache);
+                }
+                if self.0.len() == BLOCK_CACHE_SIZE {
+                    let idx = self.0
+                        .iter()
+                        .position(|(_, cache)| Arc::strong_count(cache) == 1)
+                        .unwrap();
+                    self.0.remove(idx);
+                }
+                let block_cache = Arc::new(Mutex::new(BlockCache::new(block_id, block_device)));
+                self.0.push_back((key, Arc::clone(&block_cache)));
+                block_cache
+            }
+        }
+        const LOOKUPS: usize = 200_000;
+        let device: Arc<dyn BlockDevice> = Arc::new(ZeroDevice);
+        let time = |blocks: usize, get: &mut dyn FnMut(usize) -> Arc<Mutex<BlockCache>>| -> Duration {
+            let start = Instant::now();
+            for i in 0..LOOKUPS {
+                get(i * 97 % blocks);
+            }
+            start.elapsed()
+        };
+        //hits: 240 blocks fit in the cache, misses: 512 blocks do not
+        for blocks in [240, 512] {
+            let mut scan = ScanManager(VecDeque::new());
+            let mut map = BlockCacheManager::new();
+            let scan_time = time(blocks, &mut |block_id| scan.get_block_cache(block_id, Arc::clone(&device)));
+            let map_time = time(blocks, &mut |block_id| map.get_block_cache(block_id, Arc::clone(&device)));
+            std::println!(
+                "{} lookups over {} blocks, capacity {}: scan {:?}, map {:?}",
+                LOOKUPS, blocks, BLOCK_CACHE_SIZE, scan_time, map_time
+            );
+        }
+    }
+
+    #[test]
+    fn reload_gets_higher_generation() {
+        let block_device: Arc<dyn BlockDevice> = Arc::new(ZeroDevice);
+        let mut manager = BlockCacheManager::new();
+        let generation = manager.get_block_cache(0, Arc::clone(&block_device)).lock().generation();
+        //still cached, the same copy
+        let cached = manager.get_block_cache(0, Arc::clone(&block_device));
+        assert_eq!(cached.lock().generation(), generation);
+        drop(cached);
+        for block_id in 1..=BLOCK_CACHE_SIZE {
+            manager.get_block_cache(block_id, Arc::clone(&block_device));
+        }
+        let reloaded = manager.get_block_cache(0, Arc::clone(&block_device)).lock().generation();
+        assert!(reloaded > generation);
+    }
+}
@@ -1,5 +1,10 @@
 //! impl BlockDevice for RVOS_Drivers
+use super::BLOCK_SZ;
+
 use core::any::Any;
+use spin::Mutex;
+use alloc::vec;
+use alloc::vec::Vec;
 
 /// BlockDevice is implemented by outer tiny-fs user
 pub trait BlockDevice: Send + Sync + Any {
@@ -7,4 +12,199 @@ pub trait BlockDevice: Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     /// write data to block device by os driver
     fn write_block(&self, block_id: usize, buf: &[u8]);
-}
\ No newline at end of file
+    /// read consecutive blocks from start_block_id, buf holds whole blocks
+    fn read_blocks(&self, start_block_id: usize, buf: &mut [u8]) {
+        assert_eq!(buf.len() % BLOCK_SZ, 0, "Not a complete block!");
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(start_block_id + i, block);
+        }
+    }
+    /// write consecutive blocks from start_block_id, buf holds whole blocks
+    fn write_blocks(&self, start_block_id: usize, buf: &[u8]) {
+        assert_eq!(buf.len() % BLOCK_SZ, 0, "Not a complete block!");
+        for (i, block) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.write_block(start_block_id + i, block);
+        }
+    }
+    /// copy count blocks from start to the same place of other, bypassing the block cache
+    fn copy_range(&self, other: &dyn BlockDevice, start: usize, count: usize) {
+        let mut block = [0u8; BLOCK_SZ];
+        for block_id in start..start + count {
+            self.read_block(block_id, &mut block);
+            other.write_block(block_id, &block);
+        }
+    }
+}
+
+/// BlockDevice over a caller-provided byte slice, no allocation is needed
+pub struct SliceBlockDevice<'a>(Mutex<&'a mut [u8]>);
+
+impl<'a> SliceBlockDevice<'a> {
+    /// Create a block device on buf, whose length must be a multiple of BLOCK_SZ
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        assert_eq!(buf.len() % BLOCK_SZ, 0, "Not a complete block!");
+        Self(Mutex::new(buf))
+    }
+    /// Get the number of blocks on the device
+    pub fn blocks(&self) -> usize {
+        self.0.lock().len() / BLOCK_SZ
+    }
+}
+
+impl BlockDevice for SliceBlockDevice<'static> {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let start = block_id * BLOCK_SZ;
+        buf[..BLOCK_SZ].copy_from_slice(&self.0.lock()[start..start + BLOCK_SZ]);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let start = block_id * BLOCK_SZ;
+        self.0.lock()[start..start + BLOCK_SZ].copy_from_slice(&buf[..BLOCK_SZ]);
+    }
+}
+
+/// BlockDevice of BLOCK_SZ blocks over a device with larger sectors
+///
+/// inner reads and writes whole sectors of sector_size bytes, so a block
+/// is written by reading its sector, patching it and writing it back.
+pub struct SubBlockDevice<D> {
+    inner: D,
+    sector_size: usize,
+    // one sector, the lock keeps read-modify-writes of a sector apart
+    sector: Mutex<Vec<u8>>,
+}
+
+impl<D: BlockDevice> SubBlockDevice<D> {
+    /// Wrap inner, whose sector_size must be a multiple of BLOCK_SZ
+    pub fn new(inner: D, sector_size: usize) -> Self {
+        assert!(
+            sector_size >= BLOCK_SZ && sector_size.is_multiple_of(BLOCK_SZ),
+            "Not a multiple of the block size!"
+        );
+        Self {
+            inner,
+            sector_size,
+            sector: Mutex::new(vec![0u8; sector_size]),
+        }
+    }
+    /// Get the wrapped device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+    /// Get the (sector, byte offset in it) holding block_id
+    fn locate(&self, block_id: usize) -> (usize, usize) {
+        let blocks_per_sector = self.sector_size / BLOCK_SZ;
+        (block_id / blocks_per_sector, block_id % blocks_per_sector * BLOCK_SZ)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for SubBlockDevice<D> {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let (sector_id, offset) = self.locate(block_id);
+        let mut sector = self.sector.lock();
+        self.inner.read_block(sector_id, &mut sector);
+        buf[..BLOCK_SZ].copy_from_slice(&sector[offset..offset + BLOCK_SZ]);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let (sector_id, offset) = self.locate(block_id);
+        let mut sector = self.sector.lock();
+        self.inner.read_block(sector_id, &mut sector);
+        sector[offset..offset + BLOCK_SZ].copy_from_slice(&buf[..BLOCK_SZ]);
+        self.inner.write_block(sector_id, &sector);
+    }
+}
+
+/// Panic message of an operation failed by FaultyBlockDevice
+#[cfg(feature = "test-util")]
+pub const FAULT_MARKER: &str = "tiny-fs: injected block device fault";
+
+/// BlockDevice wrapper that fails chosen operations, for fault testing
+///
+/// BlockDevice methods cannot return errors, so a failed operation
+/// panics with FAULT_MARKER and never reaches the inner device.
+#[cfg(feature = "test-util")]
+pub struct FaultyBlockDevice<D> {
+    inner: D,
+    faults: Mutex<Faults>,
+}
+
+#[cfg(feature = "test-util")]
+#[derive(Default)]
+struct Faults {
+    reads_to_fail: Vec<usize>,
+    writes_to_fail: Vec<usize>,
+    writes_left: Option<usize>,
+    writes: usize,
+}
+
+#[cfg(feature = "test-util")]
+impl<D: BlockDevice> FaultyBlockDevice<D> {
+    /// Wrap inner without any fault queued
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(Faults::default()),
+        }
+    }
+    /// Get the wrapped device
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+    /// Fail the next read from block_id
+    pub fn fail_next_read(&self, block_id: usize) {
+        self.faults.lock().reads_to_fail.push(block_id);
+    }
+    /// Fail the next write to block_id
+    pub fn fail_next_write(&self, block_id: usize) {
+        self.faults.lock().writes_to_fail.push(block_id);
+    }
+    /// Let count more writes through, then fail every write
+    pub fn fail_after_writes(&self, count: usize) {
+        self.faults.lock().writes_left = Some(count);
+    }
+    /// Drop all queued faults
+    pub fn clear_faults(&self) {
+        let mut faults = self.faults.lock();
+        faults.reads_to_fail.clear();
+        faults.writes_to_fail.clear();
+        faults.writes_left = None;
+    }
+    /// Get the number of writes that reached the inner device
+    pub fn writes(&self) -> usize {
+        self.faults.lock().writes
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl<D: BlockDevice> BlockDevice for FaultyBlockDevice<D> {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let mut faults = self.faults.lock();
+        if let Some(i) = faults.reads_to_fail.iter().position(|&id| id == block_id) {
+            faults.reads_to_fail.remove(i);
+            drop(faults);
+            panic!("{}", FAULT_MARKER);
+        }
+        drop(faults);
+        self.inner.read_block(block_id, buf);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut faults = self.faults.lock();
+        let queued = faults.writes_to_fail.iter().position(|&id| id == block_id);
+        if let Some(i) = queued {
+            faults.writes_to_fail.remove(i);
+        }
+        let exhausted = faults.writes_left == Some(0);
+        if let Some(left) = faults.writes_left.as_mut() {
+            *left = left.saturating_sub(1);
+        }
+        if queued.is_some() || exhausted {
+            drop(faults);
+            panic!("{}", FAULT_MARKER);
+        }
+        faults.writes += 1;
+        drop(faults);
+        self.inner.write_block(block_id, buf);
+    }
+}
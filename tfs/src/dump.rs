@@ -0,0 +1,103 @@
+//! ImageDump, a description of a whole image for external tools
+use super::{
+    get_block_cache,
+    DiskInode, SuperBlock,
+    TinyFileSystem,
+};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Layout and contents of an image, as returned by `TinyFileSystem::dump`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageDump {
+    /// Blocks of the whole image
+    pub total_blocks: u32,
+    /// Blocks of the inode bitmap
+    pub inode_bitmap_blocks: u32,
+    /// Blocks of the inode area
+    pub inode_area_blocks: u32,
+    /// Blocks of the data bitmap
+    pub data_bitmap_blocks: u32,
+    /// Blocks of the data area
+    pub data_area_blocks: u32,
+    /// Blocks of the refcount area, 0 unless created by `create_dedup`
+    pub refcount_blocks: u32,
+    /// Blocks of the inode crc area, 0 unless created by `create_checked`
+    pub inode_crc_blocks: u32,
+    /// Allocated bits of the inode bitmap
+    pub used_inodes: u32,
+    /// Allocated bits of the data bitmap
+    pub used_data_blocks: u32,
+    /// Every allocated inode, in inode number order
+    pub inodes: Vec<InodeDump>,
+}
+
+/// One inode of an `ImageDump`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InodeDump {
+    /// Inode number, i.e. its bit in the inode bitmap
+    pub inode: u32,
+    /// "file", "dir" or "symlink"
+    pub kind: &'static str,
+    /// Size in bytes
+    pub size: u32,
+    /// Global ids of the data blocks, 0 for a hole, empty if the data is inline
+    pub blocks: Vec<u32>,
+}
+
+impl TinyFileSystem {
+    ///Describe the superblock, the bitmaps and every allocated inode
+    pub fn dump(&self) -> ImageDump {
+        let super_block = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (
+                    super_block.total_blocks,
+                    super_block.inode_bitmap_blocks,
+                    super_block.inode_area_blocks,
+                    super_block.data_bitmap_blocks,
+                    super_block.data_area_blocks,
+                    super_block.refcount_blocks,
+                    super_block.inode_crc_blocks,
+                )
+            });
+        let inodes: Vec<InodeDump> = self.inode_bitmap
+            .iter_allocated(&self.block_device)
+            .map(|inode_bit| self.dump_inode(inode_bit as u32))
+            .collect();
+        ImageDump {
+            total_blocks: super_block.0,
+            inode_bitmap_blocks: super_block.1,
+            inode_area_blocks: super_block.2,
+            data_bitmap_blocks: super_block.3,
+            data_area_blocks: super_block.4,
+            refcount_blocks: super_block.5,
+            inode_crc_blocks: super_block.6,
+            used_inodes: inodes.len() as u32,
+            used_data_blocks: self.used_data_blocks(),
+            inodes,
+        }
+    }
+    fn dump_inode(&self, inode_bit: u32) -> InodeDump {
+        let (block_id, offset) = self.get_disk_inode_pos(inode_bit);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(offset, |disk_inode: &DiskInode| {
+                let kind = if disk_inode.is_dir() {
+                    "dir"
+                } else if disk_inode.is_file() {
+                    "file"
+                } else {
+                    "symlink"
+                };
+                let blocks = disk_inode.iter_block_ids(&self.block_device).collect();
+                InodeDump {
+                    inode: inode_bit,
+                    kind,
+                    size: disk_inode.size,
+                    blocks,
+                }
+            })
+    }
+}
@@ -0,0 +1,60 @@
+//! Error type shared by the fallible operations of tiny-fs
+use core::fmt::{self, Display, Formatter};
+
+/// Errors reported by tiny-fs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The superblock magic number does not match
+    BadMagic,
+    /// A checksum does not match the stored data
+    BadChecksum,
+    /// The inode is not a directory
+    NotADirectory,
+    /// The inode is not a regular file
+    NotAFile,
+    /// The inode is a directory where a file is expected
+    IsADirectory,
+    /// The name already exists in the directory
+    AlreadyExists,
+    /// The name is longer than a directory entry can hold
+    NameTooLong,
+    /// No free inode or data block is left
+    NoSpace,
+    /// An offset or id is beyond the area it refers to
+    OutOfBounds,
+    /// The on-disk structures are inconsistent
+    Corrupt,
+    /// The data ended before the requested length was read
+    UnexpectedEof,
+    /// The file would grow past the largest size an inode can hold
+    FileTooLarge,
+    /// The name does not exist in the directory
+    NotFound,
+    /// The name is empty, `.` or `..`, or holds a `/` or NUL
+    InvalidName,
+    /// The format parameters do not give a usable layout
+    BadGeometry,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let msg = match self {
+            Error::BadMagic => "bad magic number",
+            Error::BadChecksum => "checksum mismatch",
+            Error::NotADirectory => "not a directory",
+            Error::NotAFile => "not a regular file",
+            Error::IsADirectory => "is a directory",
+            Error::AlreadyExists => "name already exists",
+            Error::NameTooLong => "name too long",
+            Error::NoSpace => "no space left on device",
+            Error::OutOfBounds => "out of bounds",
+            Error::Corrupt => "filesystem is corrupt",
+            Error::UnexpectedEof => "unexpected end of file",
+            Error::FileTooLarge => "file too large",
+            Error::NotFound => "no such name",
+            Error::InvalidName => "invalid name",
+            Error::BadGeometry => "unusable filesystem geometry",
+        };
+        f.write_str(msg)
+    }
+}
@@ -1,23 +1,59 @@
 //! SuperBlock/DiskInode/DirEntry
-use super::{BlockDevice, BLOCK_SZ, get_block_cache};
+use super::{BlockDevice, BLOCK_SZ, get_block_cache, get_block_cache_for_overwrite};
 
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use core::fmt::{Debug, Formatter, Result};
+use core::mem::{offset_of, size_of};
+use core::ops::Range;
 
 /* Some constants */
-// Magic number for sanity check
-const TFS_MAGIC: u32 = 0x3b800001;
+// Magic number for sanity check, bumped when the on-disk format changes:
+// 0x3b800001 images have 28 direct slots and no index field, 0x3b800002
+// images 27 direct slots and no owner, so `open` refuses them rather
+// than misread their indirect blocks
+const TFS_MAGIC: u32 = 0x3b800003;
 // Inode related block numbers
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 26;
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
-// The max length of dir_entry name
-const NAME_LENGTH_LIMIT: usize = 27;
+///The largest size a disk_inode can index
+pub const MAX_FILE_SZ: usize = INDIRECT2_BOUND * BLOCK_SZ;
+// Bytes of a directory stored inline in the `direct` slots
+const INLINE_DATA_SZ: usize = 3 * DIR_ENTRY_SZ;
+// DiskInode flags
+const INODE_FLAG_INLINE: u8 = 1;
+const INODE_FLAG_HASHED: u8 = 1 << 1;
+const INODE_FLAG_RESERVED: u8 = 1 << 2;
+const INODE_FLAG_TOMBSTONES: u8 = 1 << 3;
+// SuperBlock flags
+pub const SB_FLAG_CASE_INSENSITIVE: u32 = 1;
+pub const SB_FLAG_INODES_LAST: u32 = 1 << 1;
+// Permission bits kept in the stored mode
+const MODE_BITS: u16 = 0o7777;
+// The default dir_entry size, names are up to 27 bytes
 pub const DIR_ENTRY_SZ: usize = 32; // 27 + 1 + 4
+// dir_entry sizes that can be chosen at format time
+pub const DIR_ENTRY_SIZES: [usize; 3] = [DIR_ENTRY_SZ, 64, MAX_DIR_ENTRY_SZ];
+const MAX_DIR_ENTRY_SZ: usize = 128;
+// Inode number of a removed dir_entry, inode 0 is the root and `..` points at it
+const EMPTY_INODE: u32 = u32::MAX;
+pub const SYMLINK_LENGTH_LIMIT: usize = INLINE_DATA_SZ;
+
+///First block of each area of a filesystem, see `SuperBlock::area_starts`
+pub(crate) struct AreaStarts {
+    pub inode_bitmap: u32,
+    pub inode_area: u32,
+    pub data_bitmap: u32,
+    pub refcount: u32,
+    pub inode_crc: u32,
+    pub data_area: u32,
+    //one past the last block of the data area
+    pub data_area_end: u32,
+}
 
 /**
     [SuperBlock_Description]:
@@ -25,15 +61,37 @@ pub const DIR_ENTRY_SZ: usize = 32; // 27 + 1 + 4
     and the location of other contiguous areas can also be located.
 */
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
     magic: u32,
+    ///Blocks of the device the filesystem spans
     pub total_blocks: u32,
+    ///Blocks of the inode bitmap, see `area_starts` for where it is
     pub inode_bitmap_blocks: u32,
+    ///Blocks of the inode area
     pub inode_area_blocks: u32,
+    ///Blocks of the data bitmap
     pub data_bitmap_blocks: u32,
+    ///Blocks of the data area
     pub data_area_blocks: u32,
+    ///Blocks of refcounts, 0 unless created by create_dedup
+    pub refcount_blocks: u32,
+    ///Free inodes, kept up to date on every alloc/dealloc
+    pub free_inodes: u32,
+    ///Free data blocks, kept up to date on every alloc/dealloc
+    pub free_data_blocks: u32,
+    ///Bytes of a dir_entry, 0 in images made before it could be chosen,
+    ///meaning DIR_ENTRY_SZ; see `dir_entry_sz`
+    pub dir_entry_size: u32,
+    ///Blocks of inode block crcs, 0 unless created by create_checked
+    pub inode_crc_blocks: u32,
+    ///Format options, 0 in images made before there were any
+    pub flags: u32,
 }
 
+// Pin the on-disk layout, a change here breaks existing images
+const _: () = assert!(size_of::<SuperBlock>() == 12 * 4);
+
 impl Debug for SuperBlock {
     fn fmt(&self, f: &mut Formatter) -> Result {
         f.debug_struct("SuperBlock")
@@ -42,24 +100,80 @@ impl Debug for SuperBlock {
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("refcount_blocks", &self.refcount_blocks)
+            .field("free_inodes", &self.free_inodes)
+            .field("free_data_blocks", &self.free_data_blocks)
+            .field("dir_entry_size", &self.dir_entry_sz())
+            .field("inode_crc_blocks", &self.inode_crc_blocks)
+            .field("case_insensitive", &self.is_case_insensitive())
             .finish()
     }
 }
 
 impl SuperBlock {
-    pub fn initialize(
+    pub(crate) fn initialize(
         &mut self, total_blocks: u32,
         inode_bitmap_blocks: u32, inode_area_blocks: u32,
         data_bitmap_blocks: u32, data_area_blocks: u32,
+        refcount_blocks: u32,
     ) {
         *self = Self {
             magic: TFS_MAGIC,
             total_blocks,
             inode_bitmap_blocks, inode_area_blocks,
             data_bitmap_blocks, data_area_blocks,
+            refcount_blocks,
+            free_inodes: 0,
+            free_data_blocks: 0,
+            dir_entry_size: DIR_ENTRY_SZ as u32,
+            inode_crc_blocks: 0,
+            flags: 0,
+        }
+    }
+    ///Get the size of a dir_entry in bytes
+    pub fn dir_entry_sz(&self) -> usize {
+        match self.dir_entry_size {
+            0 => DIR_ENTRY_SZ,
+            size => size as usize,
+        }
+    }
+
+    ///Get the first block of each area from the area sizes
+    ///By default the superblock is followed by the inode bitmap, the inode
+    ///area, the data bitmap, the refcount and crc areas and the data area;
+    ///with SB_FLAG_INODES_LAST the two inode areas move to the end
+    pub(crate) fn area_starts(&self) -> AreaStarts {
+        let inode_total_blocks = self.inode_bitmap_blocks + self.inode_area_blocks;
+        let (inode_bitmap, data_bitmap, data_area_end) = if self.is_inodes_last() {
+            let inode_bitmap = self.total_blocks.saturating_sub(inode_total_blocks);
+            (inode_bitmap, 1, inode_bitmap)
+        } else {
+            (1, 1 + inode_total_blocks, self.total_blocks)
+        };
+        let refcount = data_bitmap + self.data_bitmap_blocks;
+        let inode_crc = refcount + self.refcount_blocks;
+        AreaStarts {
+            inode_bitmap,
+            inode_area: inode_bitmap + self.inode_bitmap_blocks,
+            data_bitmap,
+            refcount,
+            inode_crc,
+            data_area: inode_crc + self.inode_crc_blocks,
+            data_area_end,
         }
     }
 
+    ///Whether the inode bitmap and inode area are at the end of the device
+    pub fn is_inodes_last(&self) -> bool {
+        self.flags & SB_FLAG_INODES_LAST != 0
+    }
+
+    ///Whether names are matched ignoring ASCII case
+    pub fn is_case_insensitive(&self) -> bool {
+        self.flags & SB_FLAG_CASE_INSENSITIVE != 0
+    }
+
+    ///Whether the block holds a tiny-fs superblock at all
     pub fn is_valid(&self) -> bool {
         self.magic == TFS_MAGIC
     }
@@ -71,9 +185,21 @@ impl SuperBlock {
     It contains metadata about files/directories.
 */
 #[derive(PartialEq)]
+#[repr(u8)]
 pub enum DiskInodeType {
     File,
     Directory,
+    Symlink,
+}
+
+impl DiskInodeType {
+    fn default_mode(&self) -> u16 {
+        match self {
+            DiskInodeType::File => 0o644,
+            DiskInodeType::Directory => 0o755,
+            DiskInodeType::Symlink => 0o777,
+        }
+    }
 }
 
 type IndirectBlock = [u32; BLOCK_SZ / 4];
@@ -87,10 +213,33 @@ pub struct DiskInode {
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirecr2: u32,
+    //directory: inode holding the hash index
+    //file: offset of the logical start in the first data block
+    aux: u32,
     //disk_inode type
     type_: DiskInodeType,
+    //small directories keep their entries inline in `direct`
+    flags: u8,
+    //permission bits
+    mode: u16,
+    //owner user and group
+    uid: u16,
+    gid: u16,
 }
 
+// A DiskInode takes 128 bytes, 4 of them per block
+const _: () = assert!(size_of::<DiskInodeType>() == 1);
+const _: () = assert!(size_of::<DiskInode>() == 128);
+const _: () = assert!(BLOCK_SZ.is_multiple_of(size_of::<DiskInode>()));
+const _: () = assert!(offset_of!(DiskInode, indirect1) == 4 + INODE_DIRECT_COUNT * 4);
+const _: () = assert!(offset_of!(DiskInode, aux) == offset_of!(DiskInode, indirecr2) + 4);
+const _: () = assert!(offset_of!(DiskInode, type_) == offset_of!(DiskInode, aux) + 4);
+const _: () = assert!(offset_of!(DiskInode, type_) == 120);
+const _: () = assert!(offset_of!(DiskInode, mode) == 122);
+const _: () = assert!(offset_of!(DiskInode, gid) == 126);
+const _: () = assert!(INLINE_DATA_SZ <= INODE_DIRECT_COUNT * 4);
+const _: () = assert!(MAX_FILE_SZ <= u32::MAX as usize);
+
 /* Some core methods */
 impl DiskInode {
     pub fn initialize(&mut self, type_: DiskInodeType) {
@@ -98,8 +247,48 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirecr2 = 0;
+        self.aux = 0;
+        self.flags = match type_ {
+            DiskInodeType::Directory | DiskInodeType::Symlink => INODE_FLAG_INLINE,
+            DiskInodeType::File => 0,
+        };
+        self.mode = type_.default_mode();
+        self.uid = 0;
+        self.gid = 0;
         self.type_ = type_;
     }
+    ///Initialize a directory holding `.` and `..`, dir_entry_sz bytes each
+    ///new_blocks are the `dir_blocks_needed` blocks allocated beforehand,
+    ///none when both entries fit inline
+    pub fn initialize_dir(
+        &mut self,
+        self_inode: u32,
+        parent_inode: u32,
+        dir_entry_sz: usize,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>
+    ) {
+        assert_eq!(new_blocks.len() as u32, Self::dir_blocks_needed(dir_entry_sz));
+        self.initialize(DiskInodeType::Directory);
+        self.increase_size(2 * dir_entry_sz as u32, new_blocks, block_device);
+        self.write_at(0, DirEntry::new(".", self_inode, dir_entry_sz).as_bytes(), block_device);
+        self.write_at(dir_entry_sz, DirEntry::new("..", parent_inode, dir_entry_sz).as_bytes(), block_device);
+    }
+    ///Get the number of blocks `initialize_dir` needs for entries of dir_entry_sz
+    pub fn dir_blocks_needed(dir_entry_sz: usize) -> u32 {
+        let size = 2 * dir_entry_sz;
+        if size <= INLINE_DATA_SZ {
+            return 0;
+        }
+        Self::total_blocks(size as u32)
+    }
+    ///Initialize a symlink to target, which is kept inline
+    pub fn initialize_symlink(&mut self, target: &str) {
+        assert!(target.len() <= SYMLINK_LENGTH_LIMIT);
+        self.initialize(DiskInodeType::Symlink);
+        self.size = target.len() as u32;
+        self.inline_data_mut()[..target.len()].copy_from_slice(target.as_bytes());
+    }
     ///Increase the size of current disk_inode
     ///[direct] => [indirect1] => [indirect2]
     pub fn increase_size(
@@ -108,6 +297,25 @@ impl DiskInode {
         new_blocks: Vec<u32>,
         block_device: &Arc<dyn BlockDevice>
     ) {
+        debug_assert!(new_size >= self.size, "use decrease_size to shrink");
+        if self.is_inline() {
+            if new_size as usize <= INLINE_DATA_SZ {
+                self.size = new_size;
+                return;
+            }
+            //spill the inline data into the first data block
+            let size = self.size as usize;
+            let mut inline_data = [0u8; INLINE_DATA_SZ];
+            inline_data[..size].copy_from_slice(&self.inline_data()[..size]);
+            self.direct.iter_mut().for_each(|v| *v = 0);
+            self.flags &= !INODE_FLAG_INLINE;
+            self.size = 0;
+            self.increase_size(new_size, new_blocks, block_device);
+            if size > 0 {
+                self.write_at(0, &inline_data[..size], block_device);
+            }
+            return;
+        }
         let mut current_blocks = self.data_blocks();
         self.size = new_size;
         let mut target_blocks = self.data_blocks();
@@ -142,12 +350,12 @@ impl DiskInode {
                 self.indirecr2 = new_blocks_iter.next().unwrap();
             }
             current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            target_blocks -= INODE_INDIRECT2_COUNT as u32;
+            target_blocks -= INODE_INDIRECT1_COUNT as u32;
         } else {
             return;
         }
         let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT2_COUNT;
+        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
         let a1 = target_blocks as usize / INODE_INDIRECT1_COUNT;
         let b1 = target_blocks as usize % INODE_INDIRECT1_COUNT;
         get_block_cache(self.indirecr2 as usize, Arc::clone(block_device))
@@ -158,7 +366,7 @@ impl DiskInode {
                         indirect2_block[a0] = new_blocks_iter.next().unwrap();
                     }
                     //continue to fill indirect1_block
-                    get_block_cache(indirect2_block[0] as usize, Arc::clone(block_device))
+                    get_block_cache(indirect2_block[a0] as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1_block: &mut IndirectBlock| {
                             indirect1_block[b0] = new_blocks_iter.next().unwrap(); 
@@ -172,18 +380,85 @@ impl DiskInode {
                 }
             });
     }
+    ///Decrease the size of current disk_inode
+    ///Return blocks that should be deallocated
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size);
+        if self.is_inline() {
+            self.size = new_size;
+            return Vec::new();
+        }
+        let current_blocks = self.data_blocks() as usize;
+        let target_blocks = Self::_data_blocks(new_size) as usize;
+        //zero the tail of the last remaining block
+        let tail = new_size as usize % BLOCK_SZ;
+        let tail_block_id = match tail {
+            0 => 0,
+            _ => self.get_block_id(target_blocks as u32 - 1, block_device),
+        };
+        if tail_block_id != 0 {
+            get_block_cache(tail_block_id as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block[tail..].iter_mut().for_each(|p| *p = 0);
+                });
+        }
+        //data blocks beyond new_size, holes own no block
+        let mut v: Vec<u32> = (target_blocks..current_blocks)
+            .map(|inner_id| self.get_block_id(inner_id as u32, block_device))
+            .filter(|&block_id| block_id != 0)
+            .collect();
+        //indirect2 => indirect1_blocks
+        if current_blocks > INDIRECT1_BOUND {
+            let a0 = target_blocks.saturating_sub(INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
+            let a1 = (current_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
+            get_block_cache(self.indirecr2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2_block: &IndirectBlock| {
+                    v.extend_from_slice(&indirect2_block[a0..a1]);
+                });
+            if target_blocks <= INDIRECT1_BOUND {
+                v.push(self.indirecr2);
+                self.indirecr2 = 0;
+            }
+        }
+        //indirect1
+        if current_blocks > DIRECT_BOUND && target_blocks <= DIRECT_BOUND {
+            v.push(self.indirect1);
+            self.indirect1 = 0;
+        }
+        for block_id in self.direct.iter_mut().take(current_blocks).skip(target_blocks) {
+            *block_id = 0;
+        }
+        self.size = new_size;
+        v
+    }
     ///Clear size to zero and return blocks that should be deallocated
     ///We will clear the block contents to zero later
     pub fn  clear_size(&mut self, block_device: &Arc<dyn BlockDevice>)
         -> Vec<u32>
     {
+        if self.is_file() {
+            self.aux = 0;
+        }
+        if self.is_inline() {
+            self.size = 0;
+            self.direct.iter_mut().for_each(|v| *v = 0);
+            return Vec::new();
+        }
         let mut v: Vec<u32> = Vec::new();
         let mut current_blocks = self.data_blocks() as usize;
         let mut cleared_blocks = 0usize;
         self.size = 0;
         //direct => data_block
         while cleared_blocks < current_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[cleared_blocks]);
+            if self.direct[cleared_blocks] != 0 {
+                v.push(self.direct[cleared_blocks]);
+            }
             self.direct[cleared_blocks] = 0;
             cleared_blocks += 1;
         }
@@ -199,7 +474,9 @@ impl DiskInode {
             .lock()
             .modify(0, |indirect1_block: &mut IndirectBlock| {
                 while cleared_blocks < current_blocks.min(INODE_INDIRECT1_COUNT) {
-                    v.push(indirect1_block[cleared_blocks]);
+                    if indirect1_block[cleared_blocks] != 0 {
+                        v.push(indirect1_block[cleared_blocks]);
+                    }
                     //indirect1_block[cleared_blocks] = 0;
                     cleared_blocks += 1;
                 } 
@@ -223,9 +500,7 @@ impl DiskInode {
                     get_block_cache(*indirect1 as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1_block: &mut IndirectBlock| {
-                            for block_id in indirect1_block.iter() {
-                                v.push(*block_id);
-                            } 
+                            v.extend(indirect1_block.iter().filter(|&&block_id| block_id != 0));
                         });
                  }
                  if b0 > 0 {
@@ -233,15 +508,25 @@ impl DiskInode {
                     get_block_cache(indirect2_block[a0] as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1_block: &mut IndirectBlock| {
-                            for block_id in indirect1_block.iter().take(b0) {
-                                v.push(*block_id);
-                            } 
+                            v.extend(
+                                indirect1_block.iter().take(b0).filter(|&&block_id| block_id != 0)
+                            );
                         });
                  }
             });
         self.indirecr2 = 0;
         v
     }
+    ///Locate offset as (inner block id, offset within that block)
+    pub fn block_at_offset(&self, offset: usize) -> (u32, usize) {
+        ((offset / BLOCK_SZ) as u32, offset % BLOCK_SZ)
+    }
+    ///Range within the block holding offset that an access of at most
+    ///remaining bytes starting at offset covers
+    pub fn block_slice_bounds(offset: usize, remaining: usize) -> Range<usize> {
+        let start = offset % BLOCK_SZ;
+        start..start + (BLOCK_SZ - start).min(remaining)
+    }
     ///Read data from current disk_inode
     pub fn read_at(
         &self,
@@ -250,36 +535,33 @@ impl DiskInode {
         block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
+        let end = offset.saturating_add(buf.len()).min(self.size as usize);
         if start >= end {
             return 0;
         }
+        if self.is_inline() {
+            buf[..end - start].copy_from_slice(&self.inline_data()[start..end]);
+            return end - start;
+        }
         //read file from offset to end by per-block
         let mut read_size = 0usize;
-        let mut start_block = start / BLOCK_SZ;
-        loop {
-            //calculate end of current block
-            let mut current_block_end_size = (start / BLOCK_SZ + 1) * BLOCK_SZ;
-            current_block_end_size = current_block_end_size.min(end);
-            //read and update read_size
-            let current_block_read_size = current_block_end_size - start;
-            let dst = &mut buf[read_size..read_size + current_block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + current_block_read_size];
-                dst.copy_from_slice(src);
-            });
-            read_size += current_block_read_size;
-            //move to next block
-            if current_block_end_size == end {
-                break;
+        while start < end {
+            let (inner_id, _) = self.block_at_offset(start);
+            let bounds = Self::block_slice_bounds(start, end - start);
+            let dst = &mut buf[read_size..read_size + bounds.len()];
+            let block_id = self.get_block_id(inner_id, block_device);
+            if block_id == 0 {
+                //a hole reads as zeros
+                dst.fill(0);
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        dst.copy_from_slice(&data_block[bounds.clone()]);
+                    });
             }
-            start_block += 1;
-            start = current_block_end_size;
+            read_size += bounds.len();
+            start += bounds.len();
         }
         read_size
     }
@@ -294,32 +576,34 @@ impl DiskInode {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         assert!(start <= end);
+        if start == end {
+            return 0;
+        }
+        if self.is_inline() {
+            self.inline_data_mut()[start..end].copy_from_slice(&buf[..end - start]);
+            return end - start;
+        }
         //write data into file from offset to end by per-block
-        let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
-        loop {
-            //calculate end of current block
-            let mut current_block_end_size = (start / BLOCK_SZ + 1) * BLOCK_SZ;
-            current_block_end_size = current_block_end_size.min(end);
-            //write and update write_size
-            let current_block_write_size = current_block_end_size - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                let src = &buf[write_size..write_size + current_block_write_size];
-                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + current_block_write_size];
-                dst.copy_from_slice(src); 
-            });
-            write_size += current_block_write_size;
-            //move to next block
-            if current_block_end_size == end {
-                break;
-            }
-            start_block += 1;
-            start = current_block_end_size;
+        while start < end {
+            let (inner_id, _) = self.block_at_offset(start);
+            let bounds = Self::block_slice_bounds(start, end - start);
+            let src = &buf[write_size..write_size + bounds.len()];
+            let block_id = self.get_block_id(inner_id, block_device);
+            assert_ne!(block_id, 0, "holes must be filled before write_at");
+            //a block written whole need not be read first
+            let block_cache = if bounds.len() == BLOCK_SZ {
+                get_block_cache_for_overwrite(block_id as usize, Arc::clone(block_device))
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+            };
+            block_cache
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block[bounds.clone()].copy_from_slice(src);
+                });
+            write_size += bounds.len();
+            start += bounds.len();
         }
         write_size
     }
@@ -333,6 +617,159 @@ impl DiskInode {
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    pub fn is_symlink(&self) -> bool {
+        self.type_ == DiskInodeType::Symlink
+    }
+    ///Whether the data is kept inline in `direct` instead of data blocks
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE != 0
+    }
+    ///Get the inode bit of the hash index if the directory has one
+    pub fn hash_index(&self) -> Option<u32> {
+        if self.flags & INODE_FLAG_HASHED != 0 {
+            Some(self.aux)
+        } else {
+            None
+        }
+    }
+    ///Set or remove the hash index of a directory
+    pub fn set_hash_index(&mut self, index_inode: Option<u32>) {
+        match index_inode {
+            Some(index_inode) => {
+                self.aux = index_inode;
+                self.flags |= INODE_FLAG_HASHED;
+            }
+            None => {
+                self.aux = 0;
+                self.flags &= !INODE_FLAG_HASHED;
+            }
+        }
+    }
+    ///Whether a directory may end with empty dir_entries reserved for new ones
+    pub fn is_reserved(&self) -> bool {
+        self.flags & INODE_FLAG_RESERVED != 0
+    }
+    ///Mark whether a directory ends with reserved dir_entries
+    pub fn set_reserved(&mut self, reserved: bool) {
+        if reserved {
+            self.flags |= INODE_FLAG_RESERVED;
+        } else {
+            self.flags &= !INODE_FLAG_RESERVED;
+        }
+    }
+    ///Whether a directory may hold removed dir_entries to be reused
+    pub fn has_tombstones(&self) -> bool {
+        self.flags & INODE_FLAG_TOMBSTONES != 0
+    }
+    ///Mark whether a directory holds removed dir_entries
+    pub fn set_tombstones(&mut self, tombstones: bool) {
+        if tombstones {
+            self.flags |= INODE_FLAG_TOMBSTONES;
+        } else {
+            self.flags &= !INODE_FLAG_TOMBSTONES;
+        }
+    }
+    ///Get the number of dir_entries of a directory in use,
+    ///the reserved ones at the end excluded
+    pub fn entry_count(&self, dir_entry_sz: usize, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let slots = self.size as usize / dir_entry_sz;
+        if !self.is_reserved() {
+            return slots;
+        }
+        //entries in use come first, look for the first empty one
+        let mut dir_entry = DirEntry::empty(dir_entry_sz);
+        let (mut low, mut high) = (0, slots);
+        while low < high {
+            let mid = (low + high) / 2;
+            self.read_at(mid * dir_entry_sz, dir_entry.as_bytes_mut(), block_device);
+            if dir_entry.name().is_empty() {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        low
+    }
+    ///Get the permission bits
+    pub fn mode(&self) -> u16 {
+        self.mode & MODE_BITS
+    }
+    ///Set the permission bits, only the low 12 bits are kept
+    pub fn set_mode(&mut self, mode: u16) {
+        self.mode = mode & MODE_BITS;
+    }
+    ///Get the owner user and group
+    pub fn owner(&self) -> (u16, u16) {
+        (self.uid, self.gid)
+    }
+    ///Set the owner user and group
+    pub fn set_owner(&mut self, uid: u16, gid: u16) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+    ///Cheap sanity check of the raw bytes of a disk_inode read from a
+    ///possibly corrupt image, before they are used as a DiskInode
+    ///The type must be known, the size must fit where the data lives,
+    ///and the index blocks that size needs must be present
+    pub fn validate(raw: &[u8; size_of::<DiskInode>()], dir_entry_sz: usize) -> bool {
+        //a bad type byte is no DiskInodeType, so it is checked first
+        if raw[offset_of!(DiskInode, type_)] > DiskInodeType::Symlink as u8 {
+            return false;
+        }
+        //every other field is valid for any bits
+        let disk_inode = unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const DiskInode) };
+        if disk_inode.size as usize > MAX_FILE_SZ {
+            return false;
+        }
+        if disk_inode.is_dir() && !(disk_inode.size as usize).is_multiple_of(dir_entry_sz) {
+            return false;
+        }
+        if disk_inode.head_offset() as usize >= BLOCK_SZ {
+            return false;
+        }
+        if disk_inode.is_inline() {
+            return disk_inode.size as usize <= INLINE_DATA_SZ;
+        }
+        let data_blocks = disk_inode.data_blocks() as usize;
+        (data_blocks <= DIRECT_BOUND || disk_inode.indirect1 != 0)
+            && (data_blocks <= INDIRECT1_BOUND || disk_inode.indirecr2 != 0)
+    }
+    ///Whether the size, block pointers and aux equal those in raw, the
+    ///bytes of a disk_inode as stored; type, flags, mode and owner are
+    ///not compared
+    pub fn data_fields_eq(&self, raw: &[u8; size_of::<DiskInode>()]) -> bool {
+        //size up to aux lead the disk_inode with no gap, see the asserts
+        let data_fields = offset_of!(DiskInode, size)..offset_of!(DiskInode, type_);
+        //a DiskInode has no padding, so all of its bytes are initialized
+        let bytes = unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<DiskInode>())
+        };
+        bytes[data_fields.clone()] == raw[data_fields]
+    }
+    ///Get the offset where the content of a file starts
+    ///Bytes before it were discarded, it is always less than BLOCK_SZ
+    pub fn head_offset(&self) -> u32 {
+        if self.is_file() {
+            self.aux
+        } else {
+            0
+        }
+    }
+    ///Set the offset where the content of a file starts
+    pub fn set_head_offset(&mut self, head_offset: u32) {
+        assert!(self.is_file() && (head_offset as usize) < BLOCK_SZ);
+        self.aux = head_offset;
+    }
+    fn inline_data(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self.direct.as_ptr() as *const u8, INLINE_DATA_SZ)
+        }
+    }
+    fn inline_data_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.direct.as_mut_ptr() as *mut u8, INLINE_DATA_SZ)
+        }
+    }
     /// Get real global_id on block device by inner DiskInode_id
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
         let inner_id = inner_id as usize;
@@ -347,6 +784,7 @@ impl DiskInode {
                 indirect_block[inner_id - INODE_DIRECT_COUNT]
             })
         } else {
+            assert!(inner_id < INDIRECT2_BOUND);
             // this is inner_id for indirect2
             let indirect2_inner_id = inner_id - INDIRECT1_BOUND;
             // find the first-level index block in which the block_id is located
@@ -367,6 +805,92 @@ impl DiskInode {
             })
         }
     }
+    ///Iterate over the global ids of the data blocks in file order, 0 for a hole
+    ///Each index block is read once and copied, so no block cache lock
+    ///is held between two items
+    pub fn iter_block_ids<'a>(
+        &'a self,
+        block_device: &'a Arc<dyn BlockDevice>,
+    ) -> impl Iterator<Item = u32> + 'a {
+        let data_blocks = if self.is_inline() { 0 } else { self.data_blocks() as usize };
+        let read_index = move |block_id: u32| {
+            get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |index_block: &IndirectBlock| *index_block)
+        };
+        let direct = self.direct[..data_blocks.min(DIRECT_BOUND)].iter().copied();
+        let indirect1_count = data_blocks.clamp(DIRECT_BOUND, INDIRECT1_BOUND) - DIRECT_BOUND;
+        //at most one item, so the indirect1 block is only read when used
+        let indirect1 = (0..indirect1_count)
+            .step_by(INODE_INDIRECT1_COUNT)
+            .flat_map(move |_| read_index(self.indirect1).into_iter().take(indirect1_count));
+        let indirect2_count = data_blocks.saturating_sub(INDIRECT1_BOUND);
+        let indirect2 = (0..indirect2_count)
+            .step_by(INODE_INDIRECT2_COUNT)
+            .flat_map(move |_| {
+                read_index(self.indirecr2)
+                    .into_iter()
+                    .take(indirect2_count.div_ceil(INODE_INDIRECT1_COUNT))
+                    .enumerate()
+                    .flat_map(move |(a, indirect1)| {
+                        read_index(indirect1)
+                            .into_iter()
+                            .take(indirect2_count - a * INODE_INDIRECT1_COUNT)
+                    })
+            });
+        direct.chain(indirect1).chain(indirect2)
+    }
+    ///Get the global ids of the index blocks, indirect2 before the
+    ///indirect1 blocks it points at
+    pub fn index_block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let data_blocks = if self.is_inline() { 0 } else { self.data_blocks() as usize };
+        let mut v: Vec<u32> = Vec::new();
+        if data_blocks > DIRECT_BOUND {
+            v.push(self.indirect1);
+        }
+        if data_blocks > INDIRECT1_BOUND {
+            v.push(self.indirecr2);
+            let count = (data_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
+            get_block_cache(self.indirecr2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2_block: &IndirectBlock| {
+                    v.extend_from_slice(&indirect2_block[..count]);
+                });
+        }
+        v
+    }
+    ///Point the inner_id-th data block at block_id, which must be allocated already
+    pub fn set_block_id(&mut self, inner_id: u32, block_id: u32, block_device: &Arc<dyn BlockDevice>) {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = block_id;
+        } else if inner_id < INDIRECT1_BOUND {
+            get_block_cache(
+                self.indirect1 as usize,
+                Arc::clone(block_device)
+            )
+            .lock().modify(0, |indirect_block: &mut IndirectBlock| {
+                indirect_block[inner_id - INODE_DIRECT_COUNT] = block_id;
+            });
+        } else {
+            assert!(inner_id < INDIRECT2_BOUND);
+            let indirect2_inner_id = inner_id - INDIRECT1_BOUND;
+            let indirect1 = get_block_cache(
+                self.indirecr2 as usize,
+                Arc::clone(block_device)
+            )
+            .lock().read(0, |indirect2_block: &IndirectBlock| {
+                indirect2_block[indirect2_inner_id / INODE_INDIRECT1_COUNT]
+            });
+            get_block_cache(
+                indirect1 as usize,
+                Arc::clone(block_device)
+            )
+            .lock().modify(0, |indirect1_block: &mut IndirectBlock| {
+                indirect1_block[indirect2_inner_id % INODE_INDIRECT1_COUNT] = block_id;
+            });
+        }
+    }
     /*
         The following methods is used to determine how many additional blocks
         are needed when capacity is expanded.
@@ -378,14 +902,14 @@ impl DiskInode {
                                 +-> [DiskInode::increase_size] 
     */
     fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+        size.div_ceil(BLOCK_SZ as u32)
     }
     pub fn data_blocks(&self) -> u32 {
         Self::_data_blocks(self.size)
     }
     pub fn total_blocks(size: u32) -> u32 {
         let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
+        let mut total = data_blocks;
         //indirect1
         if data_blocks > INODE_DIRECT_COUNT {
             total += 1;
@@ -393,13 +917,29 @@ impl DiskInode {
         //indirect2
         if data_blocks > INDIRECT1_BOUND {
             total += 1;
-            total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            total += (data_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
         }
         total as u32
     }
+    ///Blocks (data and index) covered by the size of this disk_inode,
+    ///holes included
+    pub fn occupied_blocks(&self) -> u32 {
+        if self.is_inline() {
+            0
+        } else {
+            Self::total_blocks(self.size)
+        }
+    }
+    ///Count the data blocks of this disk_inode that are holes
+    pub fn holes(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        self.iter_block_ids(block_device).filter(|&block_id| block_id == 0).count() as u32
+    }
     pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
         assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+        if self.is_inline() && new_size as usize <= INLINE_DATA_SZ {
+            return 0;
+        }
+        Self::total_blocks(new_size) - self.occupied_blocks()
     }
 }
 
@@ -407,48 +947,179 @@ impl DiskInode {
     [DirEntry_Description]:
     The contents of directories need to follow a special format. In our implementation,
     it can be viewed as a sequence of directory entries, each of which is a tuple.
+    An entry of `size` bytes holds the '\0' terminated name in its first size - 4 bytes
+    and the inode number in the last 4, the size is chosen at format time.
 */
-#[repr(C)]
+#[derive(Clone)]
 pub struct DirEntry {
-    name: [u8; NAME_LENGTH_LIMIT + 1], // '\0'
-    inode_number: u32,
+    bytes: [u8; MAX_DIR_ENTRY_SZ],
+    size: usize,
 }
 
 impl DirEntry {
-    pub fn empty() -> Self {
+    ///An empty dir_entry of size bytes, to be filled by `as_bytes_mut`
+    pub fn empty(size: usize) -> Self {
         Self {
-            name: [0u8; NAME_LENGTH_LIMIT + 1],
-            inode_number: 0,
+            bytes: [0u8; MAX_DIR_ENTRY_SZ],
+            size,
         }
     }
 
-    pub fn new(name: &str, inode_number: u32) -> Self {
-        let mut name_bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        name_bytes[..name.len()].copy_from_slice(name.as_bytes());
-        Self {
-            name: name_bytes,
-            inode_number,
-        }
+    ///A dir_entry of size bytes pointing name at inode_number
+    pub fn new(name: &str, inode_number: u32, size: usize) -> Self {
+        let mut dir_entry = Self::empty(size);
+        dir_entry.bytes[..name.len()].copy_from_slice(name.as_bytes());
+        dir_entry.bytes[size - 4..size].copy_from_slice(&inode_number.to_ne_bytes());
+        dir_entry
+    }
+
+    ///The max length of a name in a dir_entry of size bytes
+    pub const fn name_limit(size: usize) -> usize {
+        size - 5
     }
 
+    ///Get the name of the entry
     pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+        let len = (0usize..).find(|i| self.bytes[*i] == 0).unwrap();
+        core::str::from_utf8(&self.bytes[..len]).unwrap()
     }
 
+    ///Get the inode number the entry points at
     pub fn inode_number(&self) -> u32 {
-        self.inode_number
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&self.bytes[self.size - 4..self.size]);
+        u32::from_ne_bytes(bytes)
+    }
+
+    ///Whether the entry names no inode: reserved at the end of a
+    ///directory, or removed and left in place as a tombstone
+    pub fn is_empty(&self) -> bool {
+        self.bytes[0] == 0 || self.inode_number() == EMPTY_INODE
+    }
+
+    ///Turn the entry into a tombstone, it keeps its name but no
+    ///lookup matches it and `create` may reuse its slot
+    pub fn set_empty(&mut self) {
+        self.bytes[self.size - 4..self.size].copy_from_slice(&EMPTY_INODE.to_ne_bytes());
     }
 
     /** Serialize `DirEntry(self)` into bytes/mutable bytes  */
     pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIR_ENTRY_SZ)
-        }
+        &self.bytes[..self.size]
     }
+    ///The on-disk bytes of the entry, for reading it in place
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIR_ENTRY_SZ)
-        }
+        &mut self.bytes[..self.size]
+    }
+}
+
+// The default dir_entry is a 27 byte name, its '\0' and the inode number
+const _: () = assert!(DirEntry::name_limit(DIR_ENTRY_SZ) == 27);
+const _: () = assert!(DIR_ENTRY_SIZES[DIR_ENTRY_SIZES.len() - 1] == MAX_DIR_ENTRY_SZ);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::BlockCache;
+    use crate::SliceBlockDevice;
+    use alloc::{boxed::Box, vec};
+
+    #[test]
+    fn block_offset_helpers() {
+        let buf = Box::leak(vec![0u8; BLOCK_SZ].into_boxed_slice());
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        get_block_cache(0, Arc::clone(&block_device))
+            .lock()
+            .read(0, |disk_inode: &DiskInode| {
+                assert_eq!(disk_inode.block_at_offset(0), (0, 0));
+                assert_eq!(disk_inode.block_at_offset(BLOCK_SZ - 1), (0, BLOCK_SZ - 1));
+                assert_eq!(disk_inode.block_at_offset(BLOCK_SZ), (1, 0));
+                assert_eq!(disk_inode.block_at_offset(3 * BLOCK_SZ + 7), (3, 7));
+            });
+        assert_eq!(DiskInode::block_slice_bounds(0, 10), 0..10);
+        assert_eq!(DiskInode::block_slice_bounds(0, BLOCK_SZ + 1), 0..BLOCK_SZ);
+        assert_eq!(DiskInode::block_slice_bounds(BLOCK_SZ + 5, 10), 5..15);
+        //stops at the end of the block
+        assert_eq!(DiskInode::block_slice_bounds(2 * BLOCK_SZ - 3, 10), BLOCK_SZ - 3..BLOCK_SZ);
+        assert_eq!(DiskInode::block_slice_bounds(BLOCK_SZ - 3, 3), BLOCK_SZ - 3..BLOCK_SZ);
+        assert!(DiskInode::block_slice_bounds(7, 0).is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn iter_block_ids_matches_get_block_id() {
+        let buf = Box::leak(vec![0u8; 512 * BLOCK_SZ].into_boxed_slice());
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        //reach into the indirect2 area, block 0 holds the disk_inode
+        let size = (INDIRECT1_BOUND + 200) as u32 * BLOCK_SZ as u32 - 7;
+        let blocks: Vec<u32> = (1..=DiskInode::total_blocks(size)).collect();
+        get_block_cache(0, Arc::clone(&block_device))
+            .lock()
+            .modify(0, |disk_inode: &mut DiskInode| {
+                disk_inode.initialize(DiskInodeType::File);
+                disk_inode.increase_size(size, blocks, &block_device);
+            });
+        let (ids, expected, data_blocks) = get_block_cache(0, Arc::clone(&block_device))
+            .lock()
+            .read(0, |disk_inode: &DiskInode| {
+                let ids: Vec<u32> = disk_inode.iter_block_ids(&block_device).collect();
+                let expected: Vec<u32> = (0..disk_inode.data_blocks())
+                    .map(|inner_id| disk_inode.get_block_id(inner_id, &block_device))
+                    .collect();
+                (ids, expected, disk_inode.data_blocks())
+            });
+        assert_eq!(ids.len(), data_blocks as usize);
+        assert_eq!(ids, expected);
+    }
+    #[test]
+    fn validate_catches_corruption() {
+        let buf = Box::leak(vec![0u8; 2 * BLOCK_SZ].into_boxed_slice());
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        let disk_inode = get_block_cache(0, Arc::clone(&block_device));
+        let mut disk_inode = disk_inode.lock();
+        let validate = |disk_inode: &BlockCache, dir_entry_sz| {
+            disk_inode.read(0, |raw: &[u8; size_of::<DiskInode>()]| DiskInode::validate(raw, dir_entry_sz))
+        };
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| {
+            disk_inode.initialize(DiskInodeType::File);
+            disk_inode.increase_size(BLOCK_SZ as u32, vec![1], &block_device);
+        });
+        assert!(validate(&disk_inode, DIR_ENTRY_SZ));
+        //a size past the direct blocks with no indirect1 block
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| {
+            disk_inode.size = (DIRECT_BOUND as u32 + 1) * BLOCK_SZ as u32;
+        });
+        assert!(!validate(&disk_inode, DIR_ENTRY_SZ));
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| {
+            disk_inode.initialize(DiskInodeType::Directory);
+            disk_inode.size = DIR_ENTRY_SZ as u32 + 1;
+        });
+        assert!(!validate(&disk_inode, DIR_ENTRY_SZ));
+        //a whole number of dir_entries depends on their size
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| disk_inode.size = 3 * 32);
+        assert!(validate(&disk_inode, 32));
+        assert!(!validate(&disk_inode, 64));
+        //an unknown type, never looked at as a DiskInode
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| disk_inode.size = 0);
+        assert!(validate(&disk_inode, DIR_ENTRY_SZ));
+        disk_inode.modify(offset_of!(DiskInode, type_), |type_byte: &mut u8| *type_byte = 7);
+        assert!(!validate(&disk_inode, DIR_ENTRY_SZ));
+    }
+    #[test]
+    fn data_fields_eq_skips_mode() {
+        let buf = Box::leak(vec![0u8; BLOCK_SZ].into_boxed_slice());
+        let block_device: Arc<dyn BlockDevice> = Arc::new(SliceBlockDevice::new(buf));
+        let disk_inode = get_block_cache(0, Arc::clone(&block_device));
+        let mut disk_inode = disk_inode.lock();
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| disk_inode.initialize(DiskInodeType::File));
+        let raw = disk_inode.read(0, |raw: &[u8; size_of::<DiskInode>()]| *raw);
+        let eq = |disk_inode: &BlockCache| {
+            disk_inode.read(0, |disk_inode: &DiskInode| disk_inode.data_fields_eq(&raw))
+        };
+        assert!(eq(&disk_inode));
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| disk_inode.set_mode(0o600));
+        assert!(eq(&disk_inode));
+        //aux is the last field compared
+        disk_inode.modify(0, |disk_inode: &mut DiskInode| disk_inode.set_head_offset(1));
+        assert!(!eq(&disk_inode));
+    }
+}
@@ -5,17 +5,36 @@
 mod bitmap;
 mod block_cache;
 mod block_dev;
+mod dump;
+mod error;
 mod tfs;
 mod layout;
+mod policy;
+mod range_lock;
+mod repair;
+mod sha256;
 mod vfs;
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
-pub use block_dev::BlockDevice;
-pub use tfs::TinyFileSystem;
-pub use vfs::Inode;
-use block_cache::{get_block_cache, block_cache_sync_all};
+pub use block_cache::{cached_generation, clear_block_cache, clear_io_logger, set_io_logger, IoEvent};
+pub use block_cache::{flush_dirty_blocks, needs_flush, set_background_flush, set_writeback_batch};
+pub use block_dev::{BlockDevice, SliceBlockDevice, SubBlockDevice};
+#[cfg(feature = "test-util")]
+pub use block_dev::{FaultyBlockDevice, FAULT_MARKER};
+pub use dump::{ImageDump, InodeDump};
+pub use error::Error;
+pub use layout::{DirEntry, SuperBlock};
+pub use policy::{AllocPolicy, Contiguous, FirstFit};
+pub use repair::RepairReport;
+pub use tfs::{CreateConfig, Geometry, Layout, TinyFileSystem};
+pub use vfs::{Inode, Cursor, DirEntryIter};
+use block_cache::{get_block_cache, block_cache_sync_all, block_cache_sync_blocks, block_cache_drop_device};
+use block_cache::{background_flush, get_block_cache_for_overwrite};
 use bitmap::Bitmap;
+use tfs::InodeCrcArea;
 use layout::*;
 /// A block size of 512-bytes
 pub const BLOCK_SZ: usize = 512;
\ No newline at end of file
@@ -0,0 +1,65 @@
+//! Placement of new data blocks in the data bitmap
+use super::{BlockDevice, Bitmap};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Chooses the data bitmap bits handed out by `TinyFileSystem::alloc_n`
+pub trait AllocPolicy: Send {
+    /// Allocate count bits below limit in bitmap and return them in the
+    /// order the data blocks are used. On None bitmap must be left as it was.
+    fn alloc(
+        &mut self,
+        bitmap: &Bitmap,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+        limit: usize,
+    ) -> Option<Vec<usize>>;
+}
+
+/// Take the lowest free bits, packing data at the front of the device
+pub struct FirstFit;
+
+impl AllocPolicy for FirstFit {
+    fn alloc(
+        &mut self,
+        bitmap: &Bitmap,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+        limit: usize,
+    ) -> Option<Vec<usize>> {
+        let mut bits: Vec<usize> = Vec::with_capacity(count);
+        while bits.len() < count {
+            match bitmap.alloc(block_device) {
+                Some(bit) if bit < limit => bits.push(bit),
+                bit => {
+                    //give back what has been allocated for this call
+                    for bit in bit.into_iter().chain(bits) {
+                        let _ = bitmap.dealloc(block_device, bit);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(bits)
+    }
+}
+
+/// Take the first run of count free bits so a sequential write gets
+/// consecutive blocks, falling back to first fit when there is none
+pub struct Contiguous;
+
+impl AllocPolicy for Contiguous {
+    fn alloc(
+        &mut self,
+        bitmap: &Bitmap,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+        limit: usize,
+    ) -> Option<Vec<usize>> {
+        match bitmap.alloc_contiguous(block_device, count, limit) {
+            Some(start) => Some((start..start + count).collect()),
+            None => FirstFit.alloc(bitmap, block_device, count, limit),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Locks on ranges of the data blocks of an inode
+use alloc::vec::Vec;
+use core::ops::Range;
+use lazy_static::*;
+use spin::Mutex;
+
+/// (filesystem id, block_id, offset) of a disk_inode
+pub type InodeKey = (usize, usize, usize);
+
+lazy_static! {
+    /// Ranges currently held, a short list since holders do not nest
+    static ref RANGE_LOCKS: Mutex<Vec<(InodeKey, Range<usize>)>> = Mutex::new(Vec::new());
+}
+
+/// A held range of inner block ids of one inode, released on drop
+pub struct RangeGuard {
+    key: InodeKey,
+    blocks: Range<usize>,
+}
+
+/// Wait until no other holder overlaps blocks of the inode, then hold them
+///
+/// An empty range never waits.
+pub fn lock_range(key: InodeKey, blocks: Range<usize>) -> RangeGuard {
+    if blocks.is_empty() {
+        //never recorded, so the drop finds nothing to release
+        return RangeGuard { key, blocks };
+    }
+    loop {
+        let mut locks = RANGE_LOCKS.lock();
+        let busy = locks.iter().any(|(held_key, held)| {
+            *held_key == key && held.start < blocks.end && blocks.start < held.end
+        });
+        if !busy {
+            locks.push((key, blocks.clone()));
+            return RangeGuard { key, blocks };
+        }
+        drop(locks);
+        core::hint::spin_loop();
+    }
+}
+
+impl Drop for RangeGuard {
+    fn drop(&mut self) {
+        let mut locks = RANGE_LOCKS.lock();
+        if let Some(i) = locks
+            .iter()
+            .position(|(key, blocks)| *key == self.key && *blocks == self.blocks)
+        {
+            locks.swap_remove(i);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! TinyFileSystem::repair, reconciling the bitmaps with the inode tree
+use super::{
+    get_block_cache,
+    DiskInode, DirEntry,
+    TinyFileSystem,
+};
+
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// What `TinyFileSystem::repair` found, and freed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Allocated inodes no directory leads to, now freed
+    pub freed_inodes: u32,
+    /// Allocated data blocks no inode uses, now freed
+    pub freed_data_blocks: u32,
+    /// Inodes reached from the root that are not allocated, left as is
+    pub unallocated_inodes: Vec<u32>,
+    /// Global ids of blocks used by an inode that are not allocated, left as is
+    pub unallocated_blocks: Vec<u32>,
+}
+
+impl TinyFileSystem {
+    ///Free the inodes and data blocks that are allocated but cannot be
+    ///reached from the root inode, and report the ones in use but not
+    ///allocated without fixing them
+    ///Meant for an image nothing else is using, e.g. after a crash
+    pub fn repair(&mut self) -> RepairReport {
+        let allocated_inodes: BTreeSet<u32> = self.inode_bitmap
+            .iter_allocated(&self.block_device)
+            .map(|inode_bit| inode_bit as u32)
+            .collect();
+        let (inodes, blocks) = self.reachable(&allocated_inodes);
+        let allocated_blocks: BTreeSet<u32> = self.data_bitmap
+            .iter_allocated(&self.block_device)
+            .map(|data_bit| self.get_data_block_id(data_bit as u32))
+            .collect();
+        let mut report = RepairReport {
+            unallocated_inodes: inodes.difference(&allocated_inodes).copied().collect(),
+            unallocated_blocks: blocks.difference(&allocated_blocks).copied().collect(),
+            ..RepairReport::default()
+        };
+        for &inode_bit in allocated_inodes.difference(&inodes) {
+            self.dealloc_inode(inode_bit);
+            report.freed_inodes += 1;
+        }
+        for &block_id in allocated_blocks.difference(&blocks) {
+            //drop every reference a stale refcount still claims
+            while self.is_shared(block_id) {
+                self.dealloc_data(block_id);
+            }
+            self.forget_block(block_id);
+            self.dealloc_data(block_id);
+            report.freed_data_blocks += 1;
+        }
+        self.verify_free_counts();
+        report
+    }
+    ///Walk the tree from the root inode and collect the inode bits and
+    ///the data and index blocks it uses, hash indexes included
+    ///An unallocated inode is collected but not read, it may hold garbage
+    fn reachable(&self, allocated_inodes: &BTreeSet<u32>) -> (BTreeSet<u32>, BTreeSet<u32>) {
+        let mut inodes: BTreeSet<u32> = BTreeSet::new();
+        let mut blocks: BTreeSet<u32> = BTreeSet::new();
+        let mut pending: Vec<u32> = vec![0];
+        let dir_entry_sz = self.dir_entry_size();
+        while let Some(inode_bit) = pending.pop() {
+            //a corrupt tree may reach an inode twice
+            if !inodes.insert(inode_bit) || !allocated_inodes.contains(&inode_bit) {
+                continue;
+            }
+            let (block_id, offset) = self.get_disk_inode_pos(inode_bit);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(offset, |disk_inode: &DiskInode| {
+                    blocks.extend(
+                        disk_inode.iter_block_ids(&self.block_device).filter(|&block_id| block_id != 0)
+                    );
+                    blocks.extend(disk_inode.index_block_ids(&self.block_device));
+                    if !disk_inode.is_dir() {
+                        return;
+                    }
+                    pending.extend(disk_inode.hash_index());
+                    let mut dir_entry = DirEntry::empty(dir_entry_sz);
+                    for i in 0..disk_inode.entry_count(dir_entry_sz, &self.block_device) {
+                        disk_inode.read_at(i * dir_entry_sz, dir_entry.as_bytes_mut(), &self.block_device);
+                        if !dir_entry.is_empty() && dir_entry.name() != "." && dir_entry.name() != ".." {
+                            pending.push(dir_entry.inode_number());
+                        }
+                    }
+                });
+        }
+        (inodes, blocks)
+    }
+}
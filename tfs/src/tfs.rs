@@ -3,14 +3,21 @@
     and calls methods to adjust the filesystem layout.
 */
 use super::{
-    block_cache_sync_all, get_block_cache,
-    SuperBlock, Bitmap, DiskInode, DiskInodeType,
+    block_cache_sync_all, block_cache_drop_device, get_block_cache, get_block_cache_for_overwrite,
+    SuperBlock, Bitmap, DiskInode, DiskInodeType, DirEntry,
     Inode,
+    AllocPolicy, FirstFit,
     BlockDevice,
-    BLOCK_SZ,
+    Error,
+    BLOCK_SZ, DIR_ENTRY_SZ, DIR_ENTRY_SIZES, SB_FLAG_CASE_INSENSITIVE, SB_FLAG_INODES_LAST,
 };
 
-use alloc::sync::Arc;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Formatter};
 use spin::Mutex;
 
 type DataBlock = [u8; BLOCK_SZ];
@@ -24,6 +31,104 @@ pub struct TinyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    data_area_end_block: u32,
+    total_blocks: u32,
+    //inodes the inode area has room for, may be less than inode_bitmap.maxium()
+    inode_capacity: u32,
+    //extra references of each data block, only if created by create_dedup
+    refcount_start_block: u32,
+    refcount_blocks: u32,
+    //content hash <-> data block, rebuilt as blocks are written
+    dedup_blocks: BTreeMap<u64, u32>,
+    dedup_hashes: BTreeMap<u32, u64>,
+    //bytes of a dir_entry, chosen at format time
+    dir_entry_sz: usize,
+    //names match ignoring ASCII case, chosen at format time
+    case_insensitive: bool,
+    //crcs of the inode blocks, only if created by create_checked
+    inode_crc_blocks: u32,
+    //where they are kept, None if the filesystem is not checked
+    inode_crc_area: Option<InodeCrcArea>,
+    //inode blocks found to match their crc since open
+    verified_inode_blocks: BTreeSet<u32>,
+    //where new data blocks go, first fit unless set_alloc_policy
+    alloc_policy: Box<dyn AllocPolicy>,
+    //handed out by mounted_root, weak as the root holds the fs in turn
+    mounted_root: Weak<Inode>,
+}
+
+impl Debug for TinyFileSystem {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("TinyFileSystem")
+            .field("total_blocks", &self.total_blocks)
+            .field("inode_bitmap", &self.inode_bitmap.span())
+            .field("inode_area_start_block", &self.inode_area_start_block)
+            .field("inode_capacity", &self.inode_capacity)
+            .field("data_bitmap", &self.data_bitmap.span())
+            .field("data_area_start_block", &self.data_area_start_block)
+            .field("refcount_blocks", &self.refcount_blocks)
+            .field("dir_entry_size", &self.dir_entry_sz)
+            .field("case_insensitive", &self.case_insensitive)
+            .field("inode_crc_blocks", &self.inode_crc_blocks)
+            .finish()
+    }
+}
+
+///Where the areas of a filesystem are placed on its device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    ///Superblock, inode bitmap, inode area, data bitmap, data area
+    InodesFirst,
+    ///Superblock, data bitmap, data area, inode bitmap, inode area
+    InodesLast,
+}
+
+///Format options of `TinyFileSystem::create_with_config`
+#[derive(Clone, Copy, Debug)]
+pub struct CreateConfig {
+    ///Share identical data blocks, see `TinyFileSystem::create_dedup`
+    pub dedup: bool,
+    ///Keep a crc of each inode block, see `TinyFileSystem::create_checked`
+    pub checked: bool,
+    ///Match names ignoring ASCII case, see `TinyFileSystem::create_case_insensitive`
+    pub case_insensitive: bool,
+    ///Bytes of a dir_entry, 32, 64 or 128
+    pub dir_entry_size: usize,
+    ///Where the areas are placed, recorded in the superblock for `open`
+    pub layout: Layout,
+}
+
+impl Default for CreateConfig {
+    fn default() -> Self {
+        Self {
+            dedup: false,
+            checked: false,
+            case_insensitive: false,
+            dir_entry_size: DIR_ENTRY_SZ,
+            layout: Layout::InodesFirst,
+        }
+    }
+}
+
+///Sizes of the areas of a filesystem, see `TinyFileSystem::validate_params`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    ///Blocks of the inode bitmap
+    pub inode_bitmap_blocks: u32,
+    ///Blocks of the inode area
+    pub inode_area_blocks: u32,
+    ///Blocks of the data bitmap
+    pub data_bitmap_blocks: u32,
+    ///Blocks of the data area as recorded in the superblock
+    pub data_area_blocks: u32,
+    ///Blocks of refcounts, 0 unless config.dedup
+    pub refcount_blocks: u32,
+    ///Blocks of inode block crcs, 0 unless config.checked
+    pub inode_crc_blocks: u32,
+    ///Inodes the inode area holds
+    pub inodes: u32,
+    ///Data blocks files can be given
+    pub data_blocks: u32,
 }
 
 /* create/open/root_inode */
@@ -33,55 +138,116 @@ impl TinyFileSystem {
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
-        //create bitmaps
-        //calculate block_size of areas 
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
-        let inode_num = inode_bitmap.maxium();
-        let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
-        let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_area_blocks;
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
-        let data_area_blocks = data_total_blocks - data_bitmap_blocks;
-        let data_bitmap = Bitmap::new(
-            (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
-            data_bitmap_blocks as usize
-        );
-        let inode_area_start_block = 1 + inode_bitmap_blocks;
-        let data_area_start_block = 1 + inode_total_blocks + data_bitmap_blocks;
-        //create tfs
-        let mut tfs = Self {
-            block_device: Arc::clone(&block_device),
-            inode_bitmap,
-            data_bitmap,
-            inode_area_start_block,
-            data_area_start_block,
-        };
-        //clear all blocks
-        for i in 0..total_blocks {
-            get_block_cache(i as usize, Arc::clone(&block_device))
-                .lock()
-                .modify(0, |data_block: &mut DataBlock| {
-                   for byte in data_block.iter_mut() {
-                    *byte = 0;
-                   } 
-                });
-        }
-        //initialize SuperBlock
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        Self::create_with_config(block_device, total_blocks, inode_bitmap_blocks, CreateConfig::default())
+    }
+    ///Create a filesystem on block device which keeps a crc of each inode block
+    ///Operations on an inode whose block no longer matches fail with `Error::Corrupt`
+    pub fn create_checked(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        let config = CreateConfig { checked: true, ..CreateConfig::default() };
+        Self::create_with_config(block_device, total_blocks, inode_bitmap_blocks, config)
+    }
+    ///Create a filesystem on block device which shares identical data blocks
+    ///Only blocks written whole by `Inode::write_at` are deduplicated
+    pub fn create_dedup(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        let config = CreateConfig { dedup: true, ..CreateConfig::default() };
+        Self::create_with_config(block_device, total_blocks, inode_bitmap_blocks, config)
+    }
+    ///Create a filesystem on block device matching names ignoring ASCII case,
+    ///like FAT: `create("File")` fails once "file" exists
+    ///Names keep the case they were created with
+    pub fn create_case_insensitive(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        let config = CreateConfig { case_insensitive: true, ..CreateConfig::default() };
+        Self::create_with_config(block_device, total_blocks, inode_bitmap_blocks, config)
+    }
+    ///Create a filesystem on block device whose dir_entries take
+    ///dir_entry_size bytes, which must be 32, 64 or 128
+    ///Names may then be up to dir_entry_size - 5 bytes long
+    pub fn create_with_dir_entry_size(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        dir_entry_size: usize,
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        let config = CreateConfig { dir_entry_size, ..CreateConfig::default() };
+        Self::create_with_config(block_device, total_blocks, inode_bitmap_blocks, config)
+    }
+    ///Create a filesystem on block device with the format options of config
+    ///Fail with `Error::BadGeometry` where `validate_params` does
+    pub fn create_with_config(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        config: CreateConfig,
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        let geometry = Self::validate_params(total_blocks, inode_bitmap_blocks, config)?;
+        let Geometry {
+            inode_area_blocks,
+            data_bitmap_blocks,
+            data_area_blocks,
+            refcount_blocks,
+            inode_crc_blocks,
+            ..
+        } = geometry;
+        //initialize SuperBlock, where all areas are placed from
+        get_block_cache_for_overwrite(0, Arc::clone(&block_device))
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
         get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .modify(0, |super_block: &mut SuperBlock| {
-               super_block.initialize(
+                super_block.initialize(
                     total_blocks,
                     inode_bitmap_blocks,
                     inode_area_blocks,
                     data_bitmap_blocks,
-                    data_area_blocks
+                    data_area_blocks,
+                    refcount_blocks
                 );
+                super_block.dir_entry_size = config.dir_entry_size as u32;
+                super_block.inode_crc_blocks = inode_crc_blocks;
+                if config.case_insensitive {
+                    super_block.flags |= SB_FLAG_CASE_INSENSITIVE;
+                }
+                if config.layout == Layout::InodesLast {
+                    super_block.flags |= SB_FLAG_INODES_LAST;
+                }
             });
+        //create tfs
+        let mut tfs = get_block_cache(0, Arc::clone(&block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| Self::from_super_block(block_device, super_block));
+        let block_device = Arc::clone(&tfs.block_device);
+        //clear the other metadata blocks: bitmaps, inode, refcount and crc areas
+        //data blocks may keep stale content, every path that hands one out
+        //zeroes or overwrites the bytes below the new size first
+        let inode_area_end_block = tfs.inode_area_start_block + inode_area_blocks;
+        let metadata_blocks = (1..tfs.data_area_start_block)
+            .chain(tfs.inode_bitmap.span().0 as u32..inode_area_end_block);
+        for i in metadata_blocks {
+            get_block_cache_for_overwrite(i as usize, Arc::clone(&block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
+        }
+        let (inode_capacity, data_capacity) = (tfs.inode_capacity, tfs.data_capacity());
+        tfs.update_free_counts(|super_block| {
+            super_block.free_inodes = inode_capacity;
+            super_block.free_data_blocks = data_capacity;
+        });
         //create root_inode
-        assert_eq!(tfs.alloc_inode(), 0);
+        assert_eq!(tfs.alloc_inode(), Ok(0));
         let (root_inode_block_id, root_inode_offset)
             = tfs.get_disk_inode_pos(0);
         get_block_cache(
@@ -92,64 +258,378 @@ impl TinyFileSystem {
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
             disk_inode.initialize(DiskInodeType::Directory); 
         });
+        //every inode block gets its crc, the empty ones included
+        for block_id in tfs.inode_area_start_block..inode_area_end_block {
+            tfs.seal_inode_block(block_id as usize);
+        }
         //return tfs
         block_cache_sync_all();
-        Arc::new(Mutex::new(tfs))
+        Ok(Arc::new(Mutex::new(tfs)))
+    }
+    ///Work out the areas `create_with_config` would lay out, without
+    ///touching any device, so parameters can be checked before formatting
+    ///Fail with `Error::BadGeometry` for an unsupported dir_entry size, no
+    ///inode bitmap, or a device too small to hold the metadata and a data block
+    pub fn validate_params(
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        config: CreateConfig,
+    ) -> Result<Geometry, Error> {
+        if !DIR_ENTRY_SIZES.contains(&config.dir_entry_size) || inode_bitmap_blocks == 0 {
+            return Err(Error::BadGeometry);
+        }
+        //calculate block_size of areas
+        let inode_num = Bitmap::new(1, inode_bitmap_blocks as usize).maxium();
+        let inode_area_blocks = u32::try_from(
+            inode_num.saturating_mul(core::mem::size_of::<DiskInode>()).div_ceil(BLOCK_SZ)
+        ).map_err(|_| Error::BadGeometry)?;
+        let data_total_blocks = total_blocks
+            .checked_sub(1 + inode_area_blocks)
+            .ok_or(Error::BadGeometry)?;
+        let data_bitmap_blocks = data_total_blocks.div_ceil(4097);
+        let mut data_area_blocks = data_total_blocks - data_bitmap_blocks;
+        //the refcount area is carved from the head of the data area
+        let refcount_blocks = if config.dedup {
+            data_area_blocks.div_ceil(REFCOUNTS_PER_BLOCK)
+        } else {
+            0
+        };
+        //followed by the crcs of the inode blocks
+        let inode_crc_blocks = if config.checked {
+            inode_area_blocks.div_ceil((BLOCK_SZ / 4) as u32)
+        } else {
+            0
+        };
+        data_area_blocks = data_area_blocks
+            .checked_sub(refcount_blocks + inode_crc_blocks)
+            .ok_or(Error::BadGeometry)?;
+        //what is left once every metadata area is placed
+        let data_blocks = [
+            inode_bitmap_blocks,
+            inode_area_blocks,
+            data_bitmap_blocks,
+            refcount_blocks,
+            inode_crc_blocks,
+        ]
+        .into_iter()
+        .try_fold(total_blocks - 1, |left, blocks| left.checked_sub(blocks))
+        .filter(|&data_blocks| data_blocks > 0)
+        .ok_or(Error::BadGeometry)?;
+        Ok(Geometry {
+            inode_bitmap_blocks,
+            inode_area_blocks,
+            data_bitmap_blocks,
+            data_area_blocks,
+            refcount_blocks,
+            inode_crc_blocks,
+            inodes: Self::inode_capacity(inode_area_blocks),
+            data_blocks,
+        })
     }
     ///Open a block device as a filesystem
     ///This function is often more commonly used than `create`
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-        //read super_block
-        get_block_cache(0, Arc::clone(&block_device))
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>, Error> {
+        //copy super_block out so block 0 is unlocked while the fs is built
+        let super_block = get_block_cache(0, Arc::clone(&block_device))
             .lock()
-            .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading TFS!");
-                let inode_bitmap = Bitmap::new(
-                    1,
-                    super_block.inode_area_blocks as usize
-                );
-                let inode_total_blocks =
-                    super_block.inode_area_blocks + super_block.inode_bitmap_blocks;
-                let data_bitmap = Bitmap::new(
-                    (1 + inode_total_blocks) as usize,
-                    super_block.data_bitmap_blocks as usize
-                );
-                let inode_area_start_block = 1 + super_block.inode_bitmap_blocks;
-                let data_area_start_block = 1 + inode_total_blocks + super_block.data_bitmap_blocks;
-                let tfs = Self {
-                    block_device,
-                    inode_bitmap,
-                    data_bitmap,
-                    inode_area_start_block,
-                    data_area_start_block,
-                };
-                Arc::new(Mutex::new(tfs))
-            })
+            .read(0, |super_block: &SuperBlock| *super_block);
+        if !super_block.is_valid() {
+            return Err(Error::BadMagic);
+        }
+        let mut tfs = Self::from_super_block(block_device, &super_block);
+        let (root_block_id, root_offset) = tfs.get_disk_inode_pos(0);
+        let root_valid = get_block_cache(root_block_id as usize, Arc::clone(&tfs.block_device))
+            .lock()
+            .read(root_offset, |raw: &[u8; core::mem::size_of::<DiskInode>()]| {
+                DiskInode::validate(raw, tfs.dir_entry_sz)
+            });
+        //a misplaced bitmap shows bits past the area it covers,
+        //and the root inode is always allocated and sane
+        if !tfs.inode_bitmap.self_check(&tfs.block_device, tfs.inode_capacity as usize)
+            || !tfs.data_bitmap.self_check(&tfs.block_device, tfs.data_capacity() as usize)
+            || tfs.inode_bitmap.iter_allocated(&tfs.block_device).next() != Some(0)
+            || !root_valid
+        {
+            return Err(Error::Corrupt);
+        }
+        //images made before the counts existed have both at 0
+        if tfs.free_counts() == (0, 0) {
+            tfs.verify_free_counts();
+        }
+        Ok(Arc::new(Mutex::new(tfs)))
+    }
+    //place the areas the way super_block describes them
+    fn from_super_block(block_device: Arc<dyn BlockDevice>, super_block: &SuperBlock) -> Self {
+        let area_starts = super_block.area_starts();
+        Self {
+            block_device,
+            inode_bitmap: Bitmap::new(
+                area_starts.inode_bitmap as usize,
+                super_block.inode_bitmap_blocks as usize
+            ),
+            data_bitmap: Bitmap::new(
+                area_starts.data_bitmap as usize,
+                super_block.data_bitmap_blocks as usize
+            ),
+            inode_area_start_block: area_starts.inode_area,
+            data_area_start_block: area_starts.data_area,
+            data_area_end_block: area_starts.data_area_end,
+            total_blocks: super_block.total_blocks,
+            inode_capacity: Self::inode_capacity(super_block.inode_area_blocks),
+            refcount_start_block: area_starts.refcount,
+            refcount_blocks: super_block.refcount_blocks,
+            dedup_blocks: BTreeMap::new(),
+            dedup_hashes: BTreeMap::new(),
+            dir_entry_sz: super_block.dir_entry_sz(),
+            case_insensitive: super_block.is_case_insensitive(),
+            inode_crc_blocks: super_block.inode_crc_blocks,
+            inode_crc_area: InodeCrcArea::new(
+                area_starts.inode_area,
+                area_starts.inode_crc,
+                super_block.inode_crc_blocks,
+            ),
+            verified_inode_blocks: BTreeSet::new(),
+            alloc_policy: Box::new(FirstFit),
+            mounted_root: Weak::new(),
+        }
+    }
+    ///Get the (free inodes, free data blocks) kept in the superblock
+    ///This is O(1), but the counts may drift after a crash
+    pub fn free_counts(&self) -> (u32, u32) {
+        self.superblock(|super_block| (super_block.free_inodes, super_block.free_data_blocks))
+    }
+    ///Read the superblock as it is in the block cache
+    pub fn superblock<V>(&self, f: impl FnOnce(&SuperBlock) -> V) -> V {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, f)
+    }
+    ///Check the free counts against a full bitmap scan and repair them
+    ///Return whether they were correct
+    pub fn verify_free_counts(&mut self) -> bool {
+        let free_inodes =
+            self.inode_capacity - self.inode_bitmap.count_allocated(&self.block_device) as u32;
+        let free_data_blocks = self.data_capacity() - self.used_data_blocks();
+        let correct = self.free_counts() == (free_inodes, free_data_blocks);
+        if !correct {
+            self.update_free_counts(|super_block| {
+                super_block.free_inodes = free_inodes;
+                super_block.free_data_blocks = free_data_blocks;
+            });
+        }
+        correct
+    }
+    ///Get the number of blocks of the device the filesystem spans
+    pub fn total_blocks(&self) -> u32 {
+        self.total_blocks
+    }
+    ///Get the size of a dir_entry in bytes
+    pub fn dir_entry_size(&self) -> usize {
+        self.dir_entry_sz
+    }
+    ///Whether names are matched ignoring ASCII case, see `create_case_insensitive`
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+    ///Whether two names are the same name on this filesystem
+    pub fn names_match(&self, a: &str, b: &str) -> bool {
+        if self.case_insensitive {
+            a.eq_ignore_ascii_case(b)
+        } else {
+            a == b
+        }
+    }
+    ///Get the max length of a name in a directory
+    pub fn name_length_limit(&self) -> usize {
+        DirEntry::name_limit(self.dir_entry_sz)
+    }
+    ///Move the data blocks of inode into one contiguous run
+    ///Offline operation: inode must not be used by anyone else meanwhile
+    pub fn defragment_inode(&mut self, inode: &Inode) -> Result<(), Error> {
+        inode.relocate_data(self)
+    }
+    ///Write all dirty cached blocks back to the device
+    pub fn sync(&self) {
+        block_cache_sync_all();
+    }
+    ///Copy the whole image block for block onto dest, which can then be
+    ///opened on its own; dest must have at least total_blocks blocks
+    pub fn clone_to(&self, dest: &Arc<dyn BlockDevice>) {
+        const BATCH_BLOCKS: usize = 64;
+        self.sync();
+        //cached blocks of dest would hide the copy
+        block_cache_drop_device(dest);
+        let total_blocks = self.total_blocks as usize;
+        let mut buf = vec![0u8; BATCH_BLOCKS * BLOCK_SZ];
+        for start in (0..total_blocks).step_by(BATCH_BLOCKS) {
+            let len = (total_blocks - start).min(BATCH_BLOCKS) * BLOCK_SZ;
+            self.block_device.read_blocks(start, &mut buf[..len]);
+            dest.write_blocks(start, &buf[..len]);
+        }
+    }
+    ///Reformat in place with the current layout, leaving an empty root
+    ///Only the bitmaps, the refcount area and the root inode are reset,
+    ///the data area and the other inodes keep their stale bytes, which
+    ///makes this much cheaper than `create` when they do not matter
+    ///Inodes got before are invalid afterwards
+    pub fn quick_format(&mut self) {
+        self.inode_bitmap.clear_all(&self.block_device);
+        self.data_bitmap.clear_all(&self.block_device);
+        for block_id in self.refcount_start_block..self.refcount_start_block + self.refcount_blocks {
+            get_block_cache_for_overwrite(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
+        }
+        self.dedup_blocks.clear();
+        self.dedup_hashes.clear();
+        let (inode_capacity, data_capacity) = (self.inode_capacity, self.data_capacity());
+        self.update_free_counts(|super_block| {
+            super_block.free_inodes = inode_capacity;
+            super_block.free_data_blocks = data_capacity;
+        });
+        assert_eq!(self.alloc_inode(), Ok(0));
+        let (root_inode_block_id, root_inode_offset) = self.get_disk_inode_pos(0);
+        get_block_cache(root_inode_block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.initialize(DiskInodeType::Directory);
+            });
+        self.seal_inode_block(root_inode_block_id as usize);
+        block_cache_sync_all();
+    }
+    ///Write everything back and shut the filesystem down
+    ///If tfs is the last handle, the cached blocks of its device are
+    ///dropped too, otherwise they stay until the other handles are gone
+    pub fn close(tfs: Arc<Mutex<Self>>) {
+        tfs.lock().sync();
+        if let Ok(tfs) = Arc::try_unwrap(tfs) {
+            block_cache_drop_device(&tfs.into_inner().block_device);
+        }
     }
     ///Get the root_inode of the filesystem(is not DiskInode and return Inode)
     pub fn root_inode(tfs: &Arc<Mutex<Self>>) -> Inode {
         let (block_id, offset) = tfs.lock().get_disk_inode_pos(0);
-        Inode::new(
-            block_id,
-            offset,
-            Arc::clone(tfs),
-            Arc::clone(&tfs.lock().block_device),
-        )
+        let block_device = Arc::clone(&tfs.lock().block_device);
+        Inode::new(block_id, offset, Arc::clone(tfs), block_device)
+    }
+    ///Get the root inode as a shared handle, the same one for every call
+    ///as long as one is alive, so the fs is locked once and nothing is built
+    ///The fs only keeps a weak reference, dropping the handles frees it
+    pub fn mounted_root(tfs: &Arc<Mutex<Self>>) -> Arc<Inode> {
+        let (block_id, offset, block_device) = {
+            let fs = tfs.lock();
+            if let Some(root) = fs.mounted_root.upgrade() {
+                return root;
+            }
+            let (block_id, offset) = fs.get_disk_inode_pos(0);
+            (block_id, offset, Arc::clone(&fs.block_device))
+        };
+        //Inode::new locks the fs, so it is built unlocked
+        let root = Arc::new(Inode::new(block_id, offset, Arc::clone(tfs), block_device));
+        let mut fs = tfs.lock();
+        //another caller may have got there first
+        if let Some(root) = fs.mounted_root.upgrade() {
+            return root;
+        }
+        fs.mounted_root = Arc::downgrade(&root);
+        root
+    }
+    ///Resolve an absolute path like `/dir/file` from the root inode
+    ///
+    ///There is no current directory, so a relative path resolves to `None`,
+    ///as does a missing component or a component below a file.
+    ///Empty components from repeated `/` are skipped.
+    pub fn inode_of_path(tfs: &Arc<Mutex<Self>>, path: &str) -> Option<Arc<Inode>> {
+        let rest = path.strip_prefix('/')?;
+        let mut inode = Arc::new(Self::root_inode(tfs));
+        for name in rest.split('/').filter(|name| !name.is_empty()) {
+            inode = inode.find(name)?;
+        }
+        Some(inode)
+    }
+    ///Load the superblock, the first bitmap blocks and the root inode
+    ///into the block cache, so the first operations after `open` hit it
+    pub fn warm_cache(&self) {
+        let (root_block_id, _) = self.get_disk_inode_pos(0);
+        let blocks = [
+            0,
+            self.inode_bitmap.span().0,
+            self.data_bitmap.span().0,
+            root_block_id as usize,
+        ];
+        for block_id in blocks {
+            get_block_cache(block_id, Arc::clone(&self.block_device));
+        }
     }
 }
 
 /* allocation and get global position on block device */
 impl TinyFileSystem {
     ///Allocate a new inode and return bit
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    pub fn alloc_inode(&mut self) -> Result<u32, Error> {
+        let inode_bit = self.inode_bitmap
+            .alloc(&self.block_device)
+            .ok_or(Error::NoSpace)?;
+        if inode_bit as u32 >= self.inode_capacity {
+            //the bitmap covers more inodes than the inode area holds
+            let _ = self.inode_bitmap.dealloc(&self.block_device, inode_bit);
+            return Err(Error::NoSpace);
+        }
+        self.update_free_counts(|super_block| {
+            super_block.free_inodes = super_block.free_inodes.saturating_sub(1);
+        });
+        Ok(inode_bit as u32)
+    }
+    ///Deallocate an inode by bit
+    ///A bit past the inode bitmap comes from a bug elsewhere and is ignored
+    pub fn dealloc_inode(&mut self, inode_bit: u32) {
+        if self.inode_bitmap.dealloc(&self.block_device, inode_bit as usize).is_ok() {
+            self.update_free_counts(|super_block| super_block.free_inodes += 1);
+        }
     }
     ///Allocate a data block and return global_id
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    pub fn alloc_data(&mut self) -> Result<u32, Error> {
+        self.alloc_n(1).map(|block_ids| block_ids[0])
+    }
+    ///Allocate count data blocks where the alloc policy places them,
+    ///return their global_ids in the order they are to be used
+    pub fn alloc_n(&mut self, count: u32) -> Result<Vec<u32>, Error> {
+        let limit = self.data_capacity() as usize;
+        let data_bits = self.alloc_policy
+            .alloc(&self.data_bitmap, &self.block_device, count as usize, limit)
+            .ok_or(Error::NoSpace)?;
+        self.update_free_counts(|super_block| {
+            super_block.free_data_blocks = super_block.free_data_blocks.saturating_sub(count);
+        });
+        Ok(data_bits
+            .into_iter()
+            .map(|data_bit| data_bit as u32 + self.data_area_start_block)
+            .collect())
+    }
+    ///Choose where data blocks allocated from now on are placed
+    pub fn set_alloc_policy(&mut self, alloc_policy: Box<dyn AllocPolicy>) {
+        self.alloc_policy = alloc_policy;
+    }
+    ///Allocate count consecutive data blocks and return the first global_id
+    pub fn alloc_contiguous(&mut self, count: u32) -> Result<u32, Error> {
+        let data_bit = self.data_bitmap
+            .alloc_contiguous(&self.block_device, count as usize, self.data_capacity() as usize)
+            .ok_or(Error::NoSpace)?;
+        self.update_free_counts(|super_block| {
+            super_block.free_data_blocks = super_block.free_data_blocks.saturating_sub(count);
+        });
+        Ok(data_bit as u32 + self.data_area_start_block)
     }
     ///Deallocate a data block by global_id
+    ///A block outside the data area comes from a bug elsewhere and is
+    ///left alone, so that metadata blocks are never zeroed or freed
     pub fn dealloc_data(&mut self, block_id: u32) {
+        let Some(data_bit) = self.data_bit(block_id) else {
+            return;
+        };
+        if self.release_shared(block_id) {
+            return;
+        }
         get_block_cache(block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(0, |data_block: &mut DataBlock| {
@@ -157,15 +637,59 @@ impl TinyFileSystem {
                     *p = 0;
                 })
             });
-        self.data_bitmap.dealloc(
-            &self.block_device,
-            (block_id - self.data_area_start_block) as usize
-        );
+        if self.data_bitmap.dealloc(&self.block_device, data_bit).is_ok() {
+            self.update_free_counts(|super_block| super_block.free_data_blocks += 1);
+        }
+    }
+    ///Deallocate a data block by global_id, leaving its content on disk
+    ///Only for blocks whose stale content may be exposed safely
+    pub fn dealloc_data_no_zero(&mut self, block_id: u32) {
+        let Some(data_bit) = self.data_bit(block_id) else {
+            return;
+        };
+        if self.release_shared(block_id) {
+            return;
+        }
+        if self.data_bitmap.dealloc(&self.block_device, data_bit).is_ok() {
+            self.update_free_counts(|super_block| super_block.free_data_blocks += 1);
+        }
+    }
+    //the data bitmap bit of block_id, None outside the data area
+    fn data_bit(&self, block_id: u32) -> Option<usize> {
+        block_id
+            .checked_sub(self.data_area_start_block)
+            .filter(|&data_bit| data_bit < self.data_capacity())
+            .map(|data_bit| data_bit as usize)
+    }
+    ///Get the number of data blocks in use
+    pub fn used_data_blocks(&self) -> u32 {
+        self.data_bitmap.count_allocated(&self.block_device) as u32
     }
     ///Get global data_block_id by bit
     pub fn get_data_block_id(&self, data_bit: u32) -> u32 {
         self.data_area_start_block + data_bit
     }
+    ///Get the number of data blocks the device has room for
+    fn data_capacity(&self) -> u32 {
+        self.data_area_end_block - self.data_area_start_block
+    }
+    fn update_free_counts(&self, f: impl FnOnce(&mut SuperBlock)) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, f);
+    }
+    ///Get the number of inodes an inode area of inode_area_blocks holds
+    fn inode_capacity(inode_area_blocks: u32) -> u32 {
+        let inodes_per_block = (BLOCK_SZ / core::mem::size_of::<DiskInode>()) as u32;
+        inode_area_blocks * inodes_per_block
+    }
+    ///Get inode bit by position, the inverse of `get_disk_inode_pos`
+    pub fn get_inode_bit(&self, block_id: u32, offset: usize) -> u32 {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        (block_id - self.inode_area_start_block) * inodes_per_block
+            + (offset / inode_size) as u32
+    }
     ///Get inode position by bit
     pub fn get_disk_inode_pos(&self, inode_bit: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
@@ -176,4 +700,190 @@ impl TinyFileSystem {
             (inode_bit % inodes_per_block) as usize * inode_size,
         )
     }
-}
\ No newline at end of file
+}
+
+/*
+    Block dedup: a data block may be referenced by several inodes.
+    The refcount area stores the extra references of each data block
+    as a u16, so 0 means the block has a single owner. Blocks must be
+    unshared before they are written in place.
+*/
+const REFCOUNTS_PER_BLOCK: u32 = (BLOCK_SZ / 2) as u32;
+
+fn block_hash(data: &DataBlock) -> u64 {
+    //FNV-1a
+    data.iter().fold(0xcbf29ce484222325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl TinyFileSystem {
+    ///Whether the filesystem was created by `create_dedup`
+    pub fn is_dedup(&self) -> bool {
+        self.refcount_blocks != 0
+    }
+    ///Whether a data block is referenced by more than one inode
+    pub fn is_shared(&self, block_id: u32) -> bool {
+        self.is_dedup() && self.extra_refs(block_id) != 0
+    }
+    ///Find another data block holding the same content as block_id
+    ///The found block gets one more reference, and the caller should
+    ///drop block_id in favor of it; otherwise block_id is remembered
+    pub fn dedup_block(&mut self, block_id: u32) -> Option<u32> {
+        let mut data: DataBlock = [0u8; BLOCK_SZ];
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |data_block: &DataBlock| data.copy_from_slice(data_block));
+        let hash = block_hash(&data);
+        if let Some(&shared) = self.dedup_blocks.get(&hash) {
+            let same = shared != block_id
+                && get_block_cache(shared as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| *data_block == data);
+            let refs = self.extra_refs(shared);
+            if same && refs < u16::MAX {
+                self.set_extra_refs(shared, refs + 1);
+                return Some(shared);
+            }
+        }
+        self.forget_block(block_id);
+        if let Some(old) = self.dedup_blocks.insert(hash, block_id) {
+            self.dedup_hashes.remove(&old);
+        }
+        self.dedup_hashes.insert(block_id, hash);
+        None
+    }
+    ///Forget the content of a data block that is about to change
+    pub fn forget_block(&mut self, block_id: u32) {
+        if let Some(hash) = self.dedup_hashes.remove(&block_id) {
+            self.dedup_blocks.remove(&hash);
+        }
+    }
+    ///Drop one reference of a shared data block
+    ///Return false if the block has no other owner and should be freed
+    fn release_shared(&mut self, block_id: u32) -> bool {
+        if !self.is_dedup() {
+            return false;
+        }
+        let refs = self.extra_refs(block_id);
+        if refs != 0 {
+            self.set_extra_refs(block_id, refs - 1);
+            return true;
+        }
+        self.forget_block(block_id);
+        false
+    }
+    fn refcount_pos(&self, block_id: u32) -> (usize, usize) {
+        let data_bit = block_id - self.data_area_start_block;
+        (
+            (self.refcount_start_block + data_bit / REFCOUNTS_PER_BLOCK) as usize,
+            (data_bit % REFCOUNTS_PER_BLOCK) as usize * 2,
+        )
+    }
+    fn extra_refs(&self, block_id: u32) -> u16 {
+        let (block_id, offset) = self.refcount_pos(block_id);
+        get_block_cache(block_id, Arc::clone(&self.block_device))
+            .lock()
+            .read(offset, |refs: &u16| *refs)
+    }
+    fn set_extra_refs(&self, block_id: u32, refs: u16) {
+        let (block_id, offset) = self.refcount_pos(block_id);
+        get_block_cache(block_id, Arc::clone(&self.block_device))
+            .lock()
+            .modify(offset, |extra_refs: &mut u16| *extra_refs = refs);
+    }
+}
+
+/*
+    Inode block checksums: a filesystem created by create_checked keeps
+    a CRC-32 of each block of the inode area in an area following the
+    refcounts. The crc is refreshed whenever an inode changes, and an
+    inode block is checked the first time it is used after open, so a
+    torn write only fails the inodes of that block.
+*/
+fn crc32(data: &[u8]) -> u32 {
+    //IEEE, bit by bit
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+///Where the crcs of the inode blocks are kept, known since create or open
+#[derive(Clone, Copy)]
+pub(crate) struct InodeCrcArea {
+    inode_area_start_block: u32,
+    crc_start_block: u32,
+}
+
+impl InodeCrcArea {
+    fn new(inode_area_start_block: u32, crc_start_block: u32, crc_blocks: u32) -> Option<Self> {
+        (crc_blocks != 0).then_some(Self {
+            inode_area_start_block,
+            crc_start_block,
+        })
+    }
+    ///Get the (block_id, offset) of the crc of an inode block
+    fn crc_pos(&self, block_id: usize) -> (usize, usize) {
+        let crcs_per_block = BLOCK_SZ / 4;
+        let index = block_id - self.inode_area_start_block as usize;
+        (
+            self.crc_start_block as usize + index / crcs_per_block,
+            index % crcs_per_block * 4,
+        )
+    }
+    ///Whether an inode block matches its crc
+    fn check(&self, block_device: &Arc<dyn BlockDevice>, block_id: usize) -> bool {
+        let (crc_block_id, crc_offset) = self.crc_pos(block_id);
+        let stored = get_block_cache(crc_block_id, Arc::clone(block_device))
+            .lock()
+            .read(crc_offset, |crc: &u32| *crc);
+        stored == Self::block_crc(block_device, block_id)
+    }
+    ///Store the crc of an inode block whose inodes changed
+    ///No block cache may be held by the caller
+    pub(crate) fn seal(&self, block_device: &Arc<dyn BlockDevice>, block_id: usize) {
+        let (crc_block_id, crc_offset) = self.crc_pos(block_id);
+        let crc = Self::block_crc(block_device, block_id);
+        get_block_cache(crc_block_id, Arc::clone(block_device))
+            .lock()
+            .modify(crc_offset, |stored: &mut u32| *stored = crc);
+    }
+    fn block_crc(block_device: &Arc<dyn BlockDevice>, block_id: usize) -> u32 {
+        get_block_cache(block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |data_block: &DataBlock| crc32(data_block))
+    }
+}
+
+impl TinyFileSystem {
+    ///Whether the filesystem was created by `create_checked`
+    pub fn is_checked(&self) -> bool {
+        self.inode_crc_area.is_some()
+    }
+    ///Check an inode block against its crc, once per block after `open`
+    pub fn verify_inode_block(&mut self, block_id: u32) -> Result<(), Error> {
+        let Some(crc_area) = self.inode_crc_area else {
+            return Ok(());
+        };
+        if self.verified_inode_blocks.contains(&block_id) {
+            return Ok(());
+        }
+        if !crc_area.check(&self.block_device, block_id as usize) {
+            return Err(Error::Corrupt);
+        }
+        self.verified_inode_blocks.insert(block_id);
+        Ok(())
+    }
+    ///Store the crc of an inode block whose inodes changed
+    ///No block cache may be held by the caller
+    pub(crate) fn seal_inode_block(&self, block_id: usize) {
+        if let Some(crc_area) = self.inode_crc_area {
+            crc_area.seal(&self.block_device, block_id);
+        }
+    }
+    pub(crate) fn inode_crc_area(&self) -> Option<InodeCrcArea> {
+        self.inode_crc_area
+    }
+}
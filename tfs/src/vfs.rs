@@ -2,17 +2,24 @@
     Virtual file system, which provides a file operation interface
     to shield the differences of different file systems.
 */
+use super::range_lock::{lock_range, RangeGuard};
+use super::sha256::Sha256;
 use super::{
-    block_cache_sync_all, get_block_cache,
+    background_flush, block_cache_sync_all, block_cache_sync_blocks, get_block_cache,
+    get_block_cache_for_overwrite,
     DiskInode, DiskInodeType, DirEntry,
-    TinyFileSystem,
+    TinyFileSystem, InodeCrcArea,
     BlockDevice,
-    DIR_ENTRY_SZ,
+    Error,
+    BLOCK_SZ, MAX_FILE_SZ, SYMLINK_LENGTH_LIMIT,
 };
 
+use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::{Mutex, MutexGuard};
 ///Virtual filesystem layer over tiny-fs
 pub struct Inode{
@@ -24,6 +31,20 @@ pub struct Inode{
     offset: usize,
     fs: Arc<Mutex<TinyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
+    //copied from fs, so that changes are sealed without locking it
+    crc_area: Option<InodeCrcArea>,
+    //see `cache_metadata`, dropped whenever this handle modifies its disk_inode
+    cache_metadata: AtomicBool,
+    metadata: Mutex<Option<CachedMetadata>>,
+}
+
+///Size and type of a disk_inode as last read through one handle
+#[derive(Clone, Copy)]
+struct CachedMetadata {
+    size: u64,
+    is_file: bool,
+    is_dir: bool,
+    is_symlink: bool,
 }
 
 /*
@@ -32,123 +53,1046 @@ pub struct Inode{
 */
 impl Inode {
     ///Create inode by name
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        //find inode by name
-        if self.read_disk_inode(|root_inode: &DiskInode| {
-            assert!(root_inode.is_dir());
-            //has the file been created?
-            self.find_inode_id(name, root_inode)
-        }).is_some() {
-            //no new inode need be created
-            return None;
+    pub fn create(&self, name: &str) -> Result<Arc<Inode>, Error> {
+        let mut fs = self.lock_checked()?;
+        self.create_locked(name, &mut fs, |new_inode, _| {
+            new_inode.initialize(DiskInodeType::File);
+        })
+    }
+    ///Open the file by name, creating it first if it does not exist,
+    ///like `OpenOptions::create(true)`
+    ///The lookup and the creation happen under one fs lock, so two
+    ///callers racing on a name get the same inode
+    ///An existing directory is `IsADirectory`, a symlink `NotAFile`
+    pub fn get_or_create(&self, name: &str) -> Result<Arc<Inode>, Error> {
+        let mut fs = self.lock_checked()?;
+        if let Some(inode_bit) = self.lookup(name, &fs)? {
+            let inode = self.inode_of_bit(inode_bit, &fs);
+            let (is_file, is_dir) = inode.read_disk_inode(|disk_inode| {
+                (disk_inode.is_file(), disk_inode.is_dir())
+            });
+            if is_dir {
+                return Err(Error::IsADirectory);
+            }
+            if !is_file {
+                return Err(Error::NotAFile);
+            }
+            return Ok(Arc::new(inode));
         }
-        //new inode need be created
-        /* initialize new_inode */
-        let new_inode_bit = fs.alloc_inode();
-        let (new_inode_block_id, new_inode_offset)
-            = fs.get_disk_inode_pos(new_inode_bit);
-        get_block_cache(
-            new_inode_block_id as usize,
-            Arc::clone(&self.block_device)
-        ).lock()
-        .modify(new_inode_offset, |new_inode: &mut DiskInode| {
-            new_inode.initialize(DiskInodeType::File); 
-        });
-        /* update root_inode to contains new_inode */
-        self.modify_disk_inode(|root_inode| {
-            //apend dir_entry in the root_inode directory
-            //update meta_data
-            let file_count = (root_inode.size as usize) / DIR_ENTRY_SZ;
-            let new_size = (file_count + 1) * DIR_ENTRY_SZ;
-            //increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            //write dir_entry
-            let dirent = DirEntry::new(name, new_inode_bit);
-            root_inode.write_at(
-                file_count * DIR_ENTRY_SZ,
-                dirent.as_bytes(),
+        self.create_locked(name, &mut fs, |new_inode, _| {
+            new_inode.initialize(DiskInodeType::File);
+        })
+    }
+    ///Create a directory holding `.` and `..` by name
+    ///With the default dir_entry size both entries are kept inline, larger
+    ///ones get their block before the directory is linked into its parent
+    pub fn create_dir(&self, name: &str) -> Result<Arc<Inode>, Error> {
+        let mut fs = self.lock_checked()?;
+        let parent_bit = fs.get_inode_bit(self.block_id as u32, self.offset);
+        let dir_entry_sz = fs.dir_entry_size();
+        let new_blocks = fs.alloc_n(DiskInode::dir_blocks_needed(dir_entry_sz))?;
+        let dir = self.create_locked(name, &mut fs, |new_inode, new_inode_bit| {
+            new_inode.initialize_dir(
+                new_inode_bit,
+                parent_bit,
+                dir_entry_sz,
+                new_blocks.clone(),
                 &self.block_device
             );
         });
-        /* create and return new_inode */
-        let (block_id, offset) = fs.get_disk_inode_pos(new_inode_bit);
-        Some(Arc::new(Inode::new(
-            block_id,
-            offset,
-            self.fs.clone(),
-            self.block_device.clone()
-        )))
+        let dir = dir.inspect_err(|_| {
+            for &block_id in new_blocks.iter() {
+                fs.dealloc_data(block_id);
+            }
+        })?;
+        block_cache_sync_all();
+        Ok(dir)
+    }
+    ///Create a symlink to target by name
+    pub fn create_symlink(&self, name: &str, target: &str) -> Result<Arc<Inode>, Error> {
+        if target.len() > SYMLINK_LENGTH_LIMIT {
+            return Err(Error::NameTooLong);
+        }
+        let mut fs = self.lock_checked()?;
+        self.create_locked(name, &mut fs, |new_inode, _| {
+            new_inode.initialize_symlink(target);
+        })
+    }
+    ///Create inode by name and write contents into it at once
+    ///Like `create` followed by `write_at(0, contents)`, but the fs lock
+    ///is taken only once; the file is left empty if contents do not fit
+    pub fn create_file(&self, name: &str, contents: &[u8]) -> Result<Arc<Inode>, Error> {
+        let mut fs = self.lock_checked()?;
+        let inode = self.create_locked(name, &mut fs, |new_inode, _| {
+            new_inode.initialize(DiskInodeType::File);
+        })?;
+        inode.write_at_locked(0, contents, &mut fs)?;
+        block_cache_sync_all();
+        Ok(inode)
+    }
+    ///Copy current file into dest_dir as name and return the copy
+    ///dest_dir may belong to another filesystem; contents are streamed a
+    ///block at a time and the copy is removed again if they do not fit
+    pub fn export_to(&self, dest_dir: &Inode, name: &str) -> Result<Arc<Inode>, Error> {
+        if !self.is_file() {
+            return Err(Error::NotAFile);
+        }
+        let copy = dest_dir.create(name)?;
+        let mut buf = [0u8; BLOCK_SZ];
+        let mut offset = 0;
+        loop {
+            let len = self.read_at(offset, &mut buf);
+            if len == 0 {
+                break;
+            }
+            if let Err(err) = copy.write_at(offset, &buf[..len]) {
+                dest_dir.remove(name)?;
+                return Err(err);
+            }
+            offset += len;
+        }
+        Ok(copy)
+    }
+    ///Create a file named prefix followed by the first number that
+    ///makes the name unused, return the chosen name with the inode
+    pub fn create_unique(&self, prefix: &str) -> Result<(String, Arc<Inode>), Error> {
+        let mut fs = self.lock_checked()?;
+        for suffix in 0u32.. {
+            let name = format!("{}{}", prefix, suffix);
+            if name.len() > fs.name_length_limit() {
+                return Err(Error::NameTooLong);
+            }
+            if self.lookup(&name, &fs)?.is_none() {
+                let inode = self.create_locked(&name, &mut fs, |new_inode, _| {
+                    new_inode.initialize(DiskInodeType::File);
+                })?;
+                return Ok((name, inode));
+            }
+        }
+        Err(Error::NoSpace)
     }
     ///Find inode by name
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
+        let fs = self.lock_checked().ok()?;
+        self.lookup(name, &fs)
+            .ok()
+            .flatten()
+            .map(|inode_bit| Arc::new(self.inode_of_bit(inode_bit, &fs)))
+    }
+    ///Resolve path relative to current inode, like `openat(2)`
+    ///
+    ///Components are looked up one directory at a time, `.` stays put and
+    ///`..` goes up, the root being its own parent. A path starting with `/`
+    ///resolves from the root of the filesystem instead, as with
+    ///`TinyFileSystem::inode_of_path`. A missing component or a component
+    ///below a file resolves to `None`; symlinks are not followed.
+    pub fn open_at(self: &Arc<Self>, path: &str) -> Option<Arc<Inode>> {
+        let mut inode = match path.strip_prefix('/') {
+            Some(_) => Arc::new(TinyFileSystem::root_inode(&self.fs)),
+            None => Arc::clone(self),
+        };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = match name {
+                "." if inode.is_dir() => inode,
+                //only the root has no `..` entry
+                ".." if inode.is_dir() => inode.find("..").unwrap_or(inode),
+                _ => inode.find(name)?,
+            };
+        }
+        Some(inode)
+    }
+    ///Resolve path like `open_at`, creating each missing directory on the
+    ///way like `mkdir -p`, and return the last one
+    ///A component that exists but is not a directory is `NotADirectory`
+    pub fn create_dir_all(self: &Arc<Self>, path: &str) -> Result<Arc<Inode>, Error> {
+        let mut dir = match path.strip_prefix('/') {
+            Some(_) => Arc::new(TinyFileSystem::root_inode(&self.fs)),
+            None => Arc::clone(self),
+        };
+        if !dir.is_dir() {
+            return Err(Error::NotADirectory);
+        }
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            dir = match name {
+                "." => dir,
+                ".." => dir.find("..").unwrap_or(dir),
+                _ => match dir.find(name) {
+                    Some(inode) if inode.is_dir() => inode,
+                    Some(_) => return Err(Error::NotADirectory),
+                    None => dir.create_dir(name)?,
+                },
+            };
+        }
+        Ok(dir)
+    }
+    ///List inodes and return name vector
+    pub fn ls(&self) -> Vec<String> {
+        let mut entries: Vec<(String, u32)> = Vec::new();
+        self.read_dir_into(&mut entries);
+        entries.into_iter().map(|(name, _)| name).collect()
+    }
+    ///Iterate over the (name, inode bit) `ls` would list, one dir_entry
+    ///read per step, see `DirEntryIter`
+    pub fn entries_iter(&self) -> DirEntryIter<'_> {
+        DirEntryIter { inode: self, index: 0 }
+    }
+    ///Count the names `ls` would list, without reading any of them
+    ///unless entries were removed, then only their inode numbers
+    pub fn count_entries(&self) -> usize {
         let fs = self.fs.lock();
+        let dir_entry_sz = fs.dir_entry_size();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_bit| {
-                let (block_id, offset) = fs.get_disk_inode_pos(inode_bit);
-                Arc::new(Self::new(
-                    block_id,
-                    offset,
-                    self.fs.clone(),
-                    self.block_device.clone()
-                ))
-            })
+            assert!(disk_inode.is_dir());
+            let file_count = disk_inode.entry_count(dir_entry_sz, &self.block_device);
+            if !disk_inode.has_tombstones() {
+                return file_count;
+            }
+            (0..file_count)
+                .filter(|&i| !self.entry_of(disk_inode, i, dir_entry_sz).is_empty())
+                .count()
         })
     }
-    ///List inodes and return name vector
-    pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
+    ///Refill out with the (name, inode bit) of each dir_entry
+    ///The strings already in out are reused to save allocations
+    pub fn read_dir_into(&self, out: &mut Vec<(String, u32)>) {
+        let fs = self.fs.lock();
+        let dir_entry_sz = fs.dir_entry_size();
         self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIR_ENTRY_SZ;
-            let mut v: Vec<String> = Vec::new();
+            let file_count = disk_inode.entry_count(dir_entry_sz, &self.block_device);
+            let mut dir_entry = DirEntry::empty(dir_entry_sz);
+            let mut listed = 0;
             for i in 0..file_count {
-                let mut dir_entry = DirEntry::empty();
                 assert_eq!(
                     disk_inode.read_at(
-                        DIR_ENTRY_SZ * i,
+                        dir_entry_sz * i,
                         dir_entry.as_bytes_mut(),
                         &self.block_device
                     ),
-                    DIR_ENTRY_SZ
+                    dir_entry_sz
                 );
-                v.push(String::from(dir_entry.name()));
+                if dir_entry.is_empty() {
+                    continue;
+                }
+                match out.get_mut(listed) {
+                    Some((name, inode_bit)) => {
+                        name.clear();
+                        name.push_str(dir_entry.name());
+                        *inode_bit = dir_entry.inode_number();
+                    }
+                    None => out.push((String::from(dir_entry.name()), dir_entry.inode_number())),
+                }
+                listed += 1;
             }
-            v
+            out.truncate(listed);
         })
     }
+    ///Get a copy of every dir_entry of current directory
+    ///Unlike `ls`, each name comes with the inode number it points at,
+    ///and removed entries are kept, see `DirEntry::is_empty`
+    pub fn read_raw_dirents(&self) -> Vec<DirEntry> {
+        let fs = self.fs.lock();
+        let dir_entry_sz = fs.dir_entry_size();
+        self.read_disk_inode(|disk_inode| {
+            let file_count = disk_inode.entry_count(dir_entry_sz, &self.block_device);
+            (0..file_count)
+                .map(|i| {
+                    let mut dir_entry = DirEntry::empty(dir_entry_sz);
+                    assert_eq!(
+                        disk_inode.read_at(
+                            dir_entry_sz * i,
+                            dir_entry.as_bytes_mut(),
+                            &self.block_device
+                        ),
+                        dir_entry_sz
+                    );
+                    dir_entry
+                })
+                .collect()
+        })
+    }
+    ///Get a copy of the dir_entry at index of current directory,
+    ///None if index is past the end
+    pub fn read_dirent_raw(&self, index: usize) -> Option<DirEntry> {
+        let fs = self.fs.lock();
+        let dir_entry_sz = fs.dir_entry_size();
+        self.read_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir());
+            if index >= disk_inode.entry_count(dir_entry_sz, &self.block_device) {
+                return None;
+            }
+            let mut dir_entry = DirEntry::empty(dir_entry_sz);
+            let len = disk_inode.read_at(
+                dir_entry_sz.saturating_mul(index),
+                dir_entry.as_bytes_mut(),
+                &self.block_device
+            );
+            (len == dir_entry_sz).then_some(dir_entry)
+        })
+    }
+    ///Overwrite the dir_entry at index of current directory with entry,
+    ///growing the directory with empty entries if index is past the end
+    ///Nothing checks entry against the others, but the hash index follows
+    ///Entries reserved by `reserve_dir_capacity` are given back first
+    pub fn write_dirent(&self, index: usize, entry: &DirEntry) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let dir_entry_sz = fs.dir_entry_size();
+        assert_eq!(entry.as_bytes().len(), dir_entry_sz);
+        let offset = dir_entry_sz.checked_mul(index).ok_or(Error::FileTooLarge)?;
+        let (is_dir, hash_index, old_entry) = self.read_disk_inode(|disk_inode| {
+            let mut old_entry = DirEntry::empty(dir_entry_sz);
+            disk_inode.read_at(offset, old_entry.as_bytes_mut(), &self.block_device);
+            (disk_inode.is_dir(), disk_inode.hash_index(), old_entry)
+        });
+        assert!(is_dir);
+        self.modify_disk_inode(|disk_inode| {
+            if disk_inode.is_reserved() {
+                let file_count = disk_inode.entry_count(dir_entry_sz, &self.block_device);
+                self.truncate_locked((file_count * dir_entry_sz) as u32, disk_inode, &mut fs);
+                disk_inode.set_reserved(false);
+            }
+        });
+        self.write_at_locked(offset, entry.as_bytes(), &mut fs)?;
+        if let Some(index_bit) = hash_index {
+            if !old_entry.is_empty() {
+                self.hash_remove(old_entry.name(), index, index_bit, &fs);
+            }
+            if !entry.is_empty() {
+                self.hash_insert(entry.name(), index, index_bit, &mut fs);
+            }
+        }
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Rename old_name of current directory to new_name, replacing the
+    ///entry named new_name if there is one, like `rename(2)`
+    ///An inode has a single entry, so the replaced file is freed with its
+    ///blocks; a directory is never replaced. Everything happens under the
+    ///fs lock, so new_name is never seen missing in between
+    ///No other dir_entry moves, the slot of old_name is left as a tombstone
+    pub fn rename_or_replace(&self, old_name: &str, new_name: &str) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        check_name(new_name, &fs)?;
+        let old_index = self.entry_index(old_name, &fs)?.ok_or(Error::NotFound)?;
+        let new_index = self.entry_index(new_name, &fs)?;
+        if new_index == Some(old_index) && self.entry_at(old_index, fs.dir_entry_size()).name() == new_name {
+            return Ok(());
+        }
+        //ignoring case both may be the same entry, which just takes the new case
+        let new_index = new_index.filter(|&new_index| new_index != old_index);
+        let dir_entry_sz = fs.dir_entry_size();
+        let inode_bit = self.entry_at(old_index, dir_entry_sz).inode_number();
+        let renamed = DirEntry::new(new_name, inode_bit, dir_entry_sz);
+        let hash_index = self.read_disk_inode(|disk_inode| disk_inode.hash_index());
+        match new_index {
+            None => {
+                self.modify_disk_inode(|disk_inode| {
+                    disk_inode.write_at(old_index * dir_entry_sz, renamed.as_bytes(), &self.block_device);
+                });
+                if let Some(index_bit) = hash_index {
+                    self.hash_remove(old_name, old_index, index_bit, &fs);
+                    self.hash_insert(new_name, old_index, index_bit, &mut fs);
+                }
+            }
+            Some(new_index) => {
+                let target_bit = self.entry_at(new_index, dir_entry_sz).inode_number();
+                if target_bit != inode_bit {
+                    let target = self.inode_of_bit(target_bit, &fs);
+                    if target.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+                        return Err(Error::NotAFile);
+                    }
+                    let _range = target.lock_all();
+                    target.modify_disk_inode(|disk_inode| {
+                        for block_id in disk_inode.clear_size(&self.block_device).into_iter() {
+                            fs.dealloc_data(block_id);
+                        }
+                    });
+                    fs.dealloc_inode(target_bit);
+                }
+                self.modify_disk_inode(|disk_inode| {
+                    disk_inode.write_at(new_index * dir_entry_sz, renamed.as_bytes(), &self.block_device);
+                    self.remove_entry(old_index, dir_entry_sz, disk_inode);
+                });
+                //the slot of new_name keeps its entry index
+                if let Some(index_bit) = hash_index {
+                    self.hash_remove(old_name, old_index, index_bit, &fs);
+                }
+            }
+        }
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Remove the file named name from current directory and free it
+    ///with its blocks; a directory is never removed
+    ///The dir_entry is left in place as a tombstone for `create` to reuse,
+    ///so no other dir_entry moves
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let index = self.entry_index(name, &fs)?.ok_or(Error::NotFound)?;
+        let dir_entry_sz = fs.dir_entry_size();
+        let inode_bit = self.entry_at(index, dir_entry_sz).inode_number();
+        let target = self.inode_of_bit(inode_bit, &fs);
+        if target.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return Err(Error::NotAFile);
+        }
+        {
+            let _range = target.lock_all();
+            target.modify_disk_inode(|disk_inode| {
+                for block_id in disk_inode.clear_size(&self.block_device).into_iter() {
+                    fs.dealloc_data(block_id);
+                }
+            });
+        }
+        fs.dealloc_inode(inode_bit);
+        let hash_index = self.modify_disk_inode(|disk_inode| {
+            self.remove_entry(index, dir_entry_sz, disk_inode);
+            disk_inode.hash_index()
+        });
+        if let Some(index_bit) = hash_index {
+            self.hash_remove(name, index, index_bit, &fs);
+        }
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Grow current directory to hold entries dir_entries up front, so that
+    ///creating that many names allocates no more data blocks for it
+    ///The reserved entries stay empty and hidden until `create` takes them
+    pub fn reserve_dir_capacity(&self, entries: usize) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let dir_entry_sz = fs.dir_entry_size();
+        let new_size = checked_size(0, entries.saturating_mul(dir_entry_sz))?;
+        self.modify_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            let old_size = disk_inode.size as usize;
+            if new_size <= old_size {
+                return Ok(());
+            }
+            self.increase_size(new_size as u32, disk_inode, &mut fs)?;
+            //freed blocks are not always zeroed, empty entries must be
+            self.fill_range(old_size, new_size, 0, disk_inode);
+            disk_inode.set_reserved(true);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///List inodes and return name vector sorted by name
+    pub fn ls_sorted(&self) -> Vec<String> {
+        let mut v = self.ls();
+        v.sort();
+        v
+    }
+    ///Get the number of blocks used by current inode and its subtree
+    pub fn used_blocks(&self) -> u32 {
+        let fs = self.fs.lock();
+        self.subtree_blocks(&fs)
+    }
     ///Read data from current inode
+    ///Data blocks are read without the fs lock, so reads and writes of
+    ///disjoint ranges of a file run in parallel
+    ///Nothing is read if the inode fails its crc, see `create_checked`
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let Ok(fs) = self.lock_checked() else {
+            return 0;
+        };
+        let _range = self.lock_range(offset, buf.len());
+        let (start, len, block_ids) = self.read_disk_inode(|disk_inode| {
+            let start = (disk_inode.head_offset() as usize).saturating_add(offset);
+            let len = (disk_inode.size as usize).saturating_sub(start).min(buf.len());
+            (start, len, self.unlocked_blocks(start, start + len, false, disk_inode, &fs))
+        });
+        match block_ids {
+            Some(block_ids) => {
+                drop(fs);
+                self.read_blocks_at(start, &block_ids, &mut buf[..len]);
+                len
+            }
+            None => self.read_disk_inode(|disk_inode| {
+                disk_inode.read_at(start, buf, &self.block_device)
+            }),
+        }
+    }
+    ///Read exactly buf.len() bytes at offset
+    ///Fail with `Error::UnexpectedEof` if the file ends before buf is filled
+    pub fn read_exact_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.lock_checked()?;
+        if self.read_at(offset, buf) < buf.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        Ok(())
+    }
+    ///Check whether the content of current inode equals expected
+    ///The file is compared one block at a time, stopping at the first mismatch
+    pub fn verify_contents(&self, expected: &[u8]) -> bool {
         let _fs = self.fs.lock();
+        let _range = self.lock_all();
         self.read_disk_inode(|disk_inode| {
-            disk_inode.read_at(offset, buf, &self.block_device)
+            let head = disk_inode.head_offset() as usize;
+            if disk_inode.size as usize - head != expected.len() {
+                return false;
+            }
+            let mut buf = [0u8; BLOCK_SZ];
+            expected.chunks(BLOCK_SZ).enumerate().all(|(i, chunk)| {
+                let len = disk_inode.read_at(
+                    head + i * BLOCK_SZ,
+                    &mut buf[..chunk.len()],
+                    &self.block_device
+                );
+                &buf[..len] == chunk
+            })
+        })
+    }
+    ///Get the SHA-256 of the content of current inode
+    ///The file is hashed one block at a time instead of read whole
+    pub fn content_digest(&self) -> [u8; 32] {
+        let _fs = self.fs.lock();
+        let _range = self.lock_all();
+        self.read_disk_inode(|disk_inode| {
+            let mut sha = Sha256::new();
+            let mut buf = [0u8; BLOCK_SZ];
+            let mut offset = disk_inode.head_offset() as usize;
+            loop {
+                let len = disk_inode.read_at(offset, &mut buf, &self.block_device);
+                if len == 0 {
+                    break sha.finalize();
+                }
+                sha.update(&buf[..len]);
+                offset += len;
+            }
+        })
+    }
+    ///Read the whole content of current inode
+    pub fn read_all(&self) -> Vec<u8> {
+        let _fs = self.fs.lock();
+        let _range = self.lock_all();
+        self.read_disk_inode(|disk_inode| {
+            let head = disk_inode.head_offset() as usize;
+            let mut v: Vec<u8> = vec![0u8; disk_inode.size as usize - head];
+            disk_inode.read_at(head, &mut v, &self.block_device);
+            v
+        })
+    }
+    ///Read the whole content of current inode as UTF-8,
+    ///like `std::fs::read_to_string`
+    #[cfg(feature = "std")]
+    pub fn read_to_string(&self) -> std::io::Result<String> {
+        String::from_utf8(self.read_all())
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+    ///Read all data like `read_all`, but fetch data blocks from the
+    ///device directly so that a large read does not evict the block cache
+    pub fn read_all_direct(&self) -> Vec<u8> {
+        let _fs = self.fs.lock();
+        let _range = self.lock_all();
+        //the device must be up to date
+        block_cache_sync_all();
+        self.read_disk_inode(|disk_inode| {
+            let size = disk_inode.size as usize;
+            if disk_inode.is_inline() {
+                let mut v: Vec<u8> = vec![0u8; size];
+                disk_inode.read_at(0, &mut v, &self.block_device);
+                return v;
+            }
+            let mut v: Vec<u8> = vec![0u8; disk_inode.data_blocks() as usize * BLOCK_SZ];
+            let mut inner_id = 0;
+            //read a run of consecutive blocks at once, holes stay zero
+            for (start_block_id, count) in extents_of(disk_inode, &self.block_device) {
+                let count = count as usize;
+                if start_block_id != 0 {
+                    self.block_device.read_blocks(
+                        start_block_id as usize,
+                        &mut v[inner_id * BLOCK_SZ..(inner_id + count) * BLOCK_SZ]
+                    );
+                }
+                inner_id += count;
+            }
+            v.truncate(size);
+            v.drain(..disk_inode.head_offset() as usize);
+            v
+        })
+    }
+    ///Get the data blocks of current inode as runs of consecutive blocks,
+    ///each a (first global block id, length) pair in file order
+    ///A run of holes has 0 as its first global block id
+    pub fn extents(&self) -> Vec<(u32, u32)> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| extents_of(disk_inode, &self.block_device))
+    }
+    ///Get the global id of each data block of current inode in file order,
+    ///None for a hole; empty if the data is kept inline
+    ///A sparse copy backs only the Some blocks and skips the rest
+    pub fn blocks(&self) -> Vec<Option<u32>> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_inline() {
+                return Vec::new();
+            }
+            disk_inode
+                .iter_block_ids(&self.block_device)
+                .map(|block_id| Some(block_id).filter(|&block_id| block_id != 0))
+                .collect()
+        })
+    }
+    ///Get the global id of the nth data block of current inode,
+    ///None past the last one, for a hole or for inline data
+    ///Content at offset is in block (head_offset + offset) / BLOCK_SZ
+    pub fn nth_block_id(&self, n: usize) -> Option<u32> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_inline() || n >= disk_inode.data_blocks() as usize {
+                return None;
+            }
+            Some(disk_inode.get_block_id(n as u32, &self.block_device)).filter(|&block_id| block_id != 0)
         })
     }
+    ///Run f on the nth data block of current inode as it sits in the block
+    ///cache, without copying it out like `read_at` does
+    ///None past the last block or for inline data, a hole shows as zeros
+    ///Bytes of the last block past the end of the file are unspecified
+    ///The fs lock is held while f runs, so f must not call into tiny-fs
+    pub fn with_block<V>(&self, n: usize, f: impl FnOnce(&[u8; BLOCK_SZ]) -> V) -> Option<V> {
+        let _fs = self.lock_checked().ok()?;
+        let block_id = self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_inline() || n >= disk_inode.data_blocks() as usize {
+                return None;
+            }
+            Some(disk_inode.get_block_id(n as u32, &self.block_device))
+        })?;
+        if block_id == 0 {
+            return Some(f(&[0u8; BLOCK_SZ]));
+        }
+        Some(
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, f)
+        )
+    }
+    ///Get the block device current inode lives on
+    ///Reading it directly bypasses the block cache, so the caller has to
+    ///sync or `flush` first to see what was written
+    pub fn block_device(&self) -> Arc<dyn BlockDevice> {
+        Arc::clone(&self.block_device)
+    }
+    ///Get a cursor reading current inode from offset
+    pub fn seek_read(self: &Arc<Self>, offset: usize) -> Cursor {
+        Cursor::new(Arc::clone(self), offset)
+    }
     ///Write data to current inode
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let write_size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size(
-                (offset + buf.len()) as u32,
-                disk_inode, &mut fs
-            );
-            disk_inode.write_at(offset, buf, &self.block_device)
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        self.write_at_tracked(offset, buf).map(|(write_size, _)| write_size)
+    }
+    ///Write data to current inode like `write_at`, also returning
+    ///the global ids of the blocks newly allocated for this write
+    pub fn write_at_tracked(&self, offset: usize, buf: &[u8]) -> Result<(usize, Vec<u32>), Error> {
+        checked_size(offset, buf.len())?;
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_range(offset, buf.len());
+        let (start, block_ids) = self.read_disk_inode(|disk_inode| {
+            let start = disk_inode.head_offset() as usize + offset;
+            (start, self.unlocked_blocks(start, start + buf.len(), true, disk_inode, &fs))
         });
-        block_cache_sync_all();
+        if let Some(block_ids) = block_ids {
+            //the range lock keeps the blocks in place without the fs lock
+            drop(fs);
+            self.write_blocks_at(start, &block_ids, buf);
+            return Ok((buf.len(), Vec::new()));
+        }
+        let write_size = self.write_at_locked(offset, buf, &mut fs);
+        if !background_flush() {
+            block_cache_sync_all();
+        }
         write_size
     }
+    ///Write back the cached blocks of current inode only: its disk inode
+    ///block and its data and index blocks, other files' stay dirty
+    ///Meant for `set_background_flush`, where `write_at` does not sync
+    pub fn flush(&self) {
+        let _fs = self.fs.lock();
+        let mut block_ids = self.data_and_index_block_ids();
+        block_ids.push(self.block_id as u32);
+        block_cache_sync_blocks(&self.block_device, &block_ids);
+    }
+    ///Write back data and metadata of current inode, like `fsync(2)`
+    ///The same as `flush`
+    pub fn sync_all(&self) {
+        self.flush();
+    }
+    ///Write back the data of current inode, like `fdatasync(2)`
+    ///Its data and index blocks are written as by `flush`, but the disk
+    ///inode block only if the size, block pointers or aux differ from the
+    ///copy on the device, which costs one block read; a change of mode
+    ///alone stays cached
+    pub fn sync_data(&self) {
+        let _fs = self.fs.lock();
+        let block_ids = self.data_and_index_block_ids();
+        //this also drains pending writebacks, so the device copy is current
+        block_cache_sync_blocks(&self.block_device, &block_ids);
+        let mut block = [0u8; BLOCK_SZ];
+        self.block_device.read_block(self.block_id, &mut block);
+        let disk_inode_sz = core::mem::size_of::<DiskInode>();
+        let on_disk: &[u8; core::mem::size_of::<DiskInode>()] =
+            block[self.offset..self.offset + disk_inode_sz].try_into().unwrap();
+        let unchanged = self.read_disk_inode(|disk_inode| disk_inode.data_fields_eq(on_disk));
+        if !unchanged {
+            block_cache_sync_blocks(&self.block_device, &[self.block_id as u32]);
+        }
+    }
+    ///Replace the whole content of current inode with buf
+    ///Data blocks are reused, and readers never see an intermediate size
+    pub fn replace_contents(&self, buf: &[u8]) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_all();
+        self.modify_disk_inode(|disk_inode| {
+            let head = disk_inode.head_offset();
+            let new_size = checked_size(head as usize, buf.len())? as u32;
+            self.unshare_range(
+                head as usize,
+                new_size.min(disk_inode.size) as usize,
+                disk_inode,
+                &mut fs
+            )?;
+            self.increase_size(new_size, disk_inode, &mut fs)?;
+            disk_inode.write_at(head as usize, buf, &self.block_device);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Replace remove_len bytes at offset with insert, shifting the bytes after them
+    ///offset may be at most the size of current inode, where it appends
+    pub fn splice(&self, offset: usize, remove_len: usize, insert: &[u8]) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_all();
+        self.modify_disk_inode(|disk_inode| {
+            let size = disk_inode.size as usize;
+            let offset = (disk_inode.head_offset() as usize).saturating_add(offset);
+            if offset > size {
+                return Err(Error::OutOfBounds);
+            }
+            checked_size(offset, insert.len())?;
+            let tail_start = offset.saturating_add(remove_len).min(size);
+            let mut tail = vec![0u8; size - tail_start];
+            disk_inode.read_at(tail_start, &mut tail, &self.block_device);
+            let new_size = checked_size(offset + insert.len(), tail.len())?;
+            self.unshare_range(offset, new_size, disk_inode, &mut fs)?;
+            self.increase_size(new_size as u32, disk_inode, &mut fs)?;
+            disk_inode.write_at(offset, insert, &self.block_device);
+            disk_inode.write_at(offset + insert.len(), &tail, &self.block_device);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Set the size of current inode to new_size
+    ///Blocks past the new end are freed, and growing fills with zeros
+    pub fn truncate(&self, new_size: usize) -> Result<(), Error> {
+        self.set_size(new_size, true)
+    }
+    ///Extend current file to new_size, the added bytes reading as zeros
+    ///even over blocks that held other data before, like `truncate` but
+    ///failing with `Error::OutOfBounds` instead of shrinking
+    pub fn grow(&self, new_size: usize) -> Result<(), Error> {
+        self.set_size(new_size, false)
+    }
+    fn set_size(&self, new_size: usize, shrink: bool) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_all();
+        self.modify_disk_inode(|disk_inode| {
+            let old_size = disk_inode.size as usize;
+            let new_size = checked_size(disk_inode.head_offset() as usize, new_size)?;
+            if !shrink && new_size < old_size {
+                return Err(Error::OutOfBounds);
+            }
+            //freed blocks are zeroed by dealloc_data, but the bytes added
+            //are zeroed here too, stale data may come from clear_no_zero
+            //or from before the filesystem was formatted
+            self.unshare_range(
+                new_size.min(old_size),
+                new_size.max(old_size),
+                disk_inode,
+                &mut fs
+            )?;
+            self.increase_size(new_size as u32, disk_inode, &mut fs)?;
+            self.fill_range(old_size, new_size, 0, disk_inode);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Drop the first n bytes of current file, so reads start n bytes later
+    ///Data blocks falling wholly before the new start are freed,
+    ///the remaining data is not moved
+    pub fn discard_front(&self, n: usize) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_all();
+        self.modify_disk_inode(|disk_inode| {
+            if !disk_inode.is_file() {
+                return Err(Error::NotAFile);
+            }
+            let size = disk_inode.size as usize;
+            let head = (disk_inode.head_offset() as usize).saturating_add(n);
+            if head > size {
+                return Err(Error::OutOfBounds);
+            }
+            let drop_blocks = head / BLOCK_SZ;
+            if drop_blocks > 0 {
+                //move the leading blocks behind the others, the shrink frees them
+                let mut block_ids: Vec<u32> =
+                    disk_inode.iter_block_ids(&self.block_device).collect();
+                block_ids.rotate_left(drop_blocks);
+                for (inner_id, block_id) in block_ids.into_iter().enumerate() {
+                    disk_inode.set_block_id(inner_id as u32, block_id, &self.block_device);
+                }
+                self.truncate_locked((size - drop_blocks * BLOCK_SZ) as u32, disk_inode, &mut fs);
+            }
+            disk_inode.set_head_offset((head % BLOCK_SZ) as u32);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Grow current inode with zeros up to the next multiple of BLOCK_SZ
+    pub fn pad_to_block(&self) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_all();
+        self.modify_disk_inode(|disk_inode| {
+            let old_size = disk_inode.size;
+            let new_size = disk_inode.data_blocks() * BLOCK_SZ as u32;
+            self.unshare_range(old_size as usize, new_size as usize, disk_inode, &mut fs)?;
+            self.increase_size(new_size, disk_inode, &mut fs)?;
+            self.fill_range(old_size as usize, new_size as usize, 0, disk_inode);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Write byte across [offset, offset + len) of current inode, growing it as needed
+    pub fn fill(&self, offset: usize, len: usize, byte: u8) -> Result<(), Error> {
+        checked_size(offset, len)?;
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_range(offset, len);
+        self.modify_disk_inode(|disk_inode| {
+            let old_size = disk_inode.size as usize;
+            let offset = disk_inode.head_offset() as usize + offset;
+            let end = checked_size(offset, len)?;
+            self.unshare_range(offset.min(old_size), end, disk_inode, &mut fs)?;
+            self.increase_size(end.max(old_size) as u32, disk_inode, &mut fs)?;
+            if offset > old_size {
+                self.fill_range(old_size, offset, 0, disk_inode);
+            }
+            self.fill_range(offset, end, byte, disk_inode);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Zero [offset, offset + len) of current file without changing its size
+    ///Blocks wholly inside the range are freed and left as holes, which
+    ///read as zeros; the partly covered blocks at the edges are zeroed in place
+    pub fn zero_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_range(offset, len);
+        self.modify_disk_inode(|disk_inode| {
+            if !disk_inode.is_file() {
+                return Err(Error::NotAFile);
+            }
+            let size = disk_inode.size as usize;
+            let start = (disk_inode.head_offset() as usize).saturating_add(offset).min(size);
+            let end = start.saturating_add(len).min(size);
+            if start == end {
+                return Ok(());
+            }
+            if disk_inode.is_inline() {
+                self.fill_range(start, end, 0, disk_inode);
+                return Ok(());
+            }
+            let first_whole = start.div_ceil(BLOCK_SZ);
+            let last_whole = end / BLOCK_SZ;
+            if first_whole >= last_whole {
+                self.unshare_range(start, end, disk_inode, &mut fs)?;
+                self.fill_range(start, end, 0, disk_inode);
+                return Ok(());
+            }
+            for inner_id in first_whole..last_whole {
+                let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device);
+                if block_id != 0 {
+                    disk_inode.set_block_id(inner_id as u32, 0, &self.block_device);
+                    fs.dealloc_data(block_id);
+                }
+            }
+            //zero the partly covered edges
+            let head_end = first_whole * BLOCK_SZ;
+            let tail_start = last_whole * BLOCK_SZ;
+            self.unshare_range(start, head_end, disk_inode, &mut fs)?;
+            self.fill_range(start, head_end, 0, disk_inode);
+            self.unshare_range(tail_start, end, disk_inode, &mut fs)?;
+            self.fill_range(tail_start, end, 0, disk_inode);
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Grow current file to len with every data block allocated up front,
+    ///like posix_fallocate; holes below len are backed as well
+    ///Fail with `Error::NoSpace`, changing nothing, if the blocks do not fit
+    pub fn preallocate(&self, len: usize) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let _range = self.lock_all();
+        self.modify_disk_inode(|disk_inode| {
+            if !disk_inode.is_file() {
+                return Err(Error::NotAFile);
+            }
+            let old_size = disk_inode.size as usize;
+            let new_size = checked_size(disk_inode.head_offset() as usize, len)?.max(old_size);
+            let blocks_needed = disk_inode.blocks_num_needed(new_size as u32)
+                + disk_inode.holes(&self.block_device);
+            if blocks_needed > fs.free_counts().1 {
+                return Err(Error::NoSpace);
+            }
+            self.increase_size(new_size as u32, disk_inode, &mut fs)?;
+            self.fill_range(old_size, new_size, 0, disk_inode);
+            self.fill_holes(0, old_size, disk_inode, &mut fs)
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Get the permission bits of current inode
+    pub fn mode(&self) -> u16 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.mode())
+    }
+    ///Set the permission bits of current inode, like chmod
+    ///They are only recorded, tiny-fs itself never checks them
+    pub fn set_mode(&self, mode: u16) {
+        let _fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| disk_inode.set_mode(mode));
+        block_cache_sync_all();
+    }
+    ///Get the owner user and group of current inode
+    pub fn owner(&self) -> (u16, u16) {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.owner())
+    }
+    ///Set the owner user and group of current inode, like chown
+    pub fn set_owner(&self, uid: u16, gid: u16) {
+        let _fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| disk_inode.set_owner(uid, gid));
+        block_cache_sync_all();
+    }
+    ///Whether user uid in group gid may write current inode
+    ///checked against its owner and mode, uid 0 may write anything
+    pub fn can_write(&self, uid: u16, gid: u16) -> bool {
+        if uid == 0 {
+            return true;
+        }
+        let _fs = self.fs.lock();
+        let (mode, (owner_uid, owner_gid)) = self.read_disk_inode(|disk_inode| {
+            (disk_inode.mode(), disk_inode.owner())
+        });
+        let bit = if uid == owner_uid {
+            0o200
+        } else if gid == owner_gid {
+            0o020
+        } else {
+            0o002
+        };
+        mode & bit != 0
+    }
+    ///Whether two handles refer to the same disk_inode
+    pub fn same_file(&self, other: &Inode) -> bool {
+        self.block_id == other.block_id
+            && self.offset == other.offset
+            && Arc::ptr_eq(&self.fs, &other.fs)
+    }
+    ///Whether current inode is a regular file
+    pub fn is_file(&self) -> bool {
+        self.metadata().is_file
+    }
+    ///Whether current inode is a directory
+    pub fn is_dir(&self) -> bool {
+        self.metadata().is_dir
+    }
+    ///Whether current inode is a symlink
+    pub fn is_symlink(&self) -> bool {
+        self.metadata().is_symlink
+    }
+    ///Keep the size and type of current inode in this handle once read,
+    ///so `apparent_size` and the `is_*` queries skip the block cache
+    ///The cache is per handle: it follows the changes made through this
+    ///handle, but not those made through another handle to the same inode
+    pub fn cache_metadata(&self, enabled: bool) {
+        self.cache_metadata.store(enabled, Ordering::Relaxed);
+        *self.metadata.lock() = None;
+    }
+    ///Get the number of data blocks of current inode
+    pub fn block_len(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.data_blocks())
+    }
+    ///Get the number of data blocks of current inode, holes included,
+    ///0 if the data is kept inline
+    pub fn data_block_count(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_inline() { 0 } else { disk_inode.data_blocks() }
+        })
+    }
+    ///Get the number of index blocks current inode needs to address its
+    ///data blocks: the indirect1 block, the indirect2 block and the
+    ///indirect1 blocks below it
+    pub fn index_block_count(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if disk_inode.is_inline() {
+                0
+            } else {
+                disk_inode.occupied_blocks() - disk_inode.data_blocks()
+            }
+        })
+    }
+    ///Get the logical size of current inode, as `ls` reports it
+    pub fn apparent_size(&self) -> u64 {
+        self.metadata().size
+    }
+    ///Get the bytes of the data and index blocks current inode holds,
+    ///as `du` reports it, holes excluded and inline data taking none
+    pub fn physical_size(&self) -> u64 {
+        let _fs = self.fs.lock();
+        let blocks = self.read_disk_inode(|disk_inode| {
+            disk_inode.occupied_blocks() - disk_inode.holes(&self.block_device)
+        });
+        blocks as u64 * BLOCK_SZ as u64
+    }
     ///Clear the data in current inode
     pub fn clear(&self) {
+        self.clear_blocks(true);
+    }
+    ///Clear the data in current inode without zeroing the freed blocks
+    ///Roughly halves the I/O, but stale data stays on disk until reused
+    pub fn clear_no_zero(&self) {
+        self.clear_blocks(false);
+    }
+    fn clear_blocks(&self, zero: bool) {
         let mut fs = self.fs.lock();
+        let _range = self.lock_all();
+        if let Some(index_bit) = self.read_disk_inode(|disk_inode| disk_inode.hash_index()) {
+            self.drop_hash_index(index_bit, &mut fs);
+        }
         self.modify_disk_inode(|disk_inode| {
-            let size =disk_inode.size;
+            let occupied_blocks = disk_inode.occupied_blocks() - disk_inode.holes(&self.block_device);
             let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
             //dealloc_blocks_num == disk_inode.total_blocks?
             assert!(
                 data_blocks_dealloc.len() ==
-                DiskInode::total_blocks(size) as usize
+                occupied_blocks as usize
             );
             for block_id in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(block_id);
+                if zero {
+                    fs.dealloc_data(block_id);
+                } else {
+                    fs.dealloc_data_no_zero(block_id);
+                }
             }
         });
         block_cache_sync_all();
@@ -158,19 +1102,42 @@ impl Inode {
 /* tiny-fs users tend not to use the following methods directly */
 impl Inode {
     ///Create a vfs inode
+    ///fs is locked for a moment, so the caller must not hold it
     pub fn new(
         block_id: u32,
         offset: usize,
         fs: Arc<Mutex<TinyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
+        let crc_area = fs.lock().inode_crc_area();
         Self {
             block_id: block_id as usize,
             offset,
             fs,
             block_device,
+            crc_area,
+            cache_metadata: AtomicBool::new(false),
+            metadata: Mutex::new(None),
         }
     }
+    ///Get the size and type of current inode, from this handle if cached
+    fn metadata(&self) -> CachedMetadata {
+        if let Some(metadata) = *self.metadata.lock() {
+            return metadata;
+        }
+        //the fs lock is taken before the cache lock, as in modify_disk_inode
+        let _fs = self.fs.lock();
+        let metadata = self.read_disk_inode(|disk_inode| CachedMetadata {
+            size: disk_inode.size as u64,
+            is_file: disk_inode.is_file(),
+            is_dir: disk_inode.is_dir(),
+            is_symlink: disk_inode.is_symlink(),
+        });
+        if self.cache_metadata.load(Ordering::Relaxed) {
+            *self.metadata.lock() = Some(metadata);
+        }
+        metadata
+    }
     ///Read disk_inode directly with f by vfs inode
     fn read_disk_inode<V>(
         &self,
@@ -180,54 +1147,791 @@ impl Inode {
             .lock()
             .read(self.offset, f)
     }
+    ///Ids of the allocated data blocks and the index blocks of current inode
+    fn data_and_index_block_ids(&self) -> Vec<u32> {
+        self.read_disk_inode(|disk_inode| {
+            let mut block_ids: Vec<u32> = disk_inode
+                .iter_block_ids(&self.block_device)
+                .filter(|&block_id| block_id != 0)
+                .collect();
+            block_ids.extend(disk_inode.index_block_ids(&self.block_device));
+            block_ids
+        })
+    }
     ///Modify disk_inode directly with f by vfs inode
     fn modify_disk_inode<V>(
         &self,
         f: impl FnOnce(&mut DiskInode) -> V
     ) -> V {
-        get_block_cache(self.block_id, Arc::clone(&self.block_device))
+        let value = get_block_cache(self.block_id, Arc::clone(&self.block_device))
             .lock()
-            .modify(self.offset, f)
+            .modify(self.offset, f);
+        if let Some(crc_area) = self.crc_area {
+            crc_area.seal(&self.block_device, self.block_id);
+        }
+        *self.metadata.lock() = None;
+        value
+    }
+    ///Lock fs after checking the inode block of current inode against its crc
+    ///Fail with `Error::Corrupt` if the filesystem is checked and it does not match
+    fn lock_checked(&self) -> Result<MutexGuard<'_, TinyFileSystem>, Error> {
+        let mut fs = self.fs.lock();
+        fs.verify_inode_block(self.block_id as u32)?;
+        Ok(fs)
     }
     ///Increase the size of disk_inode by vfs inode
+    ///A smaller new_size is a shrink and goes to `truncate_locked`
     fn increase_size(
         &self,
         new_size: u32,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<TinyFileSystem>,
-    ) {
+    ) -> Result<Vec<u32>, Error> {
         if new_size < disk_inode.size {
-            return;
+            self.truncate_locked(new_size, disk_inode, fs);
+            return Ok(Vec::new());
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        if new_size as usize > MAX_FILE_SZ {
+            return Err(Error::FileTooLarge);
         }
+        let blocks_needed = disk_inode.blocks_num_needed(new_size);
+        let v = fs.alloc_n(blocks_needed)?;
         //move to DiskInode layer to complete increase_size
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        disk_inode.increase_size(new_size, v.clone(), &self.block_device);
+        Ok(v)
+    }
+    ///Shrink disk_inode to new_size and free the blocks it no longer needs
+    fn truncate_locked(
+        &self,
+        new_size: u32,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<TinyFileSystem>,
+    ) {
+        let blocks_dealloc = disk_inode.decrease_size(new_size, &self.block_device);
+        for block_id in blocks_dealloc.into_iter() {
+            fs.dealloc_data(block_id);
+        }
+    }
+    ///Create inode by name while fs is locked by the caller
+    ///init gets the new disk_inode and its inode bit
+    fn create_locked(
+        &self,
+        name: &str,
+        fs: &mut MutexGuard<TinyFileSystem>,
+        init: impl FnOnce(&mut DiskInode, u32),
+    ) -> Result<Arc<Inode>, Error> {
+        check_name(name, fs)?;
+        //has the file been created?
+        if self.lookup(name, fs)?.is_some() {
+            //no new inode need be created
+            return Err(Error::AlreadyExists);
+        }
+        //new inode need be created
+        /* initialize new_inode */
+        let new_inode_bit = fs.alloc_inode()?;
+        let (new_inode_block_id, new_inode_offset)
+            = fs.get_disk_inode_pos(new_inode_bit);
+        get_block_cache(
+            new_inode_block_id as usize,
+            Arc::clone(&self.block_device)
+        ).lock()
+        .try_modify(new_inode_offset, |new_inode: &mut DiskInode| {
+            init(new_inode, new_inode_bit);
+        })
+        .ok_or(Error::Corrupt)
+        .inspect_err(|_| fs.dealloc_inode(new_inode_bit))?;
+        fs.seal_inode_block(new_inode_block_id as usize);
+        /* update root_inode to contains new_inode */
+        let (entry_index, hash_index) = self.modify_disk_inode(|root_inode| {
+            //apend dir_entry in the root_inode directory
+            //update meta_data
+            let dir_entry_sz = fs.dir_entry_size();
+            let file_count = root_inode.entry_count(dir_entry_sz, &self.block_device);
+            //take the first tombstone, if any is left
+            let tombstone = if root_inode.has_tombstones() {
+                (0..file_count).find(|&i| self.entry_of(root_inode, i, dir_entry_sz).is_empty())
+            } else {
+                None
+            };
+            let entry_index = match tombstone {
+                Some(entry_index) => entry_index,
+                None => {
+                    root_inode.set_tombstones(false);
+                    let new_size = (file_count + 1) * dir_entry_sz;
+                    //increase size, unless a reserved entry is taken
+                    if new_size as u32 > root_inode.size {
+                        self.increase_size(new_size as u32, root_inode, fs)?;
+                    }
+                    if new_size as u32 == root_inode.size {
+                        root_inode.set_reserved(false);
+                    }
+                    file_count
+                }
+            };
+            //write dir_entry
+            let dirent = DirEntry::new(name, new_inode_bit, dir_entry_sz);
+            root_inode.write_at(
+                entry_index * dir_entry_sz,
+                dirent.as_bytes(),
+                &self.block_device
+            );
+            Ok((entry_index, root_inode.hash_index()))
+        }).inspect_err(|_| fs.dealloc_inode(new_inode_bit))?;
+        if let Some(index_bit) = hash_index {
+            self.hash_insert(name, entry_index, index_bit, fs);
+        }
+        /* create and return new_inode */
+        Ok(Arc::new(self.inode_of_bit(new_inode_bit, fs)))
+    }
+    ///Write data to current inode while fs is locked by the caller
+    fn write_at_locked(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        fs: &mut MutexGuard<TinyFileSystem>,
+    ) -> Result<(usize, Vec<u32>), Error> {
+        self.modify_disk_inode(|disk_inode| {
+            let old_size = disk_inode.size as usize;
+            let offset = disk_inode.head_offset() as usize + offset;
+            let end = checked_size(offset, buf.len())?;
+            self.unshare_range(offset.min(old_size), end, disk_inode, fs)?;
+            let mut new_blocks = self.increase_size(
+                end.max(old_size) as u32,
+                disk_inode, fs
+            )?;
+            //blocks freed by clear_no_zero still hold stale data
+            if offset > old_size {
+                self.fill_range(old_size, offset, 0, disk_inode);
+            }
+            let write_size = disk_inode.write_at(offset, buf, &self.block_device);
+            if fs.is_dedup() {
+                //share the blocks written whole with identical ones
+                for inner_id in offset.div_ceil(BLOCK_SZ)..(offset + buf.len()) / BLOCK_SZ {
+                    let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device);
+                    if let Some(shared) = fs.dedup_block(block_id) {
+                        disk_inode.set_block_id(inner_id as u32, shared, &self.block_device);
+                        fs.dealloc_data(block_id);
+                        new_blocks.retain(|&new_block| new_block != block_id);
+                    }
+                }
+            }
+            Ok((write_size, new_blocks))
+        })
+    }
+    ///Copy the data blocks of current inode into a contiguous run,
+    ///then free the old ones; the indirect blocks stay where they are
+    ///Holes are kept and take no room in the run
+    pub(crate) fn relocate_data(&self, fs: &mut TinyFileSystem) -> Result<(), Error> {
+        let _range = self.lock_all();
+        self.modify_disk_inode(|disk_inode| {
+            if disk_inode.is_inline() {
+                return Ok(());
+            }
+            let old_block_ids: Vec<u32> = disk_inode.iter_block_ids(&self.block_device).collect();
+            let blocks = old_block_ids.iter().filter(|&&block_id| block_id != 0).count() as u32;
+            if blocks == 0 {
+                return Ok(());
+            }
+            let start_block_id = fs.alloc_contiguous(blocks)?;
+            let backed = old_block_ids
+                .into_iter()
+                .enumerate()
+                .filter(|&(_, block_id)| block_id != 0);
+            for (i, (inner_id, old_block_id)) in backed.enumerate() {
+                let inner_id = inner_id as u32;
+                let data = get_block_cache(old_block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(0, |data_block: &[u8; BLOCK_SZ]| *data_block);
+                let new_block_id = start_block_id + i as u32;
+                get_block_cache(new_block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut [u8; BLOCK_SZ]| *data_block = data);
+                disk_inode.set_block_id(inner_id, new_block_id, &self.block_device);
+                fs.dealloc_data(old_block_id);
+            }
+            Ok(())
+        })?;
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Lock the blocks holding [offset, offset + len) of current inode
+    ///The fs lock must be held, and is taken before any range lock
+    fn lock_range(&self, offset: usize, len: usize) -> RangeGuard {
+        lock_range(
+            (Arc::as_ptr(&self.fs) as usize, self.block_id, self.offset),
+            offset / BLOCK_SZ..offset.saturating_add(len).div_ceil(BLOCK_SZ)
+        )
+    }
+    ///Lock every block of current inode, for operations that move or free them
+    fn lock_all(&self) -> RangeGuard {
+        lock_range(
+            (Arc::as_ptr(&self.fs) as usize, self.block_id, self.offset),
+            0..usize::MAX
+        )
+    }
+    ///Get the data blocks holding [start, end) of disk_inode if they may be
+    ///accessed without the fs lock: the range lies within a regular file kept
+    ///in blocks, and for a write every block is present and private
+    fn unlocked_blocks(
+        &self,
+        start: usize,
+        end: usize,
+        write: bool,
+        disk_inode: &DiskInode,
+        fs: &TinyFileSystem,
+    ) -> Option<Vec<u32>> {
+        if !disk_inode.is_file()
+            || disk_inode.is_inline()
+            || start >= end
+            || end > disk_inode.size as usize
+            || (write && fs.is_dedup())
+        {
+            return None;
+        }
+        let block_ids: Vec<u32> = (start / BLOCK_SZ..end.div_ceil(BLOCK_SZ))
+            .map(|inner_id| disk_inode.get_block_id(inner_id as u32, &self.block_device))
+            .collect();
+        if write && block_ids.contains(&0) {
+            return None;
+        }
+        Some(block_ids)
+    }
+    ///Read buf from start of a file whose blocks from there on are block_ids
+    fn read_blocks_at(&self, start: usize, block_ids: &[u32], buf: &mut [u8]) {
+        let mut done = 0;
+        for &block_id in block_ids {
+            let bounds = DiskInode::block_slice_bounds(start + done, buf.len() - done);
+            let dst = &mut buf[done..done + bounds.len()];
+            if block_id == 0 {
+                dst.fill(0);
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(0, |data_block: &[u8; BLOCK_SZ]| {
+                        dst.copy_from_slice(&data_block[bounds.clone()]);
+                    });
+            }
+            done += bounds.len();
+        }
+    }
+    ///Write buf at start of a file whose blocks from there on are block_ids
+    fn write_blocks_at(&self, start: usize, block_ids: &[u32], buf: &[u8]) {
+        let mut done = 0;
+        for &block_id in block_ids {
+            let bounds = DiskInode::block_slice_bounds(start + done, buf.len() - done);
+            let src = &buf[done..done + bounds.len()];
+            let block_cache = if bounds.len() == BLOCK_SZ {
+                get_block_cache_for_overwrite(block_id as usize, Arc::clone(&self.block_device))
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            };
+            block_cache
+                .lock()
+                .modify(0, |data_block: &mut [u8; BLOCK_SZ]| {
+                    data_block[bounds.clone()].copy_from_slice(src);
+                });
+            done += bounds.len();
+        }
+        //write back once all data is in the cache, like `block_cache_sync_all`
+        if !background_flush() {
+            block_cache_sync_blocks(&self.block_device, block_ids);
+        }
+    }
+    ///Write byte across [start, end) of disk_inode, one block at a time
+    fn fill_range(&self, start: usize, end: usize, byte: u8, disk_inode: &mut DiskInode) {
+        let scratch = [byte; BLOCK_SZ];
+        let mut pos = start;
+        while pos < end {
+            //stay within the current block
+            let len = (BLOCK_SZ - pos % BLOCK_SZ).min(end - pos);
+            disk_inode.write_at(pos, &scratch[..len], &self.block_device);
+            pos += len;
+        }
+    }
+    ///Prepare the data blocks covering [start, end) to be written in place
+    ///Holes get fresh zeroed blocks, and shared blocks are replaced by
+    ///private copies on a dedup filesystem
+    fn unshare_range(
+        &self,
+        start: usize,
+        end: usize,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<TinyFileSystem>,
+    ) -> Result<(), Error> {
+        self.fill_holes(start, end, disk_inode, fs)?;
+        if !fs.is_dedup() || disk_inode.is_inline() {
+            return Ok(());
+        }
+        let end_inner_id = end.div_ceil(BLOCK_SZ).min(disk_inode.data_blocks() as usize);
+        for inner_id in start / BLOCK_SZ..end_inner_id {
+            let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device);
+            if !fs.is_shared(block_id) {
+                fs.forget_block(block_id);
+                continue;
+            }
+            let copy = fs.alloc_data()?;
+            let data = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |data_block: &[u8; BLOCK_SZ]| *data_block);
+            get_block_cache(copy as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut [u8; BLOCK_SZ]| *data_block = data);
+            disk_inode.set_block_id(inner_id as u32, copy, &self.block_device);
+            fs.dealloc_data(block_id);
+        }
+        Ok(())
+    }
+    ///Back the holes among the data blocks covering [start, end) with zeroed blocks
+    fn fill_holes(
+        &self,
+        start: usize,
+        end: usize,
+        disk_inode: &mut DiskInode,
+        fs: &mut MutexGuard<TinyFileSystem>,
+    ) -> Result<(), Error> {
+        if disk_inode.is_inline() {
+            return Ok(());
+        }
+        let end_inner_id = end.div_ceil(BLOCK_SZ).min(disk_inode.data_blocks() as usize);
+        for inner_id in start / BLOCK_SZ..end_inner_id {
+            if disk_inode.get_block_id(inner_id as u32, &self.block_device) != 0 {
+                continue;
+            }
+            //the new block may hold stale data left by clear_no_zero
+            let new_block_id = fs.alloc_data()?;
+            get_block_cache(new_block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut [u8; BLOCK_SZ]| data_block.fill(0));
+            disk_inode.set_block_id(inner_id as u32, new_block_id, &self.block_device);
+        }
+        Ok(())
+    }
+    ///Sum the blocks occupied by current inode and its subtree
+    fn subtree_blocks(&self, fs: &TinyFileSystem) -> u32 {
+        let (occupied_blocks, children) = self.read_disk_inode(|disk_inode| {
+            let mut children: Vec<u32> = Vec::new();
+            if let Some(index_bit) = disk_inode.hash_index() {
+                children.push(index_bit);
+            }
+            if disk_inode.is_dir() {
+                let dir_entry_sz = fs.dir_entry_size();
+                let file_count = disk_inode.entry_count(dir_entry_sz, &self.block_device);
+                let mut dir_entry = DirEntry::empty(dir_entry_sz);
+                for i in 0..file_count {
+                    assert_eq!(
+                        disk_inode.read_at(
+                            dir_entry_sz * i,
+                            dir_entry.as_bytes_mut(),
+                            &self.block_device
+                        ),
+                        dir_entry_sz
+                    );
+                    //do not walk back up the tree
+                    if !dir_entry.is_empty() && dir_entry.name() != "." && dir_entry.name() != ".." {
+                        children.push(dir_entry.inode_number());
+                    }
+                }
+            }
+            (disk_inode.occupied_blocks() - disk_inode.holes(&self.block_device), children)
+        });
+        occupied_blocks + children
+            .into_iter()
+            .map(|inode_bit| self.inode_of_bit(inode_bit, fs).subtree_blocks(fs))
+            .sum::<u32>()
+    }
+    ///Get the vfs inode of an inode bit
+    fn inode_of_bit(&self, inode_bit: u32, fs: &TinyFileSystem) -> Inode {
+        let (block_id, offset) = fs.get_disk_inode_pos(inode_bit);
+        Self {
+            block_id: block_id as usize,
+            offset,
+            fs: self.fs.clone(),
+            block_device: self.block_device.clone(),
+            crc_area: fs.inode_crc_area(),
+            cache_metadata: AtomicBool::new(false),
+            metadata: Mutex::new(None),
+        }
+    }
+    ///Find inode bit under current directory by name
+    ///The hash index is consulted if the directory has one
+    fn lookup(&self, name: &str, fs: &TinyFileSystem) -> Result<Option<u32>, Error> {
+        //the position of a handle built by `Inode::new` is not trusted
+        let (is_dir, hash_index) = get_block_cache(self.block_id, Arc::clone(&self.block_device))
+            .lock()
+            .try_read(self.offset, |disk_inode: &DiskInode| {
+                (disk_inode.is_dir(), disk_inode.hash_index())
+            })
+            .ok_or(Error::Corrupt)?;
+        if !is_dir {
+            return Err(Error::NotADirectory);
+        }
+        Ok(match hash_index {
+            Some(index_bit) => self.find_hashed(name, index_bit, fs),
+            None => self.read_disk_inode(|disk_inode| {
+                self.find_inode_id(name, disk_inode, fs)
+            }),
+        })
+    }
+    ///Find the index of the dir_entry named name in current directory
+    fn entry_index(&self, name: &str, fs: &TinyFileSystem) -> Result<Option<usize>, Error> {
+        let dir_entry_sz = fs.dir_entry_size();
+        self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return Err(Error::NotADirectory);
+            }
+            let file_count = disk_inode.entry_count(dir_entry_sz, &self.block_device);
+            Ok((0..file_count).find(|&i| {
+                let dir_entry = self.entry_of(disk_inode, i, dir_entry_sz);
+                !dir_entry.is_empty() && fs.names_match(dir_entry.name(), name)
+            }))
+        })
+    }
+    ///Read the dir_entry at index of current directory
+    fn entry_at(&self, index: usize, dir_entry_sz: usize) -> DirEntry {
+        self.read_disk_inode(|disk_inode| self.entry_of(disk_inode, index, dir_entry_sz))
+    }
+    ///Read the dir_entry at index of disk_inode
+    fn entry_of(&self, disk_inode: &DiskInode, index: usize, dir_entry_sz: usize) -> DirEntry {
+        let mut dir_entry = DirEntry::empty(dir_entry_sz);
+        assert_eq!(
+            disk_inode.read_at(index * dir_entry_sz, dir_entry.as_bytes_mut(), &self.block_device),
+            dir_entry_sz
+        );
+        dir_entry
     }
     ///Find inode under disk_inode by name
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode)
+    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode, fs: &TinyFileSystem)
         -> Option <u32>
     {
         //assert it is a directory
         assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIR_ENTRY_SZ;
-        let mut dir_entry = DirEntry::empty();
+        let dir_entry_sz = fs.dir_entry_size();
+        let file_count = disk_inode.entry_count(dir_entry_sz, &self.block_device);
+        let mut dir_entry = DirEntry::empty(dir_entry_sz);
         for i in 0..file_count {
             assert_eq!(
                 disk_inode.read_at(
-                    DIR_ENTRY_SZ *i,
+                    dir_entry_sz *i,
                     dir_entry.as_bytes_mut(),
                     &self.block_device
                 ),
-                DIR_ENTRY_SZ
+                dir_entry_sz
             );
-            if dir_entry.name() == name {
-                return Some(dir_entry.inode_number() as u32);
+            if !dir_entry.is_empty() && fs.names_match(dir_entry.name(), name) {
+                return Some(dir_entry.inode_number());
+            }
+        }
+        None
+    }
+    ///Turn the dir_entry at index of disk_inode into a tombstone
+    fn remove_entry(&self, index: usize, dir_entry_sz: usize, disk_inode: &mut DiskInode) {
+        let mut dir_entry = self.entry_of(disk_inode, index, dir_entry_sz);
+        dir_entry.set_empty();
+        disk_inode.write_at(index * dir_entry_sz, dir_entry.as_bytes(), &self.block_device);
+        disk_inode.set_tombstones(true);
+    }
+}
+
+///Get offset + len as a file size
+///Fail with `Error::FileTooLarge` past what a disk_inode can index, overflow included
+fn checked_size(offset: usize, len: usize) -> Result<usize, Error> {
+    offset
+        .checked_add(len)
+        .filter(|&size| size <= MAX_FILE_SZ)
+        .ok_or(Error::FileTooLarge)
+}
+
+///Check that name can be a dir_entry of fs
+///Fail with `Error::InvalidName` for names path resolution could not reach
+///or an empty name, which marks a free dir_entry
+fn check_name(name: &str, fs: &TinyFileSystem) -> Result<(), Error> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(Error::InvalidName);
+    }
+    if name.len() > fs.name_length_limit() {
+        return Err(Error::NameTooLong);
+    }
+    Ok(())
+}
+
+///Coalesce the data blocks of disk_inode into (start, length) runs
+///Consecutive holes form a run starting at 0
+fn extents_of(disk_inode: &DiskInode, block_device: &Arc<dyn BlockDevice>) -> Vec<(u32, u32)> {
+    let mut extents: Vec<(u32, u32)> = Vec::new();
+    for block_id in disk_inode.iter_block_ids(block_device) {
+        match extents.last_mut() {
+            Some((0, len)) if block_id == 0 => *len += 1,
+            Some((start, len)) if *start != 0 && *start + *len == block_id => *len += 1,
+            _ => extents.push((block_id, 1)),
+        }
+    }
+    extents
+}
+
+/*
+    Directory hash index: an open-addressed table of u32 slots kept in
+    a hidden file inode. The first u32 of the file counts the occupied
+    slots and the table follows it. A slot holds the dir_entry index
+    plus one, zero marks an empty slot and HASH_TOMBSTONE one whose
+    entry went away. Tombstones keep probes going and count as occupied
+    until the table is rebuilt, which happens whenever it would become
+    more than half full; it gets twice the slots if the entries alone
+    fill more than half of it.
+*/
+const HASH_MIN_SLOTS: usize = BLOCK_SZ / 4;
+const HASH_TOMBSTONE: u32 = u32::MAX;
+
+fn name_hash(name: &str, fs: &TinyFileSystem) -> u32 {
+    let case_insensitive = fs.is_case_insensitive();
+    //FNV-1a, over the lowercase name if names match ignoring case
+    name.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        let byte = if case_insensitive { byte.to_ascii_lowercase() } else { byte };
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+impl Inode {
+    ///Build a hash index for current directory, so that `find` and
+    ///`create` no longer scan every dir_entry
+    pub fn enable_hash_index(&self) -> Result<(), Error> {
+        let mut fs = self.lock_checked()?;
+        let (is_dir, hash_index) = self.read_disk_inode(|disk_inode| {
+            (disk_inode.is_dir(), disk_inode.hash_index())
+        });
+        if !is_dir {
+            return Err(Error::NotADirectory);
+        }
+        if hash_index.is_some() {
+            return Ok(());
+        }
+        let index_bit = fs.alloc_inode()?;
+        let index = self.inode_of_bit(index_bit, &fs);
+        index.modify_disk_inode(|disk_inode| {
+            disk_inode.initialize(DiskInodeType::File);
+        });
+        if let Err(err) = self.rebuild_hash_index(&index, &mut fs) {
+            fs.dealloc_inode(index_bit);
+            return Err(err);
+        }
+        self.modify_disk_inode(|disk_inode| disk_inode.set_hash_index(Some(index_bit)));
+        block_cache_sync_all();
+        Ok(())
+    }
+    ///Refill the hash index with the dir_entries of current directory,
+    ///dropping its tombstones; the table never shrinks
+    fn rebuild_hash_index(
+        &self,
+        index: &Inode,
+        fs: &mut MutexGuard<TinyFileSystem>,
+    ) -> Result<(), Error> {
+        let dir_entry_sz = fs.dir_entry_size();
+        let names: Vec<(usize, String)> = self.read_disk_inode(|disk_inode| {
+            let file_count = disk_inode.size as usize / dir_entry_sz;
+            let mut dir_entry = DirEntry::empty(dir_entry_sz);
+            (0..file_count)
+                .filter_map(|i| {
+                    disk_inode.read_at(dir_entry_sz * i, dir_entry.as_bytes_mut(), &self.block_device);
+                    (!dir_entry.is_empty()).then(|| (i, String::from(dir_entry.name())))
+                })
+                .collect()
+        });
+        let capacity = (names.len() * 2 + 1)
+            .next_power_of_two()
+            .max(HASH_MIN_SLOTS)
+            .max(index.hash_capacity());
+        let mut slots = vec![0u32; capacity];
+        for (i, name) in names.iter() {
+            let mut slot = name_hash(name, fs) as usize & (capacity - 1);
+            while slots[slot] != 0 {
+                slot = (slot + 1) & (capacity - 1);
+            }
+            slots[slot] = *i as u32 + 1;
+        }
+        let bytes: Vec<u8> = core::iter::once(names.len() as u32)
+            .chain(slots)
+            .flat_map(|word| word.to_ne_bytes())
+            .collect();
+        index.modify_disk_inode(|disk_inode| {
+            index.increase_size(bytes.len() as u32, disk_inode, fs)?;
+            disk_inode.write_at(0, &bytes, &self.block_device);
+            Ok(())
+        })
+    }
+    ///Record the dir_entry at entry_index, which is already written,
+    ///in the hash index; the first tombstone on the way is reused
+    ///The index is dropped if it cannot grow, lookups then scan again
+    fn hash_insert(
+        &self,
+        name: &str,
+        entry_index: usize,
+        index_bit: u32,
+        fs: &mut MutexGuard<TinyFileSystem>,
+    ) {
+        let index = self.inode_of_bit(index_bit, fs);
+        let capacity = index.hash_capacity();
+        let occupied = index.hash_word(0) as usize;
+        if (occupied + 1) * 2 > capacity {
+            if self.rebuild_hash_index(&index, fs).is_err() {
+                self.drop_hash_index(index_bit, fs);
+            }
+            return;
+        }
+        //at least half of the slots are empty, so the probe ends
+        let mut slot = name_hash(name, fs) as usize & (capacity - 1);
+        let mut tombstone = None;
+        loop {
+            match index.hash_word(slot + 1) {
+                0 => break,
+                HASH_TOMBSTONE => {
+                    tombstone.get_or_insert(slot);
+                }
+                _ => {}
+            }
+            slot = (slot + 1) & (capacity - 1);
+        }
+        match tombstone {
+            Some(tombstone) => index.set_hash_word(tombstone + 1, entry_index as u32 + 1),
+            None => {
+                index.set_hash_word(slot + 1, entry_index as u32 + 1);
+                index.set_hash_word(0, occupied as u32 + 1);
+            }
+        }
+    }
+    ///Turn the slot of the dir_entry at entry_index, named name,
+    ///into a tombstone
+    fn hash_remove(&self, name: &str, entry_index: usize, index_bit: u32, fs: &TinyFileSystem) {
+        let index = self.inode_of_bit(index_bit, fs);
+        let capacity = index.hash_capacity();
+        let start = name_hash(name, fs) as usize & (capacity - 1);
+        for step in 0..capacity {
+            let slot = (start + step) & (capacity - 1);
+            match index.hash_word(slot + 1) {
+                0 => return,
+                entry if entry == entry_index as u32 + 1 => {
+                    index.set_hash_word(slot + 1, HASH_TOMBSTONE);
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+    ///Free the hash index of current directory
+    fn drop_hash_index(&self, index_bit: u32, fs: &mut MutexGuard<TinyFileSystem>) {
+        let index = self.inode_of_bit(index_bit, fs);
+        index.modify_disk_inode(|disk_inode| {
+            for block_id in disk_inode.clear_size(&self.block_device).into_iter() {
+                fs.dealloc_data(block_id);
+            }
+        });
+        fs.dealloc_inode(index_bit);
+        self.modify_disk_inode(|disk_inode| disk_inode.set_hash_index(None));
+    }
+    ///Find inode bit by name through the hash index
+    ///Index and directory are locked in turn since they may share a block
+    fn find_hashed(&self, name: &str, index_bit: u32, fs: &TinyFileSystem) -> Option<u32> {
+        let index = self.inode_of_bit(index_bit, fs);
+        let capacity = index.hash_capacity();
+        let start = name_hash(name, fs) as usize & (capacity - 1);
+        //probe every slot at most once, even if none is empty
+        for step in 0..capacity {
+            let entry = index.hash_word(((start + step) & (capacity - 1)) + 1);
+            if entry == 0 {
+                return None;
+            }
+            if entry == HASH_TOMBSTONE {
+                continue;
+            }
+            let dir_entry_sz = fs.dir_entry_size();
+            let mut dir_entry = DirEntry::empty(dir_entry_sz);
+            self.read_disk_inode(|disk_inode| {
+                disk_inode.read_at(
+                    dir_entry_sz * (entry as usize - 1),
+                    dir_entry.as_bytes_mut(),
+                    &self.block_device
+                )
+            });
+            if !dir_entry.is_empty() && fs.names_match(dir_entry.name(), name) {
+                return Some(dir_entry.inode_number());
             }
         }
         None
     }
-}
\ No newline at end of file
+    ///Get the number of table slots of the hash index held by current inode
+    fn hash_capacity(&self) -> usize {
+        self.read_disk_inode(|disk_inode| (disk_inode.size as usize / 4).saturating_sub(1))
+    }
+    ///Read a word of the hash index held by current inode
+    ///Word 0 is the occupied slot count, slot i is word i + 1
+    fn hash_word(&self, word: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.read_disk_inode(|disk_inode| {
+            disk_inode.read_at(word * 4, &mut bytes, &self.block_device)
+        });
+        u32::from_ne_bytes(bytes)
+    }
+    ///Write a word of the hash index held by current inode
+    fn set_hash_word(&self, word: usize, value: u32) {
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.write_at(word * 4, &value.to_ne_bytes(), &self.block_device);
+        });
+    }
+}
+
+///A read cursor over an inode, the `no_std` analog of `std::io::Read`
+pub struct Cursor {
+    inode: Arc<Inode>,
+    offset: usize,
+}
+
+impl Cursor {
+    ///Create a cursor reading inode from offset
+    pub fn new(inode: Arc<Inode>, offset: usize) -> Self {
+        Self { inode, offset }
+    }
+    ///Read data from current offset and advance it
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let read_size = self.inode.read_at(self.offset, buf);
+        self.offset += read_size;
+        read_size
+    }
+    ///Move the cursor to pos
+    pub fn seek(&mut self, pos: usize) {
+        self.offset = pos;
+    }
+    ///Get current offset of the cursor
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+}
+
+///Lazy iterator over the entries of a directory, from `Inode::entries_iter`
+///
+///The fs lock is taken anew by each `next` and not held in between, so
+///the directory may be used meanwhile. Entries are visited by index:
+///one created during the walk may or may not be seen, and one removed
+///before it is reached is skipped.
+pub struct DirEntryIter<'a> {
+    inode: &'a Inode,
+    index: usize,
+}
+
+impl Iterator for DirEntryIter<'_> {
+    type Item = (String, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let fs = self.inode.fs.lock();
+        let dir_entry_sz = fs.dir_entry_size();
+        self.inode.read_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir());
+            let file_count = disk_inode.entry_count(dir_entry_sz, &self.inode.block_device);
+            while self.index < file_count {
+                let dir_entry = self.inode.entry_of(disk_inode, self.index, dir_entry_sz);
+                self.index += 1;
+                if !dir_entry.is_empty() {
+                    return Some((String::from(dir_entry.name()), dir_entry.inode_number()));
+                }
+            }
+            None
+        })
+    }
+}